@@ -1,9 +1,14 @@
 use criterion::{criterion_group, criterion_main, Criterion};
+use demo_isa::err::ISAErr;
 use demo_isa::reg::F64Reg;
 use demo_isa::reg::UsizeReg;
 use demo_isa::Inst;
+use demo_vm::asm::assemble;
+use demo_vm::cpu::{CpuCore, CpuErr};
+use demo_vm::memory::Memory;
+use demo_vm::port::PortBus;
+use demo_vm::test::fibonacci_code;
 use demo_vm::VmTmp;
-use log::debug;
 // use mimalloc::MiMalloc;
 
 // #[global_allocator]
@@ -16,121 +21,139 @@ criterion_group!(
     bench_add_u,
     bench_add_d,
     bench_push_u,
-    bench_load_uh
+    bench_load_uh,
+    bench_dispatch,
+    bench_numeric_loop
 );
 criterion_main!(benches);
 
 const NUM_INST: usize = 10000;
-pub fn bench_nop(c: &mut Criterion) {
-    // env_logger::init();
 
+/// 代码只加载一次，每轮只把pc和栈复位，计时不含加载和预解码
+fn bench_code(c: &mut Criterion, name: &str, code: Vec<Inst>) {
     let mut vm = VmTmp::new();
-    let code = vec![Inst::Nop; NUM_INST];
-    c.bench_function("nop", |b| {
+    vm.set_code(code);
+    c.bench_function(name, |b| {
         b.iter(|| {
-            vm.set_code(code.clone());
-            match vm.start() {
-                Ok(_) => {}
-                Err(e) => debug!("error: {:?}", e),
-            }
+            vm.set_pc(0);
+            vm.mem_store(None, None, Some(vec![]));
+            vm.start().unwrap();
             assert_eq!(vm.get_pc(), NUM_INST);
-            vm.reset();
         })
     });
 }
 
+pub fn bench_nop(c: &mut Criterion) {
+    bench_code(c, "nop", vec![Inst::Nop; NUM_INST]);
+}
+
 pub fn bench_mu(c: &mut Criterion) {
-    let mut vm = VmTmp::new();
-    let code = vec![Inst::MU(UsizeReg::U1, 1); NUM_INST];
-    c.bench_function("MU", |b| {
-        b.iter(|| {
-            vm.set_code(code.clone());
-            match vm.start() {
-                Ok(_) => {}
-                Err(e) => debug!("error: {:?}", e),
-            }
-            assert_eq!(vm.get_pc(), NUM_INST);
-            vm.reset();
-        })
-    });
+    bench_code(c, "MU", vec![Inst::MU(UsizeReg::U1, 1); NUM_INST]);
 }
 
 pub fn bench_md(c: &mut Criterion) {
-    let mut vm = VmTmp::new();
-    let code = vec![Inst::MD(F64Reg::F1, 1.0); NUM_INST];
-    c.bench_function("MD", |b| {
-        b.iter(|| {
-            vm.set_code(code.clone());
-            match vm.start() {
-                Ok(_) => {}
-                Err(e) => debug!("error: {:?}", e),
-            }
-            assert_eq!(vm.get_pc(), NUM_INST);
-            vm.reset();
-        })
-    });
+    bench_code(c, "MD", vec![Inst::MD(F64Reg::F1, 1.0); NUM_INST]);
 }
 
 pub fn bench_add_u(c: &mut Criterion) {
-    let mut vm = VmTmp::new();
-    let code = vec![Inst::AddU(UsizeReg::U1, UsizeReg::U2, UsizeReg::U3); NUM_INST];
-    c.bench_function("addU", |b| {
-        b.iter(|| {
-            vm.set_code(code.clone());
-            match vm.start() {
-                Ok(_) => {}
-                Err(e) => debug!("error: {:?}", e),
-            }
-            assert_eq!(vm.get_pc(), NUM_INST);
-            vm.reset();
-        })
-    });
+    bench_code(
+        c,
+        "addU",
+        vec![Inst::AddU(UsizeReg::U1, UsizeReg::U2, UsizeReg::U3); NUM_INST],
+    );
 }
 
 pub fn bench_add_d(c: &mut Criterion) {
-    let mut vm = VmTmp::new();
-    let code = vec![Inst::AddD(F64Reg::F1, F64Reg::F2, F64Reg::F3); NUM_INST];
-    c.bench_function("addD", |b| {
-        b.iter(|| {
-            vm.set_code(code.clone());
-            match vm.start() {
-                Ok(_) => {}
-                Err(e) => debug!("error: {:?}", e),
-            }
-            assert_eq!(vm.get_pc(), NUM_INST);
-            vm.reset();
-        })
-    });
+    bench_code(
+        c,
+        "addD",
+        vec![Inst::AddD(F64Reg::F1, F64Reg::F2, F64Reg::F3); NUM_INST],
+    );
 }
 
 pub fn bench_push_u(c: &mut Criterion) {
-    let mut vm = VmTmp::new();
-    let code = vec![Inst::PushU(UsizeReg::U1); NUM_INST];
-    c.bench_function("pushU", |b| {
+    bench_code(c, "pushU", vec![Inst::PushU(UsizeReg::U1); NUM_INST]);
+}
+
+pub fn bench_load_uh(c: &mut Criterion) {
+    bench_code(
+        c,
+        "loadUH",
+        vec![Inst::LoadUH(UsizeReg::U1, UsizeReg::U2); NUM_INST],
+    );
+}
+
+/// 逐条`match`解释执行，即预解码之前的执行方式
+fn run_match(core: &mut CpuCore, mem: &mut Memory, bus: &mut PortBus) {
+    loop {
+        let pc = core.get_pc();
+        let inst = *mem.fetch_code(pc).unwrap();
+        core.set_pc(pc + 1);
+        match core.run_inst(&inst, mem, bus) {
+            Ok(()) => {}
+            Err(CpuErr::ISAErr(ISAErr::Halt)) => return,
+            Err(e) => panic!("{:?}", e),
+        }
+    }
+}
+
+/// 对比`match`解释执行、预解码的线程化代码及融合指令后的线程化代码
+pub fn bench_dispatch(c: &mut Criterion) {
+    let code = fibonacci_code(15);
+    let mut group = c.benchmark_group("dispatch_fibonacci");
+    group.bench_function("match", |b| {
+        let (mut core, mut mem, mut bus) = (CpuCore::new(), Memory::new(), PortBus::new());
+        mem.store(Some(code.clone()), None, None);
         b.iter(|| {
-            vm.set_code(code.clone());
-            match vm.start() {
-                Ok(_) => {}
-                Err(e) => debug!("error: {:?}", e),
-            }
-            assert_eq!(vm.get_pc(), NUM_INST);
-            vm.reset();
+            mem.store(None, None, Some(vec![]));
+            core.reset();
+            run_match(&mut core, &mut mem, &mut bus);
+            assert_eq!(core.get_u_reg(UsizeReg::U1), 610);
         })
     });
+    for (name, fuse) in [("threaded", false), ("fused", true)] {
+        group.bench_function(name, |b| {
+            let (mut core, mut mem, mut bus) = (CpuCore::new(), Memory::new(), PortBus::new());
+            mem.set_fusion(fuse);
+            mem.store(Some(code.clone()), None, None);
+            b.iter(|| {
+                mem.store(None, None, Some(vec![]));
+                core.reset();
+                core.start(&mut mem, &mut bus, None).unwrap();
+                assert_eq!(core.get_u_reg(UsizeReg::U1), 610);
+            })
+        });
+    }
+    group.finish();
 }
 
-pub fn bench_load_uh(c: &mut Criterion) {
+/// 堆上f64数组的乘加循环，启用`jit`特性时会被编译为本机代码
+pub fn bench_numeric_loop(c: &mut Criterion) {
+    let code = assemble(
+        "
+        mu u2, 100_000
+        mu u3, 1
+        md f1, 1.000001
+    loop:
+        loaddh f2, u3
+        muld f2, f2, f1
+        addd f3, f3, f2
+        adddi f2, 0.5
+        storedh f2, u3
+        addui u1, 1
+        mu u4, loop
+        jne u4, u1, u2
+        halt
+        ",
+    )
+    .unwrap();
     let mut vm = VmTmp::new();
-    let code = vec![Inst::LoadUH(UsizeReg::U1, UsizeReg::U2); NUM_INST];
-    c.bench_function("loadUH", |b| {
+    vm.set_code(code);
+    c.bench_function("numeric_loop", |b| {
         b.iter(|| {
-            vm.set_code(code.clone());
-            match vm.start() {
-                Ok(_) => {}
-                Err(e) => debug!("error: {:?}", e),
-            }
-            assert_eq!(vm.get_pc(), NUM_INST);
-            vm.reset();
+            vm.set_pc(0);
+            vm.set_u_reg(UsizeReg::U1, 0);
+            vm.start().unwrap();
         })
     });
 }
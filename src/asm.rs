@@ -0,0 +1,573 @@
+//! 文本汇编器，把`.dasm`源码翻译为`Vec<Inst>`
+//!
+//! 语法：
+//! ```text
+//! ; 注释以`;`开始直到行尾
+//! .const N 10          ; 常量定义，值只能是数字字面量
+//! main:                ; 标签，值为下一条指令的地址
+//!     mu u8, fib       ; 标签和常量可以出现在任何立即数的位置
+//!     mu u1, N
+//!     call u8
+//!     halt
+//! ```
+//! 助记符为`Inst`变体名的小写形式，操作数顺序与`Inst`的字段顺序一致。
+//! 立即数支持十进制、`0x`十六进制和`0b`二进制，可以用`_`分隔。
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{self, Display};
+
+use demo_isa::reg::{F64Reg, F64RegType, UsizeReg, UsizeRegType};
+use demo_isa::Inst;
+
+pub use self::disasm::{disassemble, disassemble_with_labels};
+
+pub mod disasm;
+
+pub const U_REGS: [UsizeReg; 8] = [
+    UsizeReg::U1,
+    UsizeReg::U2,
+    UsizeReg::U3,
+    UsizeReg::U4,
+    UsizeReg::U5,
+    UsizeReg::U6,
+    UsizeReg::U7,
+    UsizeReg::U8,
+];
+pub const F_REGS: [F64Reg; 8] = [
+    F64Reg::F1,
+    F64Reg::F2,
+    F64Reg::F3,
+    F64Reg::F4,
+    F64Reg::F5,
+    F64Reg::F6,
+    F64Reg::F7,
+    F64Reg::F8,
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AsmErrKind {
+    UnknownMnemonic(String),
+    UnknownDirective(String),
+    InvalidRegister(String),
+    InvalidNumber(String),
+    InvalidLabel(String),
+    UndefinedSymbol(String),
+    DuplicateSymbol(String),
+    OperandCount { expected: usize, found: usize },
+    UnexpectedToken(String),
+    MissingOperand,
+}
+
+/// 汇编错误，行号和列号从1开始
+#[derive(Debug, Clone, PartialEq)]
+pub struct AsmErr {
+    pub line: usize,
+    pub col: usize,
+    pub kind: AsmErrKind,
+}
+
+impl Display for AsmErr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: ", self.line, self.col)?;
+        match &self.kind {
+            AsmErrKind::UnknownMnemonic(s) => write!(f, "unknown mnemonic `{}`", s),
+            AsmErrKind::UnknownDirective(s) => write!(f, "unknown directive `{}`", s),
+            AsmErrKind::InvalidRegister(s) => write!(f, "invalid register `{}`", s),
+            AsmErrKind::InvalidNumber(s) => write!(f, "invalid number `{}`", s),
+            AsmErrKind::InvalidLabel(s) => write!(f, "invalid label `{}`", s),
+            AsmErrKind::UndefinedSymbol(s) => write!(f, "undefined symbol `{}`", s),
+            AsmErrKind::DuplicateSymbol(s) => write!(f, "duplicate symbol `{}`", s),
+            AsmErrKind::OperandCount { expected, found } => {
+                write!(f, "expected {} operands, found {}", expected, found)
+            }
+            AsmErrKind::UnexpectedToken(s) => write!(f, "unexpected `{}`", s),
+            AsmErrKind::MissingOperand => write!(f, "missing operand"),
+        }
+    }
+}
+
+impl std::error::Error for AsmErr {}
+
+/// 汇编的结果，`labels`是标签到代码地址的符号表
+#[derive(Debug, Clone, Default)]
+pub struct Program {
+    pub code: Vec<Inst>,
+    pub labels: BTreeMap<String, UsizeRegType>,
+    /// 调试信息：每条指令所在的源码行号，从1开始
+    pub lines: Vec<usize>,
+}
+
+/// 把代码地址表示为`标签+偏移`，使用地址不大于`addr`的最近的标签
+pub fn symbolize(
+    labels: &BTreeMap<String, UsizeRegType>,
+    addr: UsizeRegType,
+) -> Option<(&str, UsizeRegType)> {
+    labels
+        .iter()
+        .filter(|(_, &a)| a <= addr)
+        .max_by_key(|(_, &a)| a)
+        .map(|(name, &a)| (name.as_str(), addr - a))
+}
+
+/// 把源码汇编为指令序列
+pub fn assemble(src: &str) -> Result<Vec<Inst>, AsmErr> {
+    Ok(assemble_program(src)?.code)
+}
+
+/// 把源码汇编为带符号表的程序
+pub fn assemble_program(src: &str) -> Result<Program, AsmErr> {
+    let lines = src
+        .lines()
+        .enumerate()
+        .map(|(i, l)| parse_line(i + 1, l))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // 第一遍：收集标签和常量
+    let mut labels = BTreeMap::new();
+    let mut consts = HashMap::new();
+    let mut addr = 0;
+    for line in &lines {
+        for label in &line.labels {
+            if labels.contains_key(label.text) || consts.contains_key(label.text) {
+                return Err(label.err(AsmErrKind::DuplicateSymbol(label.text.to_string())));
+            }
+            labels.insert(label.text.to_string(), addr);
+        }
+        match &line.body {
+            Body::Const(name, value) => {
+                if labels.contains_key(name.text) || consts.contains_key(name.text) {
+                    return Err(name.err(AsmErrKind::DuplicateSymbol(name.text.to_string())));
+                }
+                consts.insert(name.text, *value);
+            }
+            Body::Inst(..) => addr += 1,
+            Body::Empty => {}
+        }
+    }
+
+    // 第二遍：翻译指令
+    let symbols = Symbols {
+        labels: &labels,
+        consts: &consts,
+    };
+    let mut code = Vec::with_capacity(addr);
+    let mut line_nos = Vec::with_capacity(addr);
+    for line in &lines {
+        if let Body::Inst(mnemonic, operands) = &line.body {
+            code.push(encode(&symbols, mnemonic, operands)?);
+            line_nos.push(mnemonic.line);
+        }
+    }
+    Ok(Program {
+        code,
+        labels,
+        lines: line_nos,
+    })
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Token<'a> {
+    text: &'a str,
+    line: usize,
+    col: usize,
+}
+
+impl Token<'_> {
+    fn err(&self, kind: AsmErrKind) -> AsmErr {
+        AsmErr {
+            line: self.line,
+            col: self.col,
+            kind,
+        }
+    }
+}
+
+enum Body<'a> {
+    Empty,
+    Const(Token<'a>, Token<'a>),
+    Inst(Token<'a>, Vec<Token<'a>>),
+}
+
+struct Line<'a> {
+    labels: Vec<Token<'a>>,
+    body: Body<'a>,
+}
+
+fn tokenize(line_no: usize, line: &str) -> Vec<Token<'_>> {
+    let line = match line.find(';') {
+        Some(i) => &line[..i],
+        None => line,
+    };
+    let mut tokens = Vec::new();
+    let mut start = None;
+    for (i, c) in line.char_indices() {
+        let sep = c == ',' || c == ':';
+        if c.is_whitespace() || sep {
+            if let Some(s) = start.take() {
+                tokens.push(token(line_no, line, s, i));
+            }
+            if sep {
+                tokens.push(token(line_no, line, i, i + 1));
+            }
+        } else if start.is_none() {
+            start = Some(i);
+        }
+    }
+    if let Some(s) = start {
+        tokens.push(token(line_no, line, s, line.len()));
+    }
+    tokens
+}
+
+fn token<'a>(line_no: usize, line: &'a str, start: usize, end: usize) -> Token<'a> {
+    Token {
+        text: &line[start..end],
+        line: line_no,
+        col: line[..start].chars().count() + 1,
+    }
+}
+
+fn is_ident(s: &str) -> bool {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {}
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+fn parse_line(line_no: usize, line: &str) -> Result<Line<'_>, AsmErr> {
+    let tokens = tokenize(line_no, line);
+    let mut rest = &tokens[..];
+    let mut labels = Vec::new();
+    while rest.len() >= 2 && rest[1].text == ":" {
+        if !is_ident(rest[0].text) {
+            return Err(rest[0].err(AsmErrKind::InvalidLabel(rest[0].text.to_string())));
+        }
+        labels.push(rest[0]);
+        rest = &rest[2..];
+    }
+    let body = match rest.split_first() {
+        None => Body::Empty,
+        Some((head, args)) if head.text.starts_with('.') => {
+            if !head.text.eq_ignore_ascii_case(".const") {
+                return Err(head.err(AsmErrKind::UnknownDirective(head.text.to_string())));
+            }
+            // `.const NAME VALUE`，名字和值之间可以有逗号
+            let args: Vec<_> = args.iter().filter(|t| t.text != ",").collect();
+            match args[..] {
+                [name, value] => {
+                    if !is_ident(name.text) {
+                        return Err(name.err(AsmErrKind::InvalidLabel(name.text.to_string())));
+                    }
+                    if parse_u(value.text).is_none() && value.text.parse::<F64RegType>().is_err() {
+                        return Err(value.err(AsmErrKind::InvalidNumber(value.text.to_string())));
+                    }
+                    Body::Const(*name, *value)
+                }
+                [_, _, extra, ..] => {
+                    return Err(extra.err(AsmErrKind::UnexpectedToken(extra.text.to_string())))
+                }
+                _ => {
+                    return Err(AsmErr {
+                        line: line_no,
+                        col: head.col + head.text.len(),
+                        kind: AsmErrKind::MissingOperand,
+                    })
+                }
+            }
+        }
+        Some((head, args)) => Body::Inst(*head, split_operands(head, args)?),
+    };
+    Ok(Line { labels, body })
+}
+
+/// 检查操作数之间以逗号分隔，返回去掉逗号的操作数
+fn split_operands<'a>(head: &Token<'a>, args: &[Token<'a>]) -> Result<Vec<Token<'a>>, AsmErr> {
+    let mut operands = Vec::new();
+    let mut expect_operand = true;
+    let mut last = *head;
+    for t in args {
+        match (t.text, expect_operand) {
+            (",", true) => return Err(t.err(AsmErrKind::MissingOperand)),
+            (",", false) => expect_operand = true,
+            (":", _) => return Err(t.err(AsmErrKind::UnexpectedToken(t.text.to_string()))),
+            (_, true) => {
+                operands.push(*t);
+                expect_operand = false;
+            }
+            (_, false) => return Err(t.err(AsmErrKind::UnexpectedToken(t.text.to_string()))),
+        }
+        last = *t;
+    }
+    if expect_operand && !args.is_empty() {
+        return Err(AsmErr {
+            line: last.line,
+            col: last.col + 1,
+            kind: AsmErrKind::MissingOperand,
+        });
+    }
+    Ok(operands)
+}
+
+fn parse_u(text: &str) -> Option<UsizeRegType> {
+    let text = text.replace('_', "");
+    let (digits, radix) = if let Some(h) = text.strip_prefix("0x").or(text.strip_prefix("0X")) {
+        (h, 16)
+    } else if let Some(b) = text.strip_prefix("0b").or(text.strip_prefix("0B")) {
+        (b, 2)
+    } else {
+        (text.as_str(), 10)
+    };
+    if digits.is_empty() || !digits.chars().next()?.is_ascii_alphanumeric() {
+        return None;
+    }
+    UsizeRegType::from_str_radix(digits, radix).ok()
+}
+
+struct Symbols<'s, 'a> {
+    labels: &'s BTreeMap<String, UsizeRegType>,
+    consts: &'s HashMap<&'a str, Token<'a>>,
+}
+
+struct Operands<'o, 'a> {
+    symbols: &'o Symbols<'o, 'a>,
+    mnemonic: &'o Token<'a>,
+    tokens: &'o [Token<'a>],
+}
+
+impl Operands<'_, '_> {
+    fn expect(&self, n: usize) -> Result<(), AsmErr> {
+        if self.tokens.len() == n {
+            return Ok(());
+        }
+        let at = self.tokens.get(n).unwrap_or(self.mnemonic);
+        Err(at.err(AsmErrKind::OperandCount {
+            expected: n,
+            found: self.tokens.len(),
+        }))
+    }
+    fn u(&self, i: usize) -> Result<UsizeReg, AsmErr> {
+        let t = &self.tokens[i];
+        parse_reg(t.text, 'u')
+            .map(|i| U_REGS[i])
+            .ok_or_else(|| t.err(AsmErrKind::InvalidRegister(t.text.to_string())))
+    }
+    fn f(&self, i: usize) -> Result<F64Reg, AsmErr> {
+        let t = &self.tokens[i];
+        parse_reg(t.text, 'f')
+            .map(|i| F_REGS[i])
+            .ok_or_else(|| t.err(AsmErrKind::InvalidRegister(t.text.to_string())))
+    }
+    fn imm_u(&self, i: usize) -> Result<UsizeRegType, AsmErr> {
+        let t = &self.tokens[i];
+        if let Some(v) = parse_u(t.text) {
+            return Ok(v);
+        }
+        if let Some(addr) = self.symbols.labels.get(t.text) {
+            return Ok(*addr);
+        }
+        match self.symbols.consts.get(t.text) {
+            Some(c) => {
+                parse_u(c.text).ok_or_else(|| t.err(AsmErrKind::InvalidNumber(c.text.to_string())))
+            }
+            None if is_ident(t.text) => Err(t.err(AsmErrKind::UndefinedSymbol(t.text.to_string()))),
+            None => Err(t.err(AsmErrKind::InvalidNumber(t.text.to_string()))),
+        }
+    }
+    fn imm_f(&self, i: usize) -> Result<F64RegType, AsmErr> {
+        let t = &self.tokens[i];
+        if let Ok(v) = t.text.parse() {
+            return Ok(v);
+        }
+        match self.symbols.consts.get(t.text) {
+            Some(c) => c
+                .text
+                .parse()
+                .ok()
+                .or_else(|| parse_u(c.text).map(|u| u as F64RegType))
+                .ok_or_else(|| t.err(AsmErrKind::InvalidNumber(c.text.to_string()))),
+            None if is_ident(t.text) && !is_float_word(t.text) => {
+                Err(t.err(AsmErrKind::UndefinedSymbol(t.text.to_string())))
+            }
+            None => Err(t.err(AsmErrKind::InvalidNumber(t.text.to_string()))),
+        }
+    }
+}
+
+fn is_float_word(s: &str) -> bool {
+    ["inf", "infinity", "nan"]
+        .iter()
+        .any(|w| s.eq_ignore_ascii_case(w))
+}
+
+fn parse_reg(text: &str, kind: char) -> Option<usize> {
+    let mut chars = text.chars();
+    if chars.next()?.to_ascii_lowercase() != kind {
+        return None;
+    }
+    match chars.as_str() {
+        n @ ("1" | "2" | "3" | "4" | "5" | "6" | "7" | "8") => {
+            n.parse::<usize>().ok().map(|n| n - 1)
+        }
+        _ => None,
+    }
+}
+
+fn encode(symbols: &Symbols, mnemonic: &Token, tokens: &[Token]) -> Result<Inst, AsmErr> {
+    let o = Operands {
+        symbols,
+        mnemonic,
+        tokens,
+    };
+    let m = mnemonic.text.to_ascii_lowercase();
+    let arity = match m.as_str() {
+        "nop" | "ret" | "halt" => 0,
+        "jo" | "jno" | "jmp" | "pushu" | "pushd" | "popu" | "popd" | "call" | "syscall" => 1,
+        "mod" | "addu" | "addd" | "subu" | "subd" | "mulu" | "muld" | "divu" | "divd" | "and"
+        | "or" | "xor" | "je" | "jne" => 3,
+        "mu" | "md" | "movu" | "movd" | "addui" | "adddi" | "subui" | "subdi" | "not" | "negu"
+        | "negd" | "shl" | "shr" | "loaduh" | "loaddh" | "loadus" | "loadds" | "storeuh"
+        | "storedh" | "storeus" | "storeds" | "jz" | "jnz" | "inu" | "ind" | "outu" | "outd" => 2,
+        _ => return Err(mnemonic.err(AsmErrKind::UnknownMnemonic(mnemonic.text.to_string()))),
+    };
+    o.expect(arity)?;
+    let inst = match m.as_str() {
+        "nop" => Inst::Nop,
+        "mu" => Inst::MU(o.u(0)?, o.imm_u(1)?),
+        "md" => Inst::MD(o.f(0)?, o.imm_f(1)?),
+        "movu" => Inst::MovU(o.u(0)?, o.u(1)?),
+        "movd" => Inst::MovD(o.f(0)?, o.f(1)?),
+        "mod" => Inst::Mod(o.u(0)?, o.u(1)?, o.u(2)?),
+        "addu" => Inst::AddU(o.u(0)?, o.u(1)?, o.u(2)?),
+        "addui" => Inst::AddUI(o.u(0)?, o.imm_u(1)?),
+        "addd" => Inst::AddD(o.f(0)?, o.f(1)?, o.f(2)?),
+        "adddi" => Inst::AddDI(o.f(0)?, o.imm_f(1)?),
+        "subu" => Inst::SubU(o.u(0)?, o.u(1)?, o.u(2)?),
+        "subui" => Inst::SubUI(o.u(0)?, o.imm_u(1)?),
+        "subd" => Inst::SubD(o.f(0)?, o.f(1)?, o.f(2)?),
+        "subdi" => Inst::SubDI(o.f(0)?, o.imm_f(1)?),
+        "mulu" => Inst::MulU(o.u(0)?, o.u(1)?, o.u(2)?),
+        "muld" => Inst::MulD(o.f(0)?, o.f(1)?, o.f(2)?),
+        "divu" => Inst::DivU(o.u(0)?, o.u(1)?, o.u(2)?),
+        "divd" => Inst::DivD(o.f(0)?, o.f(1)?, o.f(2)?),
+        "and" => Inst::And(o.u(0)?, o.u(1)?, o.u(2)?),
+        "or" => Inst::Or(o.u(0)?, o.u(1)?, o.u(2)?),
+        "xor" => Inst::Xor(o.u(0)?, o.u(1)?, o.u(2)?),
+        "not" => Inst::Not(o.u(0)?, o.u(1)?),
+        "negu" => Inst::NegU(o.u(0)?, o.u(1)?),
+        "negd" => Inst::NegD(o.f(0)?, o.f(1)?),
+        "shl" => Inst::Shl(o.u(0)?, o.u(1)?),
+        "shr" => Inst::Shr(o.u(0)?, o.u(1)?),
+        "loaduh" => Inst::LoadUH(o.u(0)?, o.u(1)?),
+        "loaddh" => Inst::LoadDH(o.f(0)?, o.u(1)?),
+        "loadus" => Inst::LoadUS(o.u(0)?, o.u(1)?),
+        "loadds" => Inst::LoadDS(o.f(0)?, o.u(1)?),
+        "storeuh" => Inst::StoreUH(o.u(0)?, o.u(1)?),
+        "storedh" => Inst::StoreDH(o.f(0)?, o.u(1)?),
+        "storeus" => Inst::StoreUS(o.u(0)?, o.u(1)?),
+        "storeds" => Inst::StoreDS(o.f(0)?, o.u(1)?),
+        "jo" => Inst::Jo(o.u(0)?),
+        "jno" => Inst::Jno(o.u(0)?),
+        "je" => Inst::Je(o.u(0)?, o.u(1)?, o.u(2)?),
+        "jne" => Inst::Jne(o.u(0)?, o.u(1)?, o.u(2)?),
+        "jz" => Inst::Jz(o.u(0)?, o.u(1)?),
+        "jnz" => Inst::Jnz(o.u(0)?, o.u(1)?),
+        "jmp" => Inst::Jmp(o.u(0)?),
+        "pushu" => Inst::PushU(o.u(0)?),
+        "pushd" => Inst::PushD(o.f(0)?),
+        "popu" => Inst::PopU(o.u(0)?),
+        "popd" => Inst::PopD(o.f(0)?),
+        "call" => Inst::Call(o.u(0)?),
+        "syscall" => Inst::SysCall(o.u(0)?),
+        "inu" => Inst::InU(o.u(0)?, o.u(1)?),
+        "ind" => Inst::InD(o.f(0)?, o.u(1)?),
+        "outu" => Inst::OutU(o.u(0)?, o.u(1)?),
+        "outd" => Inst::OutD(o.f(0)?, o.u(1)?),
+        "ret" => Inst::Ret,
+        "halt" => Inst::Halt,
+        _ => unreachable!(),
+    };
+    Ok(inst)
+}
+
+#[cfg(test)]
+#[test]
+fn test_assemble_fibonacci() {
+    use crate::test::fibonacci_code;
+    let src = "
+        .const N 9
+        mu u8, fib
+        mu u1, N
+        call u8       ; U1=fib(n)
+        halt
+    fib:              ; fib(U1)->U1
+        mu u2, not_zero
+        jnz u2, u1
+        ret
+    not_zero:
+        mu u2, recurse
+        subui u1, 1
+        jnz u2, u1
+        mu u1, 1
+        ret
+    recurse:
+        pushu u1
+        mu u2, fib
+        call u2
+        popu u2
+        pushu u1
+        mu u1, 1
+        subu u1, u2, u1
+        mu u2, fib
+        call u2
+        popu u2
+        addu u1, u2, u1
+        ret
+    ";
+    let program = assemble_program(src).unwrap();
+    assert_eq!(program.code, fibonacci_code(9));
+    assert_eq!(program.labels["fib"], 4);
+    assert_eq!(program.labels["recurse"], 12);
+    assert_eq!(program.lines[..3], [3, 4, 5]);
+}
+#[cfg(test)]
+#[test]
+fn test_assemble_err() {
+    let err = |src| assemble(src).unwrap_err();
+    assert_eq!(
+        err("nop\n  mu u9, 1"),
+        AsmErr {
+            line: 2,
+            col: 6,
+            kind: AsmErrKind::InvalidRegister("u9".to_string())
+        }
+    );
+    assert_eq!(
+        err("  jmp u1, u2").kind,
+        AsmErrKind::OperandCount {
+            expected: 1,
+            found: 2
+        }
+    );
+    assert_eq!(err("mu u1, nowhere").col, 8);
+    assert_eq!(
+        err("a: nop\na: nop").kind,
+        AsmErrKind::DuplicateSymbol("a".to_string())
+    );
+    assert_eq!(
+        err("addu u1 u2, u3").kind,
+        AsmErrKind::UnexpectedToken("u2".to_string())
+    );
+    assert_eq!(
+        err(".org 1").kind,
+        AsmErrKind::UnknownDirective(".org".to_string())
+    );
+    assert_eq!(
+        err("md f1, 1.5x").kind,
+        AsmErrKind::InvalidNumber("1.5x".to_string())
+    );
+    assert_eq!(
+        assemble(".const PI 3.5\nmd f1, PI ; pi\nMU U1, 0x1_0").unwrap(),
+        vec![Inst::MD(F64Reg::F1, 3.5), Inst::MU(UsizeReg::U1, 16)]
+    );
+}
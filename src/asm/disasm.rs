@@ -0,0 +1,347 @@
+//! 反汇编器，输出可以由`assemble`还原为相同的指令序列
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use demo_isa::reg::{F64Reg, UsizeReg, UsizeRegType};
+use demo_isa::Inst;
+
+/// 反汇编指令序列，每行末尾以注释标出地址
+///
+/// 若`MU`加载的值随后被跳转或调用指令用作目标地址，则该值以合成标签`Lxxxx`表示，
+/// 与符号表中的标签重名时加上后缀`_1`、`_2`……
+pub fn disassemble(code: &[Inst]) -> String {
+    disassemble_with_labels(code, &BTreeMap::new())
+}
+
+/// 与`disassemble`相同，但优先使用符号表中的标签名
+pub fn disassemble_with_labels(code: &[Inst], labels: &BTreeMap<String, UsizeRegType>) -> String {
+    let mut names: BTreeMap<UsizeRegType, String> = BTreeMap::new();
+    for (name, addr) in labels {
+        names.entry(*addr).or_insert_with(|| name.clone());
+    }
+    let targets = jump_target_loads(code);
+    for &i in &targets {
+        if let Inst::MU(_, addr) = code[i] {
+            names
+                .entry(addr)
+                .or_insert_with(|| synthetic_name(addr, labels));
+        }
+    }
+
+    let mut out = String::new();
+    for (addr, inst) in code.iter().enumerate() {
+        if let Some(name) = names.get(&addr) {
+            writeln!(out, "{}:", name).unwrap();
+        }
+        let text = match *inst {
+            Inst::MU(reg, v) if targets.contains(&addr) => {
+                format!("mu {}, {}", u(reg), names[&v])
+            }
+            _ => format_inst(inst),
+        };
+        writeln!(out, "    {:<28}; {:04}", text, addr).unwrap();
+    }
+    // 指向代码末尾的标签
+    if let Some(name) = names.get(&code.len()) {
+        writeln!(out, "{}:", name).unwrap();
+    }
+    out
+}
+
+/// 不与符号表中任何标签重名的合成标签
+fn synthetic_name(addr: UsizeRegType, labels: &BTreeMap<String, UsizeRegType>) -> String {
+    let base = format!("L{:04}", addr);
+    (0..)
+        .map(|i| match i {
+            0 => base.clone(),
+            i => format!("{}_{}", base, i),
+        })
+        .find(|name| !labels.contains_key(name))
+        .unwrap()
+}
+
+/// 找出为跳转或调用加载目标地址的`MU`指令的下标
+fn jump_target_loads(code: &[Inst]) -> Vec<usize> {
+    let mut loads = Vec::new();
+    for (i, inst) in code.iter().enumerate() {
+        let addr_reg = match *inst {
+            Inst::Jo(r)
+            | Inst::Jno(r)
+            | Inst::Je(r, _, _)
+            | Inst::Jne(r, _, _)
+            | Inst::Jz(r, _)
+            | Inst::Jnz(r, _)
+            | Inst::Jmp(r)
+            | Inst::Call(r) => r,
+            _ => continue,
+        };
+        // 在同一个基本块内向前查找最近一次写入该寄存器的指令
+        for j in (0..i).rev() {
+            match code[j] {
+                Inst::MU(r, v) if r == addr_reg => {
+                    if v <= code.len() && !loads.contains(&j) {
+                        loads.push(j);
+                    }
+                    break;
+                }
+                inst if writes_u_reg(&inst) == Some(addr_reg) || ends_block(&inst) => break,
+                _ => {}
+            }
+        }
+    }
+    loads
+}
+
+fn ends_block(inst: &Inst) -> bool {
+    matches!(
+        inst,
+        Inst::Jo(_)
+            | Inst::Jno(_)
+            | Inst::Je(..)
+            | Inst::Jne(..)
+            | Inst::Jz(..)
+            | Inst::Jnz(..)
+            | Inst::Jmp(_)
+            | Inst::Call(_)
+            | Inst::SysCall(_)
+            | Inst::Ret
+            | Inst::Halt
+    )
+}
+
+fn writes_u_reg(inst: &Inst) -> Option<UsizeReg> {
+    match *inst {
+        Inst::MU(r, _)
+        | Inst::MovU(r, _)
+        | Inst::Mod(r, _, _)
+        | Inst::AddU(r, _, _)
+        | Inst::AddUI(r, _)
+        | Inst::SubU(r, _, _)
+        | Inst::SubUI(r, _)
+        | Inst::MulU(r, _, _)
+        | Inst::DivU(r, _, _)
+        | Inst::And(r, _, _)
+        | Inst::Or(r, _, _)
+        | Inst::Xor(r, _, _)
+        | Inst::Not(r, _)
+        | Inst::NegU(r, _)
+        | Inst::Shl(r, _)
+        | Inst::Shr(r, _)
+        | Inst::LoadUH(r, _)
+        | Inst::LoadUS(r, _)
+        | Inst::PopU(r)
+        | Inst::InU(r, _) => Some(r),
+        _ => None,
+    }
+}
+
+fn u(reg: UsizeReg) -> String {
+    format!("u{}", reg as usize + 1)
+}
+fn f(reg: F64Reg) -> String {
+    format!("f{}", reg as usize + 1)
+}
+
+/// 指令的助记符
+pub fn mnemonic(inst: &Inst) -> &'static str {
+    match inst {
+        Inst::Nop => "nop",
+        Inst::MU(..) => "mu",
+        Inst::MD(..) => "md",
+        Inst::MovU(..) => "movu",
+        Inst::MovD(..) => "movd",
+        Inst::Mod(..) => "mod",
+        Inst::AddU(..) => "addu",
+        Inst::AddUI(..) => "addui",
+        Inst::AddD(..) => "addd",
+        Inst::AddDI(..) => "adddi",
+        Inst::SubU(..) => "subu",
+        Inst::SubUI(..) => "subui",
+        Inst::SubD(..) => "subd",
+        Inst::SubDI(..) => "subdi",
+        Inst::MulU(..) => "mulu",
+        Inst::MulD(..) => "muld",
+        Inst::DivU(..) => "divu",
+        Inst::DivD(..) => "divd",
+        Inst::And(..) => "and",
+        Inst::Or(..) => "or",
+        Inst::Xor(..) => "xor",
+        Inst::Not(..) => "not",
+        Inst::NegU(..) => "negu",
+        Inst::NegD(..) => "negd",
+        Inst::Shl(..) => "shl",
+        Inst::Shr(..) => "shr",
+        Inst::LoadUH(..) => "loaduh",
+        Inst::LoadDH(..) => "loaddh",
+        Inst::LoadUS(..) => "loadus",
+        Inst::LoadDS(..) => "loadds",
+        Inst::StoreUH(..) => "storeuh",
+        Inst::StoreDH(..) => "storedh",
+        Inst::StoreUS(..) => "storeus",
+        Inst::StoreDS(..) => "storeds",
+        Inst::Jo(..) => "jo",
+        Inst::Jno(..) => "jno",
+        Inst::Je(..) => "je",
+        Inst::Jne(..) => "jne",
+        Inst::Jz(..) => "jz",
+        Inst::Jnz(..) => "jnz",
+        Inst::Jmp(..) => "jmp",
+        Inst::PushU(..) => "pushu",
+        Inst::PushD(..) => "pushd",
+        Inst::PopU(..) => "popu",
+        Inst::PopD(..) => "popd",
+        Inst::Call(..) => "call",
+        Inst::SysCall(..) => "syscall",
+        Inst::InU(..) => "inu",
+        Inst::InD(..) => "ind",
+        Inst::OutU(..) => "outu",
+        Inst::OutD(..) => "outd",
+        Inst::Ret => "ret",
+        Inst::Halt => "halt",
+    }
+}
+
+/// 把单条指令格式化为汇编文本
+pub fn format_inst(inst: &Inst) -> String {
+    let operands = match *inst {
+        Inst::Nop | Inst::Ret | Inst::Halt => vec![],
+        Inst::MU(r, v) | Inst::AddUI(r, v) | Inst::SubUI(r, v) => vec![u(r), v.to_string()],
+        Inst::MD(r, v) | Inst::AddDI(r, v) | Inst::SubDI(r, v) => vec![f(r), format!("{:?}", v)],
+        Inst::MovU(a, b)
+        | Inst::Not(a, b)
+        | Inst::NegU(a, b)
+        | Inst::Shl(a, b)
+        | Inst::Shr(a, b)
+        | Inst::LoadUH(a, b)
+        | Inst::LoadUS(a, b)
+        | Inst::StoreUH(a, b)
+        | Inst::StoreUS(a, b)
+        | Inst::Jz(a, b)
+        | Inst::Jnz(a, b)
+        | Inst::InU(a, b)
+        | Inst::OutU(a, b) => vec![u(a), u(b)],
+        Inst::MovD(a, b) | Inst::NegD(a, b) => vec![f(a), f(b)],
+        Inst::LoadDH(a, b)
+        | Inst::LoadDS(a, b)
+        | Inst::StoreDH(a, b)
+        | Inst::StoreDS(a, b)
+        | Inst::InD(a, b)
+        | Inst::OutD(a, b) => vec![f(a), u(b)],
+        Inst::Mod(a, b, c)
+        | Inst::AddU(a, b, c)
+        | Inst::SubU(a, b, c)
+        | Inst::MulU(a, b, c)
+        | Inst::DivU(a, b, c)
+        | Inst::And(a, b, c)
+        | Inst::Or(a, b, c)
+        | Inst::Xor(a, b, c)
+        | Inst::Je(a, b, c)
+        | Inst::Jne(a, b, c) => vec![u(a), u(b), u(c)],
+        Inst::AddD(a, b, c) | Inst::SubD(a, b, c) | Inst::MulD(a, b, c) | Inst::DivD(a, b, c) => {
+            vec![f(a), f(b), f(c)]
+        }
+        Inst::Jo(a)
+        | Inst::Jno(a)
+        | Inst::Jmp(a)
+        | Inst::PushU(a)
+        | Inst::PopU(a)
+        | Inst::Call(a)
+        | Inst::SysCall(a) => vec![u(a)],
+        Inst::PushD(a) | Inst::PopD(a) => vec![f(a)],
+    };
+    if operands.is_empty() {
+        mnemonic(inst).to_string()
+    } else {
+        format!("{} {}", mnemonic(inst), operands.join(", "))
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_disassemble_round_trip() {
+    use super::{assemble, assemble_program, F_REGS, U_REGS};
+    use crate::test::fibonacci_code;
+    use rand::random;
+
+    let code = fibonacci_code(9);
+    let text = disassemble(&code);
+    assert!(text.contains("L0004:\n"));
+    assert!(text.contains("mu u2, L0012"));
+    assert_eq!(assemble(&text).unwrap(), code);
+
+    // 随机生成覆盖所有变体的指令
+    for _ in 0..100 {
+        let ur = || U_REGS[random::<usize>() % 8];
+        let fr = || F_REGS[random::<usize>() % 8];
+        let code = vec![
+            Inst::Nop,
+            Inst::MU(ur(), random()),
+            Inst::MD(fr(), random::<f64>() * 1e10 - 5e9),
+            Inst::MovU(ur(), ur()),
+            Inst::MovD(fr(), fr()),
+            Inst::Mod(ur(), ur(), ur()),
+            Inst::AddU(ur(), ur(), ur()),
+            Inst::AddUI(ur(), random()),
+            Inst::AddD(fr(), fr(), fr()),
+            Inst::AddDI(fr(), random()),
+            Inst::SubU(ur(), ur(), ur()),
+            Inst::SubUI(ur(), random()),
+            Inst::SubD(fr(), fr(), fr()),
+            Inst::SubDI(fr(), f64::INFINITY),
+            Inst::MulU(ur(), ur(), ur()),
+            Inst::MulD(fr(), fr(), fr()),
+            Inst::DivU(ur(), ur(), ur()),
+            Inst::DivD(fr(), fr(), fr()),
+            Inst::And(ur(), ur(), ur()),
+            Inst::Or(ur(), ur(), ur()),
+            Inst::Xor(ur(), ur(), ur()),
+            Inst::Not(ur(), ur()),
+            Inst::NegU(ur(), ur()),
+            Inst::NegD(fr(), fr()),
+            Inst::Shl(ur(), ur()),
+            Inst::Shr(ur(), ur()),
+            Inst::LoadUH(ur(), ur()),
+            Inst::LoadDH(fr(), ur()),
+            Inst::LoadUS(ur(), ur()),
+            Inst::LoadDS(fr(), ur()),
+            Inst::StoreUH(ur(), ur()),
+            Inst::StoreDH(fr(), ur()),
+            Inst::StoreUS(ur(), ur()),
+            Inst::StoreDS(fr(), ur()),
+            Inst::MU(UsizeReg::U1, random::<usize>() % 60),
+            Inst::Jo(UsizeReg::U1),
+            Inst::Jno(ur()),
+            Inst::Je(ur(), ur(), ur()),
+            Inst::Jne(ur(), ur(), ur()),
+            Inst::Jz(ur(), ur()),
+            Inst::Jnz(ur(), ur()),
+            Inst::Jmp(ur()),
+            Inst::PushU(ur()),
+            Inst::PushD(fr()),
+            Inst::PopU(ur()),
+            Inst::PopD(fr()),
+            Inst::Call(ur()),
+            Inst::SysCall(ur()),
+            Inst::InU(ur(), ur()),
+            Inst::InD(fr(), ur()),
+            Inst::OutU(ur(), ur()),
+            Inst::OutD(fr(), ur()),
+            Inst::Ret,
+            Inst::Halt,
+        ];
+        assert_eq!(assemble(&disassemble(&code)).unwrap(), code);
+    }
+
+    let program = assemble_program("start:\n mu u1, done\n jmp u1\ndone:").unwrap();
+    let text = disassemble_with_labels(&program.code, &program.labels);
+    assert_eq!(
+        text,
+        "start:\n    mu u1, done                 ; 0000\n    jmp u1                      ; 0001\ndone:\n"
+    );
+    // 用户标签`L0003`在别的地址上，合成标签不能与它重名
+    let program =
+        assemble_program("L0003:\n mu u1, 3\n jmp u1\n nop\n halt\n mu u1, L0003").unwrap();
+    let text = disassemble_with_labels(&program.code, &program.labels);
+    assert!(text.contains("L0003_1:\n"));
+    assert_eq!(assemble(&text).unwrap(), program.code);
+}
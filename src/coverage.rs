@@ -0,0 +1,230 @@
+//! 客户程序的代码覆盖率
+//!
+//! `Coverage`作为观察者记录每个代码地址执行的次数，以及每条条件跳转
+//! （`Je`、`Jne`、`Jz`、`Jnz`、`Jo`、`Jno`）跳转和不跳转的次数。
+//! 多次执行的结果可以用`merge`合并，也可以编码后保存，下次执行时读出再合并。
+//!
+//! `lcov`按汇编器给出的行号（见`Program::lines`）把结果输出为lcov格式，供genhtml等工具使用。
+//!
+//! 编码与镜像相同，所有整数均为小端序：
+//! ```text
+//! magic    b"DVMC"
+//! version  u16
+//! counts   u64 数量，随后是每个代码地址：u64 执行次数 + u64 跳转次数 + u64 不跳转次数
+//! ```
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+
+use demo_isa::reg::{Flags, UsizeRegType};
+use demo_isa::Inst;
+
+use crate::cpu::observer::ExecObserver;
+use crate::cpu::CpuCore;
+use crate::image::{ImageErr, Reader, Writer};
+
+pub const COVERAGE_MAGIC: &[u8; 4] = b"DVMC";
+pub const COVERAGE_VERSION: u16 = 1;
+
+/// 每个代码地址的执行次数和条件跳转的去向
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Coverage {
+    hits: Vec<u64>,
+    /// 条件跳转跳转和不跳转的次数，其他指令为0
+    branches: Vec<[u64; 2]>,
+}
+
+impl ExecObserver for Coverage {
+    fn before_inst(&mut self, pc: UsizeRegType, inst: &Inst, core: &CpuCore) {
+        if self.hits.len() <= pc {
+            self.hits.resize(pc + 1, 0);
+            self.branches.resize(pc + 1, [0; 2]);
+        }
+        self.hits[pc] += 1;
+        if let Some(taken) = branch_taken(inst, core) {
+            self.branches[pc][!taken as usize] += 1;
+        }
+    }
+}
+
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage {
+            hits: Vec::new(),
+            branches: Vec::new(),
+        }
+    }
+    /// pc处的指令执行的次数
+    pub fn hits(&self, pc: UsizeRegType) -> u64 {
+        self.hits.get(pc).copied().unwrap_or(0)
+    }
+    /// pc处的条件跳转跳转和不跳转的次数
+    pub fn branch(&self, pc: UsizeRegType) -> [u64; 2] {
+        self.branches.get(pc).copied().unwrap_or_default()
+    }
+    /// 累加另一次执行的结果
+    pub fn merge(&mut self, other: &Coverage) {
+        if self.hits.len() < other.hits.len() {
+            self.hits.resize(other.hits.len(), 0);
+            self.branches.resize(other.hits.len(), [0; 2]);
+        }
+        for (pc, &n) in other.hits.iter().enumerate() {
+            self.hits[pc] += n;
+            self.branches[pc][0] += other.branches[pc][0];
+            self.branches[pc][1] += other.branches[pc][1];
+        }
+    }
+    pub fn encode(&self) -> Vec<u8> {
+        let mut w = Writer::new();
+        w.bytes(COVERAGE_MAGIC);
+        w.u16(COVERAGE_VERSION);
+        w.usize(self.hits.len());
+        for (&n, &[taken, not_taken]) in self.hits.iter().zip(&self.branches) {
+            w.u64(n);
+            w.u64(taken);
+            w.u64(not_taken);
+        }
+        w.finish()
+    }
+    pub fn decode(bytes: &[u8]) -> Result<Coverage, ImageErr> {
+        let mut r = Reader::new(bytes);
+        if r.take(COVERAGE_MAGIC.len())? != COVERAGE_MAGIC {
+            return Err(ImageErr::BadMagic);
+        }
+        let version = r.u16()?;
+        if version != COVERAGE_VERSION {
+            return Err(ImageErr::UnsupportedVersion(version));
+        }
+        let n = r.len(24)?;
+        let mut coverage = Coverage::new();
+        for _ in 0..n {
+            coverage.hits.push(r.u64()?);
+            coverage.branches.push([r.u64()?, r.u64()?]);
+        }
+        r.finish()?;
+        Ok(coverage)
+    }
+    /// 输出`code`的lcov记录，`source`为源文件名，`lines[pc]`为pc处指令的行号
+    ///
+    /// 没有行号的指令按pc + 1计，同一行的多条指令取最大的执行次数。
+    /// `symbols`中的每个标签都作为一个函数，执行次数为其第一条指令的执行次数
+    pub fn lcov(
+        &self,
+        source: &str,
+        code: &[Inst],
+        lines: &[usize],
+        symbols: &BTreeMap<String, UsizeRegType>,
+    ) -> String {
+        let line = |pc: usize| lines.get(pc).copied().unwrap_or(pc + 1);
+        let mut out = String::new();
+        writeln!(out, "TN:\nSF:{}", source).unwrap();
+
+        let funcs: Vec<_> = symbols.iter().filter(|(_, &a)| a < code.len()).collect();
+        for (name, &addr) in &funcs {
+            writeln!(out, "FN:{},{}", line(addr), name).unwrap();
+        }
+        for (name, &addr) in &funcs {
+            writeln!(out, "FNDA:{},{}", self.hits(addr), name).unwrap();
+        }
+        let hit = funcs.iter().filter(|(_, &a)| self.hits(a) > 0).count();
+        writeln!(out, "FNF:{}\nFNH:{}", funcs.len(), hit).unwrap();
+
+        let (mut found, mut hit) = (0, 0);
+        let mut blocks: BTreeMap<usize, usize> = BTreeMap::new();
+        for (pc, inst) in code.iter().enumerate() {
+            if !is_branch(inst) {
+                continue;
+            }
+            let l = line(pc);
+            let block = blocks.entry(l).or_default();
+            for (i, n) in self.branch(pc).into_iter().enumerate() {
+                let taken = if self.hits(pc) == 0 {
+                    "-".to_string()
+                } else {
+                    n.to_string()
+                };
+                writeln!(out, "BRDA:{},{},{},{}", l, block, i, taken).unwrap();
+                found += 1;
+                hit += (n > 0) as usize;
+            }
+            *block += 1;
+        }
+        writeln!(out, "BRF:{}\nBRH:{}", found, hit).unwrap();
+
+        let mut counts: BTreeMap<usize, u64> = BTreeMap::new();
+        for pc in 0..code.len() {
+            let n = counts.entry(line(pc)).or_default();
+            *n = (*n).max(self.hits(pc));
+        }
+        for (l, n) in &counts {
+            writeln!(out, "DA:{},{}", l, n).unwrap();
+        }
+        let hit = counts.values().filter(|&&n| n > 0).count();
+        writeln!(out, "LF:{}\nLH:{}\nend_of_record", counts.len(), hit).unwrap();
+        out
+    }
+}
+
+fn is_branch(inst: &Inst) -> bool {
+    matches!(
+        inst,
+        Inst::Je(..) | Inst::Jne(..) | Inst::Jz(..) | Inst::Jnz(..) | Inst::Jo(_) | Inst::Jno(_)
+    )
+}
+
+/// 按执行前的状态判断条件跳转是否跳转，其他指令返回`None`
+pub fn branch_taken(inst: &Inst, core: &CpuCore) -> Option<bool> {
+    let overflow = || core.get_flags().contains(Flags::Overflow);
+    match *inst {
+        Inst::Je(_, a, b) => Some(core.get_u_reg(a) == core.get_u_reg(b)),
+        Inst::Jne(_, a, b) => Some(core.get_u_reg(a) != core.get_u_reg(b)),
+        Inst::Jz(_, a) => Some(core.get_u_reg(a) == 0),
+        Inst::Jnz(_, a) => Some(core.get_u_reg(a) != 0),
+        Inst::Jo(_) => Some(overflow()),
+        Inst::Jno(_) => Some(!overflow()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_coverage() {
+    use crate::asm::assemble_program;
+    use crate::VmTmp;
+
+    let src = "
+    abs:                    ; u1 = |u1 - u2|
+        mu u8, less
+        subu u3, u1, u2
+        jo u8
+        movu u1, u3
+        halt
+    less:
+        subu u1, u2, u1
+        halt
+    ";
+    let program = assemble_program(src).unwrap();
+    let run = |a, b| {
+        let mut vm = VmTmp::new();
+        vm.set_code(program.code.clone());
+        vm.set_u_reg(demo_isa::reg::UsizeReg::U1, a);
+        vm.set_u_reg(demo_isa::reg::UsizeReg::U2, b);
+        let mut coverage = Coverage::new();
+        vm.start_with(&mut coverage).unwrap();
+        coverage
+    };
+    let mut coverage = run(5, 3);
+    assert_eq!(coverage.branch(2), [0, 1]);
+    assert_eq!(coverage.hits(5), 0);
+    coverage.merge(&run(3, 5));
+    coverage.merge(&run(4, 3));
+    let coverage = Coverage::decode(&coverage.encode()).unwrap();
+    assert_eq!(coverage.branch(2), [1, 2]);
+    assert_eq!(
+        coverage.lcov("abs.dasm", &program.code, &program.lines, &program.labels),
+        "TN:\nSF:abs.dasm\n\
+         FN:3,abs\nFN:9,less\nFNDA:3,abs\nFNDA:1,less\nFNF:2\nFNH:2\n\
+         BRDA:5,0,0,1\nBRDA:5,0,1,2\nBRF:2\nBRH:2\n\
+         DA:3,3\nDA:4,3\nDA:5,3\nDA:6,2\nDA:7,2\nDA:9,1\nDA:10,1\n\
+         LF:7\nLH:7\nend_of_record\n"
+    );
+}
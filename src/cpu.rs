@@ -1,19 +1,48 @@
+pub mod async_call;
 pub mod core;
+pub mod fuel;
+pub mod green;
+#[cfg(feature = "jit")]
+pub(crate) mod jit;
+pub mod observer;
+pub mod replay;
+pub(crate) mod threaded;
 
+use std::collections::BTreeMap;
+use std::fmt;
 
-use demo_isa::{err::ISAErr, reg::Flags};
+use demo_isa::{
+    err::ISAErr,
+    reg::{Flags, UsizeReg, UsizeRegType},
+    Inst, RegType,
+};
 use enumflags2::{make_bitflags, BitFlags};
-#[cfg(debug_assertions)]
-use log::debug;
 
+use crate::asm::{disasm::format_inst, symbolize};
 use crate::memory::{Memory, MemoryErr};
+use crate::port::{PortBus, PortErr};
+use crate::sys_call::SysCallErr;
 
+use self::async_call::AsyncCalls;
 use self::core::Regs;
+use self::fuel::CostTable;
+use self::green::Threads;
+use self::observer::{ExecObserver, NoopObserver};
+use self::replay::{Io, Recording};
+use self::threaded::Op;
 
 #[derive(Debug)]
 pub enum CpuErr {
     MemoryErr(MemoryErr),
     ISAErr(ISAErr),
+    /// 重放时执行到的指令与记录不符，见`replay`
+    Diverged,
+    /// 访问端口出错，见`port`
+    PortErr(PortErr),
+    /// 剩下的绿色线程都在等待，见`green`
+    Deadlock,
+    /// 执行到异步系统调用，执行以`ExitReason::Suspended`停止，见`async_call`
+    Suspend,
 }
 impl From<MemoryErr> for CpuErr {
     fn from(err: MemoryErr) -> CpuErr {
@@ -25,10 +54,107 @@ impl From<ISAErr> for CpuErr {
         CpuErr::ISAErr(err)
     }
 }
+impl From<SysCallErr> for CpuErr {
+    fn from(err: SysCallErr) -> CpuErr {
+        match err {
+            SysCallErr::MemoryErr(e) => CpuErr::MemoryErr(e),
+            SysCallErr::Deadlock => CpuErr::Deadlock,
+            SysCallErr::ISAErr(e) => CpuErr::ISAErr(e),
+            e => CpuErr::ISAErr(e.into()),
+        }
+    }
+}
+impl From<PortErr> for CpuErr {
+    fn from(err: PortErr) -> CpuErr {
+        CpuErr::PortErr(err)
+    }
+}
+/// 正常停止执行的原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitReason {
+    /// 执行了`Halt`
+    Halt,
+    /// pc恰好越过最后一条指令
+    EndOfCode,
+    /// 达到指令数上限，可以继续执行
+    StepLimit,
+    /// 燃料不够执行下一条指令，补充燃料后可以继续执行
+    OutOfFuel,
+    /// 执行到异步系统调用，pc停在这条指令上，完成调用后可以继续执行
+    Suspended,
+}
+/// 一次执行的结果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExitStatus {
+    pub reason: ExitReason,
+    /// 停止时U1的值
+    pub value: UsizeRegType,
+    /// 本次执行的指令数
+    pub steps: usize,
+}
+/// 调用栈中的一帧
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    /// 当前帧为将要执行的地址，其余帧为返回地址
+    pub pc: UsizeRegType,
+    pub bp: UsizeRegType,
+}
+/// 执行出错时的现场
+#[derive(Debug)]
+pub struct Fault {
+    pub err: CpuErr,
+    /// 出错指令的地址
+    pub pc: UsizeRegType,
+    /// 出错的指令，取指失败时为`None`
+    pub inst: Option<Inst>,
+    /// 出错时的调用栈，第一帧的pc为出错指令的地址
+    pub backtrace: Vec<Frame>,
+    /// 与`backtrace`逐帧对应的符号，形如`fib+3`，没有符号表时为空
+    pub symbols: Vec<Option<String>>,
+}
+impl Fault {
+    /// 用符号表解析调用栈中的地址
+    pub fn symbolize(&mut self, labels: &BTreeMap<String, UsizeRegType>) {
+        self.symbols = self
+            .backtrace
+            .iter()
+            .map(|frame| match symbolize(labels, frame.pc)? {
+                (name, 0) => Some(name.to_string()),
+                (name, off) => Some(format!("{}+{}", name, off)),
+            })
+            .collect();
+    }
+    fn location(&self, i: usize) -> String {
+        let pc = self.backtrace[i].pc;
+        match self.symbols.get(i) {
+            Some(Some(sym)) => format!("{:04} <{}>", pc, sym),
+            _ => format!("{:04}", pc),
+        }
+    }
+}
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?} at {}", self.err, self.location(0))?;
+        if let Some(inst) = &self.inst {
+            write!(f, ": {}", format_inst(inst))?;
+        }
+        for i in 0..self.backtrace.len() {
+            write!(f, "\n  #{} {}", i, self.location(i))?;
+        }
+        Ok(())
+    }
+}
 #[derive(Debug)]
 pub struct CpuCore {
     regs: Regs,
     pub flags: BitFlags<Flags>,
+    /// 剩余的燃料，`None`表示不计量
+    fuel: Option<u64>,
+    costs: Box<CostTable>,
+    io: Io,
+    threads: Threads,
+    /// 按调用号登记的异步系统调用，优先于`SYS_CALL_TABLE`
+    async_calls: AsyncCalls,
 }
 impl Default for CpuCore {
     fn default() -> Self {
@@ -42,27 +168,321 @@ impl CpuCore {
         CpuCore {
             regs,
             flags: make_bitflags!(Flags::{}),
+            fuel: None,
+            costs: Box::default(),
+            io: Io::Live,
+            threads: Threads::default(),
+            async_calls: AsyncCalls::default(),
         }
     }
-    pub fn start(&mut self, mem: &mut Memory) -> Result<(), CpuErr> {
+    /// 执行直到停机、越过代码末尾、出错或执行了`limit`条指令
+    pub fn start(
+        &mut self,
+        mem: &mut Memory,
+        bus: &mut PortBus,
+        limit: Option<usize>,
+    ) -> Result<ExitStatus, Fault> {
+        self.start_with(mem, bus, limit, &mut NoopObserver)
+    }
+    /// 同`start`，执行时通知观察者
+    ///
+    /// 设置了时间片时每执行这么多条指令切换一次绿色线程，见`green`
+    pub fn start_with<O: ExecObserver>(
+        &mut self,
+        mem: &mut Memory,
+        bus: &mut PortBus,
+        limit: Option<usize>,
+        obs: &mut O,
+    ) -> Result<ExitStatus, Fault> {
+        let Some(quantum) = self.threads.quantum else {
+            return self.run(mem, bus, limit, obs);
+        };
+        let limit = limit.unwrap_or(usize::MAX);
+        let mut steps = 0;
         loop {
+            let n = quantum.min(limit - steps);
+            let status = self.run(mem, bus, Some(n), obs)?;
+            steps += status.steps;
+            if status.reason != ExitReason::StepLimit || steps >= limit {
+                return Ok(ExitStatus { steps, ..status });
+            }
+            green::yield_now(self, mem);
+        }
+    }
+    /// 执行直到停止，不切换线程
+    fn run<O: ExecObserver>(
+        &mut self,
+        mem: &mut Memory,
+        bus: &mut PortBus,
+        limit: Option<usize>,
+        obs: &mut O,
+    ) -> Result<ExitStatus, Fault> {
+        let limit = limit.unwrap_or(usize::MAX);
+        let metered = self.fuel.is_some();
+        let mut steps = 0;
+        // 上一条指令是否向回跳转，只在循环的入口处尝试JIT
+        #[cfg(feature = "jit")]
+        let mut jumped = true;
+        // 与`step`相同，只是把分派循环展开在这里，省去每条指令的返回值检查
+        let reason = loop {
+            if steps >= limit {
+                break Ok(ExitReason::StepLimit);
+            }
             let pc = self.regs.get_pc();
-            let inst = *mem.fetch_code(pc)?;
-            #[cfg(debug_assertions)]
+            #[cfg(feature = "jit")]
+            if !O::ACTIVE && !metered && jumped {
+                if let Some(n) = jit::run(self, mem, pc, limit - steps) {
+                    steps += n;
+                    continue;
+                }
+            }
+            let Some(&op) = mem.ops().get(pc) else {
+                break self.end_of_code(pc, mem);
+            };
+            // 有观察者、计量燃料或剩余的指令数不够执行融合的指令时逐条执行
+            if O::ACTIVE || metered || op.len() > limit - steps {
+                if metered && !self.burn(pc, mem) {
+                    break Ok(ExitReason::OutOfFuel);
+                }
+                steps += 1;
+                if let Err(e) = self.exec_one(pc, op, mem, bus, obs) {
+                    break self.stop(e, pc, mem);
+                }
+                continue;
+            }
+            self.regs.set_pc(pc + 1);
+            steps += op.len();
+            if let Err(e) = op.exec(self, mem, bus) {
+                // 融合的指令在第二条出错时pc已前移，出错的指令总在当前pc的前一条
+                break self.stop(e, self.regs.get_pc() - 1, mem);
+            }
+            #[cfg(feature = "jit")]
             {
-                debug!("pc: {:?}, inst: {:?}", pc, inst);
-                debug!("regs: {:?}", self.regs);
-                debug!("flags: {:?}", self.flags);
-                debug!("stack: {:?}", mem.load().2);
+                jumped = self.regs.get_pc() <= pc;
             }
+        };
+        match reason {
+            Ok(reason) => Ok(ExitStatus {
+                reason,
+                value: self.get_u_reg(UsizeReg::U1),
+                steps,
+            }),
+            Err(fault) => {
+                obs.on_fault(&fault);
+                Err(fault)
+            }
+        }
+    }
+    /// 执行pc处的一条指令，停机、越过代码末尾、燃料不够或遇到异步系统调用时返回停止的原因
+    pub fn step(
+        &mut self,
+        mem: &mut Memory,
+        bus: &mut PortBus,
+    ) -> Result<Option<ExitReason>, Fault> {
+        self.step_with(mem, bus, &mut NoopObserver)
+    }
+    /// 同`step`，执行时通知观察者
+    pub fn step_with<O: ExecObserver>(
+        &mut self,
+        mem: &mut Memory,
+        bus: &mut PortBus,
+        obs: &mut O,
+    ) -> Result<Option<ExitReason>, Fault> {
+        let pc = self.regs.get_pc();
+        let r = match mem.ops().get(pc) {
+            None => self.end_of_code(pc, mem).map(Some),
+            Some(_) if self.fuel.is_some() && !self.burn(pc, mem) => {
+                Ok(Some(ExitReason::OutOfFuel))
+            }
+            Some(&op) => match self.exec_one(pc, op, mem, bus, obs) {
+                Ok(()) => Ok(None),
+                Err(e) => self.stop(e, pc, mem).map(Some),
+            },
+        };
+        if let Err(fault) = &r {
+            obs.on_fault(fault);
+        }
+        r
+    }
+    /// 不融合地执行pc处的一条指令，并通知观察者
+    #[inline(always)]
+    fn exec_one<O: ExecObserver>(
+        &mut self,
+        pc: UsizeRegType,
+        op: Op,
+        mem: &mut Memory,
+        bus: &mut PortBus,
+        obs: &mut O,
+    ) -> Result<(), CpuErr> {
+        let op = if op.len() > 1 {
+            threaded::decode(pc, &mem.code_segment()[pc])
+        } else {
+            op
+        };
+        if !O::ACTIVE {
             self.regs.set_pc(pc + 1);
-            self.run_inst(&inst, mem)?;
+            return op.exec(self, mem, bus);
+        }
+        let inst = mem.code_segment()[pc];
+        obs.before_inst(pc, &inst, self);
+        if let Some(access) = observer::mem_access(&inst, self, mem) {
+            obs.on_mem(access, mem);
+        }
+        if let Inst::SysCall(r) = inst {
+            obs.on_syscall(pc, self.get_u_reg(r), self);
+        }
+        self.regs.set_pc(pc + 1);
+        op.exec(self, mem, bus)?;
+        match inst {
+            Inst::Call(_) => obs.on_call(pc, self),
+            Inst::Ret => obs.on_ret(pc, self),
+            _ => {}
+        }
+        obs.after_inst(pc, &inst, self);
+        Ok(())
+    }
+    /// pc处没有指令：恰好越过代码末尾时正常停止，否则为非法地址
+    #[cold]
+    fn end_of_code(&self, pc: UsizeRegType, mem: &Memory) -> Result<ExitReason, Fault> {
+        if self.io.unfinished() {
+            return Err(self.fault(
+                CpuErr::Diverged,
+                pc,
+                mem.code_segment().get(pc).copied(),
+                mem,
+            ));
+        }
+        if pc == mem.code_segment().len() {
+            Ok(ExitReason::EndOfCode)
+        } else {
+            Err(self.fault(MemoryErr::InvalidCodeAddr.into(), pc, None, mem))
+        }
+    }
+    /// 指令返回了错误：`Halt`为正常停止，`Suspend`时pc退回这条指令，其余为出错
+    #[cold]
+    fn stop(&mut self, err: CpuErr, pc: UsizeRegType, mem: &Memory) -> Result<ExitReason, Fault> {
+        match err {
+            // 重放时还有记录没有用到就停机
+            CpuErr::ISAErr(ISAErr::Halt) if self.io.unfinished() => {
+                Err(self.fault(CpuErr::Diverged, pc, Some(mem.code_segment()[pc]), mem))
+            }
+            CpuErr::ISAErr(ISAErr::Halt) => Ok(ExitReason::Halt),
+            CpuErr::Suspend => {
+                self.regs.set_pc(pc);
+                Ok(ExitReason::Suspended)
+            }
+            e => Err(self.fault(e, pc, Some(mem.code_segment()[pc]), mem)),
+        }
+    }
+    #[cold]
+    #[inline(never)]
+    fn fault(&self, err: CpuErr, pc: UsizeRegType, inst: Option<Inst>, mem: &Memory) -> Fault {
+        let mut backtrace = self.backtrace(mem);
+        backtrace[0].pc = pc;
+        Fault {
+            err,
+            pc,
+            inst,
+            backtrace,
+            symbols: Vec::new(),
+        }
+    }
+    /// 沿`Call`在栈上保存的bp和返回地址回溯调用栈，第一帧为当前帧
+    ///
+    /// `Call`依次压入调用者的bp和返回地址，并把bp设为返回地址所在的位置，
+    /// 所以`stack[bp]`是返回地址，`stack[bp - 1]`是调用者的bp
+    pub fn backtrace(&self, mem: &Memory) -> Vec<Frame> {
+        let mut frames = vec![Frame {
+            pc: self.get_pc(),
+            bp: self.get_bp(),
+        }];
+        let mut bp = self.get_bp();
+        while bp > 0 {
+            match (mem.get_stack(bp, 0), mem.get_stack(bp - 1, 0)) {
+                (Ok(RegType::Usize(pc)), Ok(RegType::Usize(prev))) if prev < bp => {
+                    frames.push(Frame { pc, bp: prev });
+                    bp = prev;
+                }
+                _ => break,
+            }
+        }
+        frames
+    }
+    /// 扣除执行pc处指令所需的燃料，不够时不扣除并返回假
+    fn burn(&mut self, pc: UsizeRegType, mem: &Memory) -> bool {
+        let inst = &mem.code_segment()[pc];
+        let num = match *inst {
+            Inst::SysCall(r) => Some(self.get_u_reg(r)),
+            _ => None,
+        };
+        let cost = self.costs.cost(inst, num);
+        match self.fuel {
+            Some(fuel) if fuel >= cost => {
+                self.fuel = Some(fuel - cost);
+                true
+            }
+            _ => false,
+        }
+    }
+    /// 设置剩余的燃料，`None`表示不计量
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel;
+    }
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+    /// 补充燃料，不计量时不起作用
+    pub fn add_fuel(&mut self, n: u64) {
+        if let Some(fuel) = &mut self.fuel {
+            *fuel = fuel.saturating_add(n);
+        }
+    }
+    /// 供系统调用按工作量收费，不够时燃料耗尽，在下一条指令之前停止
+    pub fn charge_fuel(&mut self, n: u64) {
+        if let Some(fuel) = &mut self.fuel {
+            *fuel = fuel.saturating_sub(n);
+        }
+    }
+    pub fn set_cost_table(&mut self, costs: CostTable) {
+        *self.costs = costs;
+    }
+    pub fn cost_table(&self) -> &CostTable {
+        &self.costs
+    }
+    /// 设置绿色线程的时间片，`None`表示只在线程让出时切换
+    pub fn set_quantum(&mut self, quantum: Option<usize>) {
+        self.threads.quantum = quantum.map(|n| n.max(1));
+    }
+    pub(crate) fn threads(&self) -> &Threads {
+        &self.threads
+    }
+    pub(crate) fn set_threads(&mut self, threads: Threads) {
+        self.threads = threads;
+    }
+    /// 开始记录系统调用的结果和从端口读到的值，见`replay`
+    pub fn record(&mut self) {
+        self.io = Io::Record(Recording::new());
+    }
+    /// 此后按`rec`重放系统调用的结果和从端口读到的值，不再访问设备
+    pub fn replay(&mut self, rec: Recording) {
+        self.io = Io::Replay { rec, pos: 0 };
+    }
+    /// 回到直接执行，返回记下的内容，重放时返回还没有重放的部分
+    pub fn take_recording(&mut self) -> Option<Recording> {
+        match std::mem::take(&mut self.io) {
+            Io::Live => None,
+            Io::Record(rec) => Some(rec),
+            Io::Replay { mut rec, pos } => {
+                rec.entries.drain(..pos);
+                Some(rec)
+            }
         }
     }
     pub fn reset(&mut self) {
         self.regs.reset();
+        self.threads.reset();
         self.flags = make_bitflags!(Flags::{}); // clear all flags
         self.set_bp(0);
         self.set_pc(0);
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,227 @@
+//! 等待宿主完成的异步系统调用
+//!
+//! 用`CpuCore::register_async_syscall`按调用号登记异步系统调用，登记的调用号优先于
+//! `SYS_CALL_TABLE`，其余调用号照常同步执行。同步执行到异步系统调用时以
+//! `ExitReason::Suspended`停止，pc停在这条指令上。
+//!
+//! `start_async`在停止处等待系统调用返回的future，完成后从下一条指令继续执行，
+//! 返回的future可以交给tokio等任意执行器。等待期间核心和内存被借用，不会执行其他指令。
+//!
+//! 异步系统调用的结果与外部环境有关，记录时记下被改变的寄存器，重放时直接交给程序，
+//! 不调用宿主，见`replay`。异步系统调用不通知观察者。
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+
+use demo_isa::reg::{UsizeReg, UsizeRegType};
+use demo_isa::Inst;
+
+use super::{replay, CpuCore, ExitReason, ExitStatus, Fault};
+use crate::memory::Memory;
+use crate::port::PortBus;
+use crate::sys_call::{AsyncSysCall, SysCallFuture};
+
+/// 按调用号登记的异步系统调用，`Debug`只输出调用号
+#[derive(Default)]
+pub(crate) struct AsyncCalls(pub(crate) BTreeMap<UsizeRegType, AsyncSysCall>);
+
+impl fmt::Debug for AsyncCalls {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set().entries(self.0.keys()).finish()
+    }
+}
+
+impl CpuCore {
+    /// 把`num`登记为异步系统调用，返回该调用号上原有的异步系统调用
+    pub fn register_async_syscall<F>(&mut self, num: usize, call: F) -> Option<AsyncSysCall>
+    where
+        F: for<'a> Fn(&'a mut CpuCore, &'a mut Memory) -> SysCallFuture<'a> + Send + Sync + 'static,
+    {
+        self.async_calls.0.insert(num, Arc::new(call))
+    }
+    /// 取消登记，`num`回到`SYS_CALL_TABLE`中的系统调用
+    pub fn unregister_async_syscall(&mut self, num: usize) -> Option<AsyncSysCall> {
+        self.async_calls.0.remove(&num)
+    }
+    /// 同`start`，遇到异步系统调用时等待它完成再继续执行
+    ///
+    /// 从`Suspended`停止处开始时这条系统调用会再计一次指令数和燃料
+    pub async fn start_async(
+        &mut self,
+        mem: &mut Memory,
+        bus: &mut PortBus,
+        limit: Option<usize>,
+    ) -> Result<ExitStatus, Fault> {
+        let limit = limit.unwrap_or(usize::MAX);
+        let mut steps = 0;
+        loop {
+            let status = self.start(mem, bus, Some(limit - steps))?;
+            steps += status.steps;
+            if status.reason != ExitReason::Suspended {
+                return Ok(ExitStatus { steps, ..status });
+            }
+            if let Some(reason) = self.call_async(mem).await? {
+                return Ok(ExitStatus {
+                    reason,
+                    value: self.get_u_reg(UsizeReg::U1),
+                    steps,
+                });
+            }
+        }
+    }
+    /// 完成pc处的异步系统调用，pc前移到下一条指令，停机时返回`Halt`
+    async fn call_async(&mut self, mem: &mut Memory) -> Result<Option<ExitReason>, Fault> {
+        let pc = self.get_pc();
+        let num = match mem.code_segment().get(pc) {
+            Some(&Inst::SysCall(r)) => self.get_u_reg(r),
+            _ => return Ok(None),
+        };
+        let Some(call) = self.async_calls.0.get(&num).cloned() else {
+            return Ok(None);
+        };
+        self.set_pc(pc + 1);
+        match replay::async_sys_call(self, mem, num, call).await {
+            Ok(()) => Ok(None),
+            Err(e) => self.stop(e, pc, mem).map(Some),
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_async_call() {
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::task::{Context, Poll, Waker};
+
+    use super::CpuErr;
+    use crate::asm::assemble;
+    use crate::sys_call::SysCallErr;
+    use crate::{VmErr, VmTmp};
+    use demo_isa::err::ISAErr;
+
+    /// 第一次轮询时未完成，模拟等待宿主的IO
+    struct Pending(bool);
+    impl Future for Pending {
+        type Output = ();
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+            if self.0 {
+                return Poll::Ready(());
+            }
+            self.0 = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+    fn double<'a>(
+        core: &'a mut CpuCore,
+        _mem: &'a mut Memory,
+    ) -> Pin<Box<dyn Future<Output = Result<(), SysCallErr>> + Send + 'a>> {
+        Box::pin(async move {
+            Pending(false).await;
+            core.set_u_reg(UsizeReg::U5, core.get_u_reg(UsizeReg::U2) * 2);
+            Ok(())
+        })
+    }
+    fn fail<'a>(
+        _core: &'a mut CpuCore,
+        _mem: &'a mut Memory,
+    ) -> Pin<Box<dyn Future<Output = Result<(), SysCallErr>> + Send + 'a>> {
+        Box::pin(async { Err(SysCallErr::InvalidSysCallArg) })
+    }
+    fn host<'a>(
+        _core: &'a mut CpuCore,
+        _mem: &'a mut Memory,
+    ) -> Pin<Box<dyn Future<Output = Result<(), SysCallErr>> + Send + 'a>> {
+        unreachable!("重放时不调用宿主")
+    }
+    /// 返回结果和轮询的次数
+    fn block_on<F: Future + Send>(fut: F) -> (F::Output, usize) {
+        let mut fut = std::pin::pin!(fut);
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut polls = 1;
+        loop {
+            match fut.as_mut().poll(&mut cx) {
+                Poll::Ready(r) => return (r, polls),
+                Poll::Pending => polls += 1,
+            }
+        }
+    }
+
+    let code = assemble(
+        "
+        mu u2, 21
+        mu u8, 8
+        syscall u8          ; 异步
+        movu u1, u5
+        mu u8, 3
+        syscall u8          ; fence，同步
+        halt
+        ",
+    )
+    .unwrap();
+    let mut vm = VmTmp::new();
+    vm.set_code(code.clone());
+    vm.register_async_syscall(8, double);
+    vm.record();
+    let (status, polls) = block_on(vm.run_async());
+    let status = status.unwrap();
+    assert_eq!(polls, 2);
+    assert_eq!(
+        (status.reason, status.value, status.steps),
+        (ExitReason::Halt, 42, 7)
+    );
+    // 重放记下的结果，不等待宿主
+    let rec = vm.take_recording().unwrap();
+    let mut replayed = VmTmp::new();
+    replayed.set_code(code.clone());
+    replayed.register_async_syscall(8, host);
+    replayed.replay(rec);
+    let (status, polls) = block_on(replayed.run_async());
+    assert_eq!((status.unwrap().value, polls), (42, 1));
+
+    // 同步执行停在异步系统调用上
+    vm.set_pc(0);
+    let status = vm.start().unwrap();
+    assert_eq!((status.reason, status.steps), (ExitReason::Suspended, 3));
+    assert_eq!(vm.get_pc(), 2);
+    assert_eq!(block_on(vm.run_async()).0.unwrap().value, 42);
+
+    // 出错时停在系统调用处
+    vm.set_pc(0);
+    vm.register_async_syscall(8, fail);
+    vm.record();
+    assert!(matches!(
+        block_on(vm.run_async()).0,
+        Err(VmErr::Fault(f)) if matches!(f.err, CpuErr::ISAErr(ISAErr::InvalidSysCallArg)) && f.pc == 2
+    ));
+    let mut replayed = VmTmp::new();
+    replayed.set_code(code.clone());
+    replayed.register_async_syscall(8, host);
+    replayed.replay(vm.take_recording().unwrap());
+    assert!(matches!(
+        block_on(replayed.run_async()).0,
+        Err(VmErr::Fault(f)) if matches!(f.err, CpuErr::ISAErr(ISAErr::InvalidSysCallArg)) && f.pc == 2
+    ));
+    // 闭包可以捕获宿主的状态，例如通道或客户端
+    let (tx, rx) = std::sync::mpsc::channel();
+    vm.register_async_syscall(8, move |core, _mem| {
+        let tx = tx.clone();
+        Box::pin(async move {
+            Pending(false).await;
+            tx.send(core.get_u_reg(UsizeReg::U2)).unwrap();
+            core.set_u_reg(UsizeReg::U5, 7);
+            Ok(())
+        })
+    });
+    vm.set_pc(0);
+    assert_eq!(block_on(vm.run_async()).0.unwrap().value, 7);
+    assert_eq!(rx.try_recv(), Ok(21));
+    // 取消登记后调用号8不存在
+    vm.unregister_async_syscall(8);
+    vm.set_pc(0);
+    assert!(matches!(
+        vm.start(),
+        Err(VmErr::Fault(f)) if matches!(f.err, CpuErr::ISAErr(ISAErr::InvalidSysCall))
+    ));
+}
@@ -1,6 +1,7 @@
-use crate::cpu::CpuCore;
+use crate::cpu::{replay, CpuCore, CpuErr};
 use crate::memory::Memory;
-use crate::sys_call::SYS_CALL_TABLE;
+use crate::port::PortBus;
+use crate::sys_call::{EXTERNAL_SYS_CALLS, SYS_CALL_TABLE};
 
 use demo_isa::err::ISAErr;
 use demo_isa::reg::{F64Reg, F64RegType, Flags, UsizeReg, UsizeRegType};
@@ -8,8 +9,13 @@ use demo_isa::{Inst, RegType};
 use enumflags2::{make_bitflags, BitFlags};
 
 impl CpuCore {
-    pub fn run_inst(&mut self, inst: &Inst, mem: &mut Memory) -> Result<(), ISAErr> {
-        run(self, inst, mem)
+    pub fn run_inst(
+        &mut self,
+        inst: &Inst,
+        mem: &mut Memory,
+        bus: &mut PortBus,
+    ) -> Result<(), CpuErr> {
+        run(self, inst, mem, bus)
     }
     pub fn get_u_reg(&self, ur: UsizeReg) -> UsizeRegType {
         self.regs.get_u_reg(ur)
@@ -26,7 +32,7 @@ impl CpuCore {
     pub fn set_u_reg(&mut self, ur: UsizeReg, val: UsizeRegType) {
         self.regs.set_u_reg(ur, val);
     }
-    fn set_f_reg(&mut self, fr: F64Reg, val: F64RegType) {
+    pub fn set_f_reg(&mut self, fr: F64Reg, val: F64RegType) {
         self.regs.set_f_reg(fr, val);
     }
 
@@ -36,20 +42,25 @@ impl CpuCore {
     pub fn set_pc(&mut self, pc: UsizeRegType) {
         self.regs.set_pc(pc);
     }
-    fn get_bp(&self) -> UsizeRegType {
+    pub fn get_bp(&self) -> UsizeRegType {
         self.regs.get_bp()
     }
     pub fn set_bp(&mut self, bp: UsizeRegType) {
         self.regs.set_bp(bp);
     }
-    fn get_flags(&self) -> BitFlags<Flags> {
+    pub fn get_flags(&self) -> BitFlags<Flags> {
         self.flags
     }
-    fn set_flags(&mut self, flags: BitFlags<Flags>) {
+    pub fn set_flags(&mut self, flags: BitFlags<Flags>) {
         self.flags = flags;
     }
 }
-pub(crate) fn run(core: &mut CpuCore, inst: &Inst, memory: &mut Memory) -> Result<(), ISAErr> {
+pub(crate) fn run(
+    core: &mut CpuCore,
+    inst: &Inst,
+    memory: &mut Memory,
+    bus: &mut PortBus,
+) -> Result<(), CpuErr> {
     match *inst {
         Inst::Nop => {}
         Inst::MU(reg, val) => core.set_u_reg(reg, val),
@@ -66,7 +77,7 @@ pub(crate) fn run(core: &mut CpuCore, inst: &Inst, memory: &mut Memory) -> Resul
             let r1 = core.get_u_reg(sur1);
             let r2 = core.get_u_reg(sur2);
             if r2 == 0 {
-                return Err(ISAErr::DivByZero);
+                return Err(ISAErr::DivByZero.into());
             }
             core.set_u_reg(dur, r1 % r2);
         }
@@ -137,7 +148,7 @@ pub(crate) fn run(core: &mut CpuCore, inst: &Inst, memory: &mut Memory) -> Resul
             let r1 = core.get_u_reg(sur1);
             let r2 = core.get_u_reg(sur2);
             if r2 == 0 {
-                return Err(ISAErr::DivByZero);
+                return Err(ISAErr::DivByZero.into());
             }
             core.set_u_reg(dur, r1 / r2);
         }
@@ -203,10 +214,10 @@ pub(crate) fn run(core: &mut CpuCore, inst: &Inst, memory: &mut Memory) -> Resul
             memory.set_heap(
                 core.get_u_reg(reg_a),
                 &RegType::Usize(core.get_u_reg(reg_v)),
-            );
+            )?;
         }
         Inst::StoreDH(reg_v, reg_a) => {
-            memory.set_heap(core.get_u_reg(reg_a), &RegType::F64(core.get_f_reg(reg_v)));
+            memory.set_heap(core.get_u_reg(reg_a), &RegType::F64(core.get_f_reg(reg_v)))?;
         }
         Inst::Jo(addr_reg) => {
             if core.get_flags().contains(Flags::Overflow) {
@@ -255,17 +266,17 @@ pub(crate) fn run(core: &mut CpuCore, inst: &Inst, memory: &mut Memory) -> Resul
             core.set_pc(v);
         }
         Inst::PushU(ureg) => {
-            memory.push_stack(RegType::Usize(core.get_u_reg(ureg)));
+            memory.push_stack(RegType::Usize(core.get_u_reg(ureg)))?;
         }
         Inst::PushD(freg) => {
-            memory.push_stack(RegType::F64(core.get_f_reg(freg)));
+            memory.push_stack(RegType::F64(core.get_f_reg(freg)))?;
         }
         Inst::PopU(ureg) => {
             let v = memory.pop_stack()?;
             if let RegType::Usize(v) = v {
                 core.set_u_reg(ureg, v);
             } else {
-                return Err(ISAErr::TypeMismatch);
+                return Err(ISAErr::TypeMismatch.into());
             }
         }
         Inst::PopD(freg) => {
@@ -273,13 +284,13 @@ pub(crate) fn run(core: &mut CpuCore, inst: &Inst, memory: &mut Memory) -> Resul
             if let RegType::F64(v) = v {
                 core.set_f_reg(freg, v);
             } else {
-                return Err(ISAErr::TypeMismatch);
+                return Err(ISAErr::TypeMismatch.into());
             }
         }
         Inst::Call(ureg) => {
             let addr = core.get_u_reg(ureg);
-            memory.push_stack(RegType::Usize(core.get_bp()));
-            memory.push_stack(RegType::Usize(core.get_pc()));
+            memory.push_stack(RegType::Usize(core.get_bp()))?;
+            memory.push_stack(RegType::Usize(core.get_pc()))?;
             core.set_bp(memory.get_stack_top_addr());
             core.set_pc(addr);
         }
@@ -292,16 +303,19 @@ pub(crate) fn run(core: &mut CpuCore, inst: &Inst, memory: &mut Memory) -> Resul
                     core.set_pc(pc);
                     core.set_bp(bp);
                 }
-                _ => return Err(ISAErr::TypeMismatch),
+                _ => return Err(ISAErr::TypeMismatch.into()),
             }
         }
-        Inst::Halt => return Err(ISAErr::Halt),
+        Inst::Halt => return Err(ISAErr::Halt.into()),
         Inst::SysCall(ureg) => {
-            let sys_call = core.get_u_reg(ureg);
-            if let Some(sys_call) = SYS_CALL_TABLE.get(sys_call) {
-                sys_call(core, memory)?;
+            let num = core.get_u_reg(ureg);
+            if core.async_calls.0.contains_key(&num) {
+                return Err(CpuErr::Suspend);
+            }
+            if let Some(&sys_call) = SYS_CALL_TABLE.get(num) {
+                replay::sys_call(core, memory, num, sys_call, EXTERNAL_SYS_CALLS[num])?;
             } else {
-                return Err(ISAErr::InvalidSysCall);
+                return Err(ISAErr::InvalidSysCall.into());
             }
         }
 
@@ -309,7 +323,7 @@ pub(crate) fn run(core: &mut CpuCore, inst: &Inst, memory: &mut Memory) -> Resul
             if let RegType::Usize(u) = memory.get_stack(core.get_bp(), core.get_u_reg(reg_a))? {
                 core.set_u_reg(reg_v, u);
             } else {
-                return Err(ISAErr::TypeMismatch);
+                return Err(ISAErr::TypeMismatch.into());
             }
         }
         Inst::StoreUS(reg_v, reg_a) => {
@@ -323,7 +337,7 @@ pub(crate) fn run(core: &mut CpuCore, inst: &Inst, memory: &mut Memory) -> Resul
             if let RegType::F64(f) = memory.get_stack(core.get_bp(), core.get_u_reg(reg_a))? {
                 core.set_f_reg(reg_v, f);
             } else {
-                return Err(ISAErr::TypeMismatch);
+                return Err(ISAErr::TypeMismatch.into());
             }
         }
         Inst::StoreDS(reg_v, reg_a) => {
@@ -333,10 +347,22 @@ pub(crate) fn run(core: &mut CpuCore, inst: &Inst, memory: &mut Memory) -> Resul
                 RegType::F64(core.get_f_reg(reg_v)),
             )?;
         }
-        Inst::InD(_, _) => todo!(),
-        Inst::OutD(_, _) => todo!(), //TODO:实现Out
-        Inst::InU(_, _) => todo!(),  //TODO:实现In
-        Inst::OutU(_, _) => todo!(),
+        Inst::InU(reg_v, reg_p) => {
+            let v = replay::read_u(core, bus, core.get_u_reg(reg_p))?;
+            core.set_u_reg(reg_v, v);
+        }
+        Inst::InD(reg_v, reg_p) => {
+            let v = replay::read_f(core, bus, core.get_u_reg(reg_p))?;
+            core.set_f_reg(reg_v, v);
+        }
+        Inst::OutU(reg_v, reg_p) => {
+            let (port, val) = (core.get_u_reg(reg_p), core.get_u_reg(reg_v));
+            replay::write(core, port, || bus.write_u(port, val))?;
+        }
+        Inst::OutD(reg_v, reg_p) => {
+            let (port, val) = (core.get_u_reg(reg_p), core.get_f_reg(reg_v));
+            replay::write(core, port, || bus.write_f(port, val))?;
+        }
     }
     Ok(())
 }
@@ -546,6 +572,31 @@ impl Regs {
 
         self.f64_regs[reg as usize] = val;
     }
+    #[cfg(feature = "jit")]
+    pub(crate) fn u_regs_mut(&mut self) -> &mut [UsizeRegType; 8] {
+        &mut self.usize_regs
+    }
+    #[cfg(feature = "jit")]
+    pub(crate) fn f_regs_mut(&mut self) -> &mut [F64RegType; 8] {
+        &mut self.f64_regs
+    }
+    /// 按预解码的下标访问寄存器，`& 7`让编译器省去边界检查
+    #[inline(always)]
+    pub(crate) fn u(&self, i: u8) -> UsizeRegType {
+        self.usize_regs[i as usize & 7]
+    }
+    #[inline(always)]
+    pub(crate) fn set_u(&mut self, i: u8, val: UsizeRegType) {
+        self.usize_regs[i as usize & 7] = val;
+    }
+    #[inline(always)]
+    pub(crate) fn f(&self, i: u8) -> F64RegType {
+        self.f64_regs[i as usize & 7]
+    }
+    #[inline(always)]
+    pub(crate) fn set_f(&mut self, i: u8, val: F64RegType) {
+        self.f64_regs[i as usize & 7] = val;
+    }
     pub fn get_bp(&self) -> UsizeRegType {
         self.bp
     }
@@ -0,0 +1,109 @@
+//! 燃料计量
+//!
+//! 开启计量后每条指令执行前按`CostTable`扣除燃料，系统调用另按调用号加收，
+//! 系统调用本身也可以用`CpuCore::charge_fuel`按工作量收费。
+//! 剩余的燃料不够执行下一条指令时停止并返回`ExitReason::OutOfFuel`，pc停在这条指令上，
+//! 补充燃料后可以从原处继续执行。
+use demo_isa::reg::UsizeRegType;
+use demo_isa::Inst;
+
+use crate::image::{opcode, OPCODES};
+
+/// 按指令种类记录的燃料消耗
+#[derive(Debug, Clone)]
+pub struct CostTable {
+    inst: [u64; OPCODES],
+    /// 按系统调用号加收的燃料
+    syscall: Vec<u64>,
+}
+
+impl Default for CostTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CostTable {
+    /// 每条指令消耗1，系统调用不加收
+    pub fn new() -> CostTable {
+        CostTable {
+            inst: [1; OPCODES],
+            syscall: Vec::new(),
+        }
+    }
+    /// 设置与`inst`同种的指令的消耗，忽略操作数
+    pub fn set(&mut self, inst: &Inst, cost: u64) {
+        self.inst[opcode(inst) as usize] = cost;
+    }
+    /// 设置系统调用`num`在`SysCall`指令之外加收的燃料
+    pub fn set_syscall(&mut self, num: UsizeRegType, extra: u64) {
+        if self.syscall.len() <= num {
+            self.syscall.resize(num + 1, 0);
+        }
+        self.syscall[num] = extra;
+    }
+    /// 执行`inst`所需的燃料，`num`为`SysCall`的调用号
+    pub fn cost(&self, inst: &Inst, num: Option<UsizeRegType>) -> u64 {
+        let extra = num.and_then(|n| self.syscall.get(n)).copied().unwrap_or(0);
+        self.inst[opcode(inst) as usize].saturating_add(extra)
+    }
+    /// 按操作码排列的指令消耗和按调用号排列的加收，供快照使用
+    pub(crate) fn raw(&self) -> (&[u64; OPCODES], &[u64]) {
+        (&self.inst, &self.syscall)
+    }
+    pub(crate) fn from_raw(inst: [u64; OPCODES], syscall: Vec<u64>) -> CostTable {
+        CostTable { inst, syscall }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_out_of_fuel() {
+    use super::ExitReason;
+    use crate::asm::assemble;
+    use crate::VmTmp;
+    use demo_isa::reg::UsizeReg;
+
+    let code = assemble(
+        "
+        mu u1, 10
+        mu u3, 3
+    loop:
+        divu u2, u1, u3
+        subui u1, 1
+        mu u4, loop
+        jnz u4, u1
+        halt
+        ",
+    )
+    .unwrap();
+    let mut vm = VmTmp::new();
+    vm.set_code(code.clone());
+    let expected = vm.start().unwrap();
+
+    let mut costs = CostTable::new();
+    costs.set(&Inst::DivU(UsizeReg::U1, UsizeReg::U1, UsizeReg::U1), 5);
+    let mut vm = VmTmp::new();
+    vm.set_code(code);
+    vm.set_cost_table(costs);
+    vm.set_fuel(Some(7));
+    let status = vm.start().unwrap();
+    // 两条`mu`之后剩5，恰好够一次除法，`subui`时耗尽
+    assert_eq!((status.reason, status.steps), (ExitReason::OutOfFuel, 3));
+    assert_eq!((vm.get_pc(), vm.fuel()), (3, Some(0)));
+    let (mut steps, mut added) = (status.steps, 7);
+    loop {
+        vm.add_fuel(4);
+        added += 4;
+        let status = vm.start().unwrap();
+        steps += status.steps;
+        if status.reason != ExitReason::OutOfFuel {
+            assert_eq!(status.reason, ExitReason::Halt);
+            break;
+        }
+    }
+    assert_eq!(steps, expected.steps);
+    assert_eq!(vm.get_u_reg(UsizeReg::U1), 0);
+    // 每次除法多消耗4
+    assert_eq!(added - vm.fuel().unwrap(), expected.steps as u64 + 4 * 10);
+}
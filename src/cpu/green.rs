@@ -0,0 +1,431 @@
+//! 客户程序的绿色线程
+//!
+//! 线程由系统调用创建和切换，见`sys_call`：
+//! ```text
+//! 4 spawn  从U2处开始一个新线程，新线程的U1为U3，返回U5为线程号
+//! 5 yield  让出核心，切换到下一个就绪的线程
+//! 6 join   等待U2号线程结束，返回U5为它的退出值
+//! 7 exit   以U2为退出值结束当前线程，最后一个线程结束时停机，U1为退出值
+//! ```
+//! 每个线程有自己的寄存器、标志位和栈，堆、燃料和端口由所有线程共享。
+//! 主线程的线程号为0，线程号不会重复使用。新线程的栈为空，只能用`exit`结束。
+//! 创建过的线程数受`Limits::max_threads`限制，所有线程的栈一起受`Limits::max_stack`限制。
+//!
+//! 切换在系统调用之内完成：把当前线程的寄存器和栈段存入线程表，再换入下一个线程的。
+//! 调度是协作式的，按线程号轮转；用`CpuCore::set_quantum`设置时间片后，
+//! 线程每执行这么多条指令也会被切换。剩下的线程都在等待时`join`或`exit`以`CpuErr::Deadlock`出错。
+//!
+//! 快照和反向执行的检查点包含整个线程表，恢复后各线程从保存时的状态继续执行。
+use demo_isa::err::ISAErr;
+use demo_isa::reg::{F64RegType, Flags, UsizeReg, UsizeRegType};
+use enumflags2::BitFlags;
+
+use super::CpuCore;
+use crate::asm::{F_REGS, U_REGS};
+use crate::image::{ImageErr, Reader, Writer};
+use crate::memory::{Limits, Memory, MemoryErr, Stack};
+use crate::sys_call::SysCallErr;
+
+pub type ThreadId = UsizeRegType;
+
+/// 不在执行的线程的寄存器和栈
+#[derive(Debug, Clone)]
+struct Context {
+    u: [UsizeRegType; 8],
+    f: [F64RegType; 8],
+    pc: UsizeRegType,
+    bp: UsizeRegType,
+    flags: BitFlags<Flags>,
+    stack: Stack,
+}
+
+impl Context {
+    /// 取出当前线程的状态，栈段被移出
+    fn save(core: &CpuCore, mem: &mut Memory) -> Context {
+        Context {
+            u: U_REGS.map(|r| core.get_u_reg(r)),
+            f: F_REGS.map(|r| core.get_f_reg(r)),
+            pc: core.get_pc(),
+            bp: core.get_bp(),
+            flags: core.flags,
+            stack: std::mem::take(mem.stack_segment_mut()),
+        }
+    }
+    fn load(self, core: &mut CpuCore, mem: &mut Memory) {
+        for (r, v) in U_REGS.into_iter().zip(self.u) {
+            core.set_u_reg(r, v);
+        }
+        for (r, v) in F_REGS.into_iter().zip(self.f) {
+            core.set_f_reg(r, v);
+        }
+        core.set_pc(self.pc);
+        core.set_bp(self.bp);
+        core.flags = self.flags;
+        *mem.stack_segment_mut() = self.stack;
+    }
+    fn encode(&self, w: &mut Writer) {
+        for &v in &self.u {
+            w.usize(v);
+        }
+        for &v in &self.f {
+            w.f64(v);
+        }
+        w.usize(self.pc);
+        w.usize(self.bp);
+        w.u8(self.flags.bits());
+        w.stack(&self.stack);
+    }
+    fn decode(r: &mut Reader) -> Result<Context, ImageErr> {
+        let mut u = [0; 8];
+        for v in &mut u {
+            *v = r.usize()?;
+        }
+        let mut f = [0.0; 8];
+        for v in &mut f {
+            *v = r.f64()?;
+        }
+        let pc = r.usize()?;
+        let bp = r.usize()?;
+        let bits = r.u8()?;
+        let flags =
+            BitFlags::from_bits(bits).map_err(|_| ImageErr::ValueOutOfRange(bits as u64))?;
+        Ok(Context {
+            u,
+            f,
+            pc,
+            bp,
+            flags,
+            stack: r.stack()?,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+enum State {
+    Running,
+    Ready(Context),
+    /// 等待另一个线程结束
+    Joining(ThreadId, Context),
+    Exited(UsizeRegType),
+}
+
+/// 线程表，下标为线程号，只有主线程时为空
+#[derive(Debug, Clone, Default)]
+pub struct Threads {
+    list: Vec<State>,
+    current: ThreadId,
+    pub(crate) quantum: Option<usize>,
+}
+
+impl Threads {
+    /// 还没有结束的线程数
+    fn live(&self) -> usize {
+        match self.list.len() {
+            0 => 1,
+            _ => self
+                .list
+                .iter()
+                .filter(|s| !matches!(s, State::Exited(_)))
+                .count(),
+        }
+    }
+    pub(crate) fn reset(&mut self) {
+        self.list.clear();
+        self.current = 0;
+    }
+    /// 不在执行的线程的栈中元素的总数
+    pub(crate) fn parked_stack(&self) -> usize {
+        self.list
+            .iter()
+            .map(|s| match s {
+                State::Ready(ctx) | State::Joining(_, ctx) => ctx.stack.len(),
+                _ => 0,
+            })
+            .sum()
+    }
+    /// 编码见`snapshot`
+    pub(crate) fn encode(&self, w: &mut Writer) {
+        w.usize(self.current);
+        w.u8(self.quantum.is_some() as u8);
+        w.usize(self.quantum.unwrap_or(0));
+        w.usize(self.list.len());
+        for state in &self.list {
+            match state {
+                State::Running => w.u8(0),
+                State::Ready(ctx) => {
+                    w.u8(1);
+                    ctx.encode(w);
+                }
+                State::Joining(id, ctx) => {
+                    w.u8(2);
+                    w.usize(*id);
+                    ctx.encode(w);
+                }
+                State::Exited(val) => {
+                    w.u8(3);
+                    w.usize(*val);
+                }
+            }
+        }
+    }
+    /// 解码并检查线程表是否一致：当前线程是唯一在执行的线程，等待的线程号都存在
+    pub(crate) fn decode(r: &mut Reader) -> Result<Threads, ImageErr> {
+        let current = r.usize()?;
+        let tag = r.u8()?;
+        let n = r.usize()?;
+        let quantum = match tag {
+            0 => None,
+            1 => Some(n.max(1)),
+            _ => return Err(ImageErr::InvalidTag(tag)),
+        };
+        let n = r.len(1)?;
+        let list = (0..n)
+            .map(|_| {
+                Ok(match r.u8()? {
+                    0 => State::Running,
+                    1 => State::Ready(Context::decode(r)?),
+                    2 => State::Joining(r.usize()?, Context::decode(r)?),
+                    3 => State::Exited(r.usize()?),
+                    tag => return Err(ImageErr::InvalidTag(tag)),
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let running = list.iter().filter(|s| matches!(s, State::Running)).count();
+        let consistent = match list.get(current) {
+            None => list.is_empty() && current == 0,
+            Some(s) => matches!(s, State::Running) && running == 1,
+        };
+        if !consistent {
+            return Err(ImageErr::ValueOutOfRange(current as u64));
+        }
+        for (t, state) in list.iter().enumerate() {
+            if let State::Joining(id, _) = *state {
+                if id >= list.len() || id == t {
+                    return Err(ImageErr::ValueOutOfRange(id as u64));
+                }
+            }
+        }
+        Ok(Threads {
+            list,
+            current,
+            quantum,
+        })
+    }
+    /// 当前线程之后第一个就绪的线程
+    fn next_ready(&self) -> Option<ThreadId> {
+        let n = self.list.len();
+        (1..n)
+            .map(|i| (self.current + i) % n)
+            .find(|&t| matches!(self.list[t], State::Ready(_)))
+    }
+    /// 换入`next`，当前线程的状态已由调用者存入线程表
+    fn switch_to(core: &mut CpuCore, mem: &mut Memory, next: ThreadId) {
+        let State::Ready(ctx) = std::mem::replace(&mut core.threads.list[next], State::Running)
+        else {
+            unreachable!()
+        };
+        core.threads.current = next;
+        ctx.load(core, mem);
+        mem.set_parked_stack(core.threads.parked_stack());
+    }
+    /// 检查线程数和所有线程的栈是否在上限之内，`stack`为当前线程的栈
+    pub(crate) fn check(&self, limits: &Limits, stack: &Stack) -> Result<(), MemoryErr> {
+        if self.list.len() > limits.max_threads {
+            return Err(MemoryErr::ThreadLimit);
+        }
+        if stack.len().saturating_add(self.parked_stack()) > limits.max_stack {
+            return Err(MemoryErr::StackOverflow);
+        }
+        Ok(())
+    }
+}
+
+/// 创建从`entry`开始的线程，返回线程号
+pub(crate) fn spawn(
+    core: &mut CpuCore,
+    mem: &Memory,
+    entry: UsizeRegType,
+    arg: UsizeRegType,
+) -> Result<ThreadId, MemoryErr> {
+    let threads = &mut core.threads;
+    if threads.list.len().max(1) >= mem.limits().max_threads {
+        return Err(MemoryErr::ThreadLimit);
+    }
+    if threads.list.is_empty() {
+        threads.list.push(State::Running);
+    }
+    let mut u = [0; 8];
+    u[UsizeReg::U1 as usize] = arg;
+    threads.list.push(State::Ready(Context {
+        u,
+        f: [0.0; 8],
+        pc: entry,
+        bp: 0,
+        flags: BitFlags::empty(),
+        stack: Stack::new(),
+    }));
+    Ok(threads.list.len() - 1)
+}
+
+/// 切换到下一个就绪的线程，没有时继续执行当前线程
+pub(crate) fn yield_now(core: &mut CpuCore, mem: &mut Memory) {
+    if let Some(next) = core.threads.next_ready() {
+        let ctx = Context::save(core, mem);
+        core.threads.list[core.threads.current] = State::Ready(ctx);
+        Threads::switch_to(core, mem, next);
+    }
+}
+
+/// 等待`id`结束，已结束时立即把退出值写入U5
+pub(crate) fn join(core: &mut CpuCore, mem: &mut Memory, id: ThreadId) -> Result<(), SysCallErr> {
+    let threads = &core.threads;
+    if id == threads.current || id >= threads.list.len() {
+        return Err(SysCallErr::InvalidSysCallArg);
+    }
+    if let State::Exited(val) = threads.list[id] {
+        core.set_u_reg(UsizeReg::U5, val);
+        return Ok(());
+    }
+    let next = threads.next_ready().ok_or(SysCallErr::Deadlock)?;
+    let ctx = Context::save(core, mem);
+    core.threads.list[core.threads.current] = State::Joining(id, ctx);
+    Threads::switch_to(core, mem, next);
+    Ok(())
+}
+
+/// 以`val`结束当前线程，唤醒等待它的线程
+pub(crate) fn exit(
+    core: &mut CpuCore,
+    mem: &mut Memory,
+    val: UsizeRegType,
+) -> Result<(), SysCallErr> {
+    let threads = &mut core.threads;
+    if threads.list.is_empty() {
+        threads.list.push(State::Running);
+    }
+    let current = threads.current;
+    threads.list[current] = State::Exited(val);
+    for state in &mut threads.list {
+        if matches!(state, State::Joining(id, _) if *id == current) {
+            let State::Joining(_, mut ctx) = std::mem::replace(state, State::Running) else {
+                unreachable!()
+            };
+            ctx.u[UsizeReg::U5 as usize] = val;
+            *state = State::Ready(ctx);
+        }
+    }
+    match threads.next_ready() {
+        Some(next) => {
+            Threads::switch_to(core, mem, next);
+            Ok(())
+        }
+        None if threads.live() == 0 => {
+            mem.stack_segment_mut().clear();
+            core.set_u_reg(UsizeReg::U1, val);
+            Err(SysCallErr::ISAErr(ISAErr::Halt))
+        }
+        None => Err(SysCallErr::Deadlock),
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_green_threads() {
+    use crate::asm::assemble_program;
+    use crate::cpu::{CpuErr, ExitReason};
+    use crate::{VmErr, VmTmp};
+
+    // 两个工作线程交替向堆中写入自己的编号，主线程等待它们并把退出值相加
+    let program = assemble_program(
+        "
+        mu u2, worker
+        mu u3, 1
+        mu u8, 4
+        syscall u8          ; spawn
+        movu u6, u5
+        mu u3, 2
+        syscall u8
+        movu u7, u5
+        mu u8, 6
+        movu u2, u6
+        syscall u8          ; join
+        movu u6, u5
+        movu u2, u7
+        syscall u8
+        addu u2, u6, u5
+        mu u8, 7
+        syscall u8          ; exit
+    worker:
+        mu u4, 3
+        mu u3, 1
+    loop:
+        mu u2, 0
+        mu u8, 2
+        syscall u8          ; fetch_add
+        addui u5, 1
+        storeuh u1, u5
+        pushu u5            ; 每个线程有自己的栈
+        mu u8, 5
+        syscall u8          ; yield
+        subui u4, 1
+        mu u8, loop
+        jnz u8, u4
+        mu u2, 10
+        addu u2, u2, u1
+        mu u8, 7
+        syscall u8
+        ",
+    )
+    .unwrap();
+    let run = |quantum| {
+        let mut vm = VmTmp::new();
+        vm.load_image(program.clone().into()).unwrap();
+        vm.set_quantum(quantum);
+        let status = vm.start().unwrap();
+        assert_eq!(status.reason, ExitReason::Halt);
+        assert_eq!(status.value, 23);
+        assert!(vm.stack().is_empty());
+        format!("{:?}", &vm.heap()[1..])
+    };
+    // 协作式调度下两个线程严格交替
+    assert_eq!(
+        run(None),
+        "[R(Usize(1)), R(Usize(2)), R(Usize(1)), R(Usize(2)), R(Usize(1)), R(Usize(2))]"
+    );
+    assert_eq!(run(Some(3)).len(), run(None).len());
+
+    let mut vm = VmTmp::new();
+    vm.set_code(
+        assemble_program("mu u2, 0\nmu u8, 6\nsyscall u8")
+            .unwrap()
+            .code,
+    );
+    assert!(matches!(
+        vm.start(),
+        Err(VmErr::Fault(f)) if matches!(f.err, CpuErr::ISAErr(ISAErr::InvalidSysCallArg))
+    ));
+    // 新线程等待主线程，主线程等待新线程
+    let mut vm = VmTmp::new();
+    vm.set_code(
+        assemble_program(
+            "
+            mu u2, t
+            mu u8, 4
+            syscall u8
+            mu u8, 6
+            movu u2, u5
+            syscall u8
+        t:
+            mu u2, 0
+            mu u8, 6
+            syscall u8
+            ",
+        )
+        .unwrap()
+        .code,
+    );
+    assert!(matches!(
+        vm.start(),
+        Err(VmErr::Fault(f)) if matches!(f.err, CpuErr::Deadlock) && f.pc == 8
+    ));
+}
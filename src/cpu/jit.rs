@@ -0,0 +1,717 @@
+//! 基于Cranelift的JIT，需要`jit`特性
+//!
+//! 在向回跳转到达的pc（循环的入口）处计数，到达`HOT_THRESHOLD`次后把从该pc开始的基本块编译为本机代码。
+//! 基本块为一段只含寄存器运算和堆读写的指令，以一条跳转结束；跳回块首时在本机代码内循环。
+//! 系统调用、栈操作、`Call`/`Ret`等指令不编译，块在它们之前结束，交回解释器执行。
+//! 可能出错的指令（除零、堆类型不符、超过内存上限）先检查，出错时在该指令之前退出，由解释器重新执行并报告错误，
+//! 所以寄存器、标志、内存和执行的指令数与解释器完全一致。
+use std::fmt;
+use std::mem::offset_of;
+
+use cranelift_codegen::ir::condcodes::IntCC;
+use cranelift_codegen::ir::{types, AbiParam, InstBuilder, MemFlags, Signature, Value};
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, FuncId, Linkage, Module};
+use demo_isa::reg::{F64RegType, Flags, UsizeRegType};
+use demo_isa::{Inst, RegType};
+use enumflags2::BitFlags;
+
+use super::CpuCore;
+use crate::memory::Memory;
+
+/// 入口执行多少次后编译
+pub(crate) const HOT_THRESHOLD: u32 = 1000;
+/// 一个块最多包含的指令数
+const MAX_BLOCK: usize = 256;
+/// 更短的块进出本机代码的开销超过收益，不编译
+const MIN_BLOCK: usize = 4;
+
+/// 本机代码与虚拟机交换状态的结构
+#[repr(C)]
+pub(crate) struct Ctx {
+    /// 直接指向`Regs`中的寄存器
+    u: *mut UsizeRegType,
+    f: *mut F64RegType,
+    flags: u64,
+    /// 退出时的pc
+    pc: u64,
+    /// 本次执行的指令数
+    steps: u64,
+    /// 最多可以执行的指令数
+    budget: u64,
+    /// 堆读取的结果
+    scratch: u64,
+    mem: *mut Memory,
+}
+
+pub(crate) type BlockFn = unsafe extern "C" fn(*mut Ctx);
+
+#[derive(Clone, Copy)]
+enum Block {
+    Cold(u32),
+    Compiled {
+        run: BlockFn,
+        len: usize,
+    },
+    /// 入口处的指令不能编译或编译失败
+    Never,
+}
+
+/// 已编译的块及其所在的模块，代码段更新时整体丢弃
+#[derive(Default)]
+pub(crate) struct Jit {
+    module: Option<JITModule>,
+    blocks: Vec<Block>,
+    /// 当前平台不支持
+    unsupported: bool,
+}
+
+impl fmt::Debug for Jit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Jit {{ compiled: {} }}", self.compiled())
+    }
+}
+
+impl Drop for Jit {
+    fn drop(&mut self) {
+        if let Some(module) = self.module.take() {
+            // SAFETY: 块的函数指针只存放在`blocks`中，随之一起丢弃
+            unsafe { module.free_memory() };
+        }
+    }
+}
+
+impl Jit {
+    /// 已编译的块数
+    pub(crate) fn compiled(&self) -> usize {
+        self.blocks
+            .iter()
+            .filter(|b| matches!(b, Block::Compiled { .. }))
+            .count()
+    }
+    /// 记录一次到达`pc`，返回已编译的块及其长度
+    #[inline]
+    pub(crate) fn enter(&mut self, code: &[Inst], pc: usize) -> Option<(BlockFn, usize)> {
+        if self.blocks.len() != code.len() {
+            self.blocks = vec![Block::Cold(0); code.len()];
+        }
+        let block = self.blocks.get_mut(pc)?;
+        match *block {
+            Block::Compiled { run, len } => Some((run, len)),
+            Block::Never => None,
+            Block::Cold(n) if n + 1 < HOT_THRESHOLD => {
+                *block = Block::Cold(n + 1);
+                None
+            }
+            Block::Cold(_) => self.promote(code, pc),
+        }
+    }
+
+    #[cold]
+    fn promote(&mut self, code: &[Inst], pc: usize) -> Option<(BlockFn, usize)> {
+        let compiled = self.compile(code, pc);
+        self.blocks[pc] = match compiled {
+            Some((run, len)) => Block::Compiled { run, len },
+            None => Block::Never,
+        };
+        compiled
+    }
+
+    fn module(&mut self) -> Option<&mut JITModule> {
+        if self.module.is_none() && !self.unsupported {
+            self.module = new_module();
+            self.unsupported = self.module.is_none();
+        }
+        self.module.as_mut()
+    }
+
+    fn compile(&mut self, code: &[Inst], start: usize) -> Option<(BlockFn, usize)> {
+        let mut len = 0;
+        for inst in code[start..].iter().take(MAX_BLOCK) {
+            if is_branch(inst) {
+                len += 1;
+                break;
+            }
+            if !is_straight(inst) {
+                break;
+            }
+            len += 1;
+        }
+        if len < MIN_BLOCK {
+            return None;
+        }
+        let module = self.module()?;
+        let id = translate(module, &code[start..start + len], start).ok()?;
+        module.finalize_definitions().ok()?;
+        let ptr = module.get_finalized_function(id);
+        // SAFETY: 函数按`BlockFn`的签名生成
+        let run = unsafe { std::mem::transmute::<*const u8, BlockFn>(ptr) };
+        Some((run, len))
+    }
+}
+
+/// 在`pc`处尝试执行已编译的块，返回执行的指令数；没有可用的块或一条也没有执行时返回`None`
+#[inline(never)]
+pub(crate) fn run(core: &mut CpuCore, mem: &mut Memory, pc: usize, budget: usize) -> Option<usize> {
+    let (run, len) = mem.jit_enter(pc)?;
+    if len > budget {
+        return None;
+    }
+    let mut ctx = Ctx {
+        u: core.regs.u_regs_mut().as_mut_ptr(),
+        f: core.regs.f_regs_mut().as_mut_ptr(),
+        flags: core.flags.bits() as u64,
+        pc: pc as u64,
+        steps: 0,
+        budget: budget as u64,
+        scratch: 0,
+        mem,
+    };
+    // SAFETY: `ctx`及其指向的寄存器和内存在调用期间有效，且不会经其他途径访问
+    unsafe { run(&mut ctx) };
+    if ctx.steps == 0 {
+        return None;
+    }
+    core.flags = BitFlags::from_bits_truncate(ctx.flags as u8);
+    core.regs.set_pc(ctx.pc as usize);
+    Some(ctx.steps as usize)
+}
+
+fn is_branch(inst: &Inst) -> bool {
+    matches!(
+        inst,
+        Inst::Jo(..)
+            | Inst::Jno(..)
+            | Inst::Je(..)
+            | Inst::Jne(..)
+            | Inst::Jz(..)
+            | Inst::Jnz(..)
+            | Inst::Jmp(..)
+    )
+}
+
+/// 可以编译的非跳转指令
+fn is_straight(inst: &Inst) -> bool {
+    !is_branch(inst)
+        && !matches!(
+            inst,
+            Inst::LoadUS(..)
+                | Inst::LoadDS(..)
+                | Inst::StoreUS(..)
+                | Inst::StoreDS(..)
+                | Inst::PushU(..)
+                | Inst::PushD(..)
+                | Inst::PopU(..)
+                | Inst::PopD(..)
+                | Inst::Call(..)
+                | Inst::Ret
+                | Inst::Halt
+                | Inst::SysCall(..)
+                | Inst::InU(..)
+                | Inst::InD(..)
+                | Inst::OutU(..)
+                | Inst::OutD(..)
+        )
+}
+
+fn new_module() -> Option<JITModule> {
+    let mut flags = settings::builder();
+    flags.set("use_colocated_libcalls", "false").ok()?;
+    flags.set("is_pic", "false").ok()?;
+    flags.set("opt_level", "speed").ok()?;
+    let isa = cranelift_native::builder()
+        .ok()?
+        .finish(settings::Flags::new(flags))
+        .ok()?;
+    let mut builder = JITBuilder::with_isa(isa, default_libcall_names());
+    builder.symbol("demo_vm_load_u", load_u as *const u8);
+    builder.symbol("demo_vm_load_f", load_f as *const u8);
+    builder.symbol("demo_vm_store_u", store_u as *const u8);
+    builder.symbol("demo_vm_store_f", store_f as *const u8);
+    Some(JITModule::new(builder))
+}
+
+extern "C" fn load_u(ctx: *mut Ctx, addr: UsizeRegType) -> u32 {
+    // SAFETY: 只在执行块期间由本机代码调用，`ctx`和`mem`有效
+    let ctx = unsafe { &mut *ctx };
+    match unsafe { &mut *ctx.mem }.get_heap_u_type(addr) {
+        Ok(v) => {
+            ctx.scratch = v as u64;
+            0
+        }
+        Err(_) => 1,
+    }
+}
+extern "C" fn load_f(ctx: *mut Ctx, addr: UsizeRegType) -> u32 {
+    // SAFETY: 同`load_u`
+    let ctx = unsafe { &mut *ctx };
+    match unsafe { &mut *ctx.mem }.get_heap_f_type(addr) {
+        Ok(v) => {
+            ctx.scratch = v.to_bits();
+            0
+        }
+        Err(_) => 1,
+    }
+}
+extern "C" fn store_u(ctx: *mut Ctx, addr: UsizeRegType, val: UsizeRegType) -> u32 {
+    // SAFETY: 同`load_u`
+    let mem = unsafe { &mut *(*ctx).mem };
+    mem.set_heap(addr, &RegType::Usize(val)).is_err() as u32
+}
+extern "C" fn store_f(ctx: *mut Ctx, addr: UsizeRegType, val: F64RegType) -> u32 {
+    // SAFETY: 同`load_u`
+    let mem = unsafe { &mut *(*ctx).mem };
+    mem.set_heap(addr, &RegType::F64(val)).is_err() as u32
+}
+
+const FLAGS: usize = 16;
+const STEPS: usize = 17;
+
+/// 生成代码时记录块读写了哪些寄存器，入口只读取用到的，出口只写回改过的
+struct Gen<'a> {
+    b: FunctionBuilder<'a>,
+    ptr: types::Type,
+    read: [bool; STEPS + 1],
+    written: [bool; STEPS + 1],
+}
+
+impl Gen<'_> {
+    fn u(&mut self, r: u8) -> Value {
+        self.get(r as usize)
+    }
+    fn f(&mut self, r: u8) -> Value {
+        self.get(8 + r as usize)
+    }
+    fn set_u(&mut self, r: u8, v: Value) {
+        self.set(r as usize, v);
+    }
+    fn set_f(&mut self, r: u8, v: Value) {
+        self.set(8 + r as usize, v);
+    }
+    fn get(&mut self, i: usize) -> Value {
+        self.read[i] = true;
+        self.b.use_var(Variable::from_u32(i as u32))
+    }
+    fn set(&mut self, i: usize, v: Value) {
+        self.read[i] = true;
+        self.written[i] = true;
+        self.b.def_var(Variable::from_u32(i as u32), v);
+    }
+    fn ty(&self, i: usize) -> types::Type {
+        match i {
+            8..=15 => types::F64,
+            FLAGS => types::I64,
+            _ => self.ptr,
+        }
+    }
+    /// 溢出时目的寄存器不变并置溢出标志
+    fn checked(&mut self, r: u8, (v, of): (Value, Value), overflow: i64) {
+        let old = self.u(r);
+        let new = self.b.ins().select(of, old, v);
+        self.set_u(r, new);
+        let flags = self.get(FLAGS);
+        let set = self.b.ins().iconst(types::I64, overflow);
+        let flags = self.b.ins().select(of, set, flags);
+        self.set(FLAGS, flags);
+    }
+}
+
+/// 把`insts`翻译为一个函数，`start`为第一条指令的地址
+fn translate(module: &mut JITModule, insts: &[Inst], start: usize) -> Result<FuncId, String> {
+    let ptr = module.target_config().pointer_type();
+    let mut sig = module.make_signature();
+    sig.params.push(AbiParam::new(ptr));
+    // 辅助函数出错时返回非0
+    let mut helper = |name: &str, params: &[types::Type]| {
+        let mut s = Signature::new(sig.call_conv);
+        s.params.extend(params.iter().map(|&t| AbiParam::new(t)));
+        s.returns.push(AbiParam::new(types::I32));
+        module
+            .declare_function(name, Linkage::Import, &s)
+            .map_err(|e| e.to_string())
+    };
+    let load_u = helper("demo_vm_load_u", &[ptr, ptr])?;
+    let load_f = helper("demo_vm_load_f", &[ptr, ptr])?;
+    let store_u = helper("demo_vm_store_u", &[ptr, ptr, ptr])?;
+    let store_f = helper("demo_vm_store_f", &[ptr, ptr, types::F64])?;
+
+    let id = module
+        .declare_anonymous_function(&sig)
+        .map_err(|e| e.to_string())?;
+    let mut ctx = module.make_context();
+    ctx.func.signature = sig;
+    let mut fctx = FunctionBuilderContext::new();
+    let mut g = Gen {
+        b: FunctionBuilder::new(&mut ctx.func, &mut fctx),
+        ptr,
+        read: [false; STEPS + 1],
+        written: [false; STEPS + 1],
+    };
+    let load_u = module.declare_func_in_func(load_u, g.b.func);
+    let load_f = module.declare_func_in_func(load_f, g.b.func);
+    let store_u = module.declare_func_in_func(store_u, g.b.func);
+    let store_f = module.declare_func_in_func(store_f, g.b.func);
+    for i in 0..=STEPS {
+        let ty = g.ty(i);
+        g.b.declare_var(Variable::from_u32(i as u32), ty);
+    }
+
+    let entry = g.b.create_block();
+    let head = g.b.create_block();
+    let exit = g.b.create_block();
+    g.b.append_block_params_for_function_params(entry);
+    g.b.append_block_param(exit, ptr); // pc
+    g.b.append_block_param(exit, ptr); // steps
+    let cp = g.b.block_params(entry)[0];
+    let mem = MemFlags::trusted();
+    let overflow = BitFlags::from_flag(Flags::Overflow).bits() as i64;
+
+    // 先生成块体，知道用到了哪些寄存器后再生成入口；入口块仍须排在最前
+    g.b.func.layout.append_block(entry);
+    g.b.switch_to_block(head);
+    let len = insts.len() as i64;
+    // 在第`i`条指令之前退出
+    let bail = |g: &mut Gen, i: usize, fault: Value| {
+        let steps = g.get(STEPS);
+        let steps = g.b.ins().iadd_imm(steps, i as i64);
+        let pc = g.b.ins().iconst(ptr, (start + i) as i64);
+        let cont = g.b.create_block();
+        g.b.ins().brif(fault, exit, &[pc, steps], cont, &[]);
+        g.b.switch_to_block(cont);
+    };
+    let mut next_pc = None;
+    for (i, inst) in insts.iter().enumerate() {
+        let pc = start + i;
+        match *inst {
+            Inst::Nop => {}
+            Inst::MU(r, v) => {
+                let v = g.b.ins().iconst(ptr, v as i64);
+                g.set_u(r as u8, v);
+            }
+            Inst::MD(r, v) => {
+                let v = g.b.ins().f64const(v);
+                g.set_f(r as u8, v);
+            }
+            Inst::MovU(d, s) => {
+                let v = g.u(s as u8);
+                g.set_u(d as u8, v);
+            }
+            Inst::MovD(d, s) => {
+                let v = g.f(s as u8);
+                g.set_f(d as u8, v);
+            }
+            Inst::Mod(d, x, y) | Inst::DivU(d, x, y) => {
+                let y = g.u(y as u8);
+                let fault = g.b.ins().icmp_imm(IntCC::Equal, y, 0);
+                bail(&mut g, i, fault);
+                let x = g.u(x as u8);
+                let v = if matches!(inst, Inst::Mod(..)) {
+                    g.b.ins().urem(x, y)
+                } else {
+                    g.b.ins().udiv(x, y)
+                };
+                g.set_u(d as u8, v);
+            }
+            Inst::AddU(d, x, y) | Inst::SubU(d, x, y) | Inst::MulU(d, x, y) => {
+                let x = g.u(x as u8);
+                let y = g.u(y as u8);
+                let r = match inst {
+                    Inst::AddU(..) => g.b.ins().uadd_overflow(x, y),
+                    Inst::SubU(..) => g.b.ins().usub_overflow(x, y),
+                    _ => g.b.ins().umul_overflow(x, y),
+                };
+                g.checked(d as u8, r, overflow);
+            }
+            Inst::AddUI(d, imm) | Inst::SubUI(d, imm) => {
+                let x = g.u(d as u8);
+                let y = g.b.ins().iconst(ptr, imm as i64);
+                let r = match inst {
+                    Inst::AddUI(..) => g.b.ins().uadd_overflow(x, y),
+                    _ => g.b.ins().usub_overflow(x, y),
+                };
+                g.checked(d as u8, r, overflow);
+            }
+            Inst::AddD(d, x, y)
+            | Inst::SubD(d, x, y)
+            | Inst::MulD(d, x, y)
+            | Inst::DivD(d, x, y) => {
+                let x = g.f(x as u8);
+                let y = g.f(y as u8);
+                let v = match inst {
+                    Inst::AddD(..) => g.b.ins().fadd(x, y),
+                    Inst::SubD(..) => g.b.ins().fsub(x, y),
+                    Inst::MulD(..) => g.b.ins().fmul(x, y),
+                    _ => g.b.ins().fdiv(x, y),
+                };
+                g.set_f(d as u8, v);
+            }
+            Inst::AddDI(d, imm) | Inst::SubDI(d, imm) => {
+                let x = g.f(d as u8);
+                let y = g.b.ins().f64const(imm);
+                let v = match inst {
+                    Inst::AddDI(..) => g.b.ins().fadd(x, y),
+                    _ => g.b.ins().fsub(x, y),
+                };
+                g.set_f(d as u8, v);
+            }
+            Inst::And(d, x, y) | Inst::Or(d, x, y) | Inst::Xor(d, x, y) => {
+                let x = g.u(x as u8);
+                let y = g.u(y as u8);
+                let v = match inst {
+                    Inst::And(..) => g.b.ins().band(x, y),
+                    Inst::Or(..) => g.b.ins().bor(x, y),
+                    _ => g.b.ins().bxor(x, y),
+                };
+                g.set_u(d as u8, v);
+            }
+            Inst::Not(d, s) => {
+                let v = g.u(s as u8);
+                let v = g.b.ins().bnot(v);
+                g.set_u(d as u8, v);
+            }
+            Inst::NegU(d, s) => {
+                // 除0以外取负都会溢出
+                let v = g.u(s as u8);
+                let of = g.b.ins().icmp_imm(IntCC::NotEqual, v, 0);
+                let zero = g.b.ins().iconst(ptr, 0);
+                g.checked(d as u8, (zero, of), overflow);
+            }
+            Inst::NegD(d, s) => {
+                let v = g.f(s as u8);
+                let v = g.b.ins().fneg(v);
+                g.set_f(d as u8, v);
+            }
+            Inst::Shl(d, s) | Inst::Shr(d, s) => {
+                // 移位1位不会溢出
+                let v = g.u(s as u8);
+                let v = match inst {
+                    Inst::Shl(..) => g.b.ins().ishl_imm(v, 1),
+                    _ => g.b.ins().ushr_imm(v, 1),
+                };
+                g.set_u(d as u8, v);
+            }
+            Inst::LoadUH(_, a) | Inst::LoadDH(_, a) => {
+                let addr = g.u(a as u8);
+                let (helper, dst, ty) = match *inst {
+                    Inst::LoadUH(v, _) => (load_u, v as usize, ptr),
+                    Inst::LoadDH(v, _) => (load_f, 8 + v as usize, types::F64),
+                    _ => unreachable!(),
+                };
+                let call = g.b.ins().call(helper, &[cp, addr]);
+                let fault = g.b.inst_results(call)[0];
+                bail(&mut g, i, fault);
+                let v = g.b.ins().load(ty, mem, cp, offset_of!(Ctx, scratch) as i32);
+                g.set(dst, v);
+            }
+            Inst::StoreUH(v, a) => {
+                let addr = g.u(a as u8);
+                let v = g.u(v as u8);
+                let call = g.b.ins().call(store_u, &[cp, addr, v]);
+                let fault = g.b.inst_results(call)[0];
+                bail(&mut g, i, fault);
+            }
+            Inst::StoreDH(v, a) => {
+                let addr = g.u(a as u8);
+                let v = g.f(v as u8);
+                let call = g.b.ins().call(store_f, &[cp, addr, v]);
+                let fault = g.b.inst_results(call)[0];
+                bail(&mut g, i, fault);
+            }
+            Inst::Jmp(a) => next_pc = Some(g.u(a as u8)),
+            Inst::Jo(a) | Inst::Jno(a) => {
+                let flags = g.get(FLAGS);
+                let set = g.b.ins().band_imm(flags, overflow);
+                let target = g.u(a as u8);
+                let fall = g.b.ins().iconst(ptr, (pc + 1) as i64);
+                next_pc = Some(if matches!(inst, Inst::Jo(..)) {
+                    g.b.ins().select(set, target, fall)
+                } else {
+                    g.b.ins().select(set, fall, target)
+                });
+            }
+            Inst::Je(a, x, y) | Inst::Jne(a, x, y) => {
+                let x = g.u(x as u8);
+                let y = g.u(y as u8);
+                let cc = if matches!(inst, Inst::Je(..)) {
+                    IntCC::Equal
+                } else {
+                    IntCC::NotEqual
+                };
+                let taken = g.b.ins().icmp(cc, x, y);
+                let target = g.u(a as u8);
+                let fall = g.b.ins().iconst(ptr, (pc + 1) as i64);
+                next_pc = Some(g.b.ins().select(taken, target, fall));
+            }
+            Inst::Jz(a, x) | Inst::Jnz(a, x) => {
+                let x = g.u(x as u8);
+                let cc = if matches!(inst, Inst::Jz(..)) {
+                    IntCC::Equal
+                } else {
+                    IntCC::NotEqual
+                };
+                let taken = g.b.ins().icmp_imm(cc, x, 0);
+                let target = g.u(a as u8);
+                let fall = g.b.ins().iconst(ptr, (pc + 1) as i64);
+                next_pc = Some(g.b.ins().select(taken, target, fall));
+            }
+            _ => return Err(format!("cannot compile {:?}", inst)),
+        }
+    }
+    let steps = g.get(STEPS);
+    let steps = g.b.ins().iadd_imm(steps, len);
+    match next_pc {
+        // 跳回块首且剩余的指令数足够再执行一遍时在本机代码内循环
+        Some(target) => {
+            let limit = g.b.create_block();
+            let again = g.b.create_block();
+            g.b.append_block_param(limit, ptr);
+            let back = g.b.ins().icmp_imm(IntCC::Equal, target, start as i64);
+            g.b.ins()
+                .brif(back, limit, &[steps], exit, &[target, steps]);
+            g.b.switch_to_block(limit);
+            let steps = g.b.block_params(limit)[0];
+            let need = g.b.ins().iadd_imm(steps, len);
+            let budget_v = g.b.ins().load(ptr, mem, cp, offset_of!(Ctx, budget) as i32);
+            let enough =
+                g.b.ins()
+                    .icmp(IntCC::UnsignedLessThanOrEqual, need, budget_v);
+            g.b.ins().brif(enough, again, &[], exit, &[target, steps]);
+            g.b.switch_to_block(again);
+            g.set(STEPS, steps);
+            g.b.ins().jump(head, &[]);
+        }
+        None => {
+            let pc = g.b.ins().iconst(ptr, (start + insts.len()) as i64);
+            g.b.ins().jump(exit, &[pc, steps]);
+        }
+    }
+
+    // 入口：读取块中用到的寄存器
+    let u_base = |g: &mut Gen, i: usize| {
+        let field = if i < 8 {
+            offset_of!(Ctx, u)
+        } else {
+            offset_of!(Ctx, f)
+        };
+        g.b.ins().load(ptr, mem, cp, field as i32)
+    };
+    g.b.switch_to_block(entry);
+    let (ub, fb) = (u_base(&mut g, 0), u_base(&mut g, 8));
+    for i in 0..16 {
+        if g.read[i] {
+            let ty = g.ty(i);
+            let base = if i < 8 { ub } else { fb };
+            let v = g.b.ins().load(ty, mem, base, (i % 8 * 8) as i32);
+            g.b.def_var(Variable::from_u32(i as u32), v);
+        }
+    }
+    if g.read[FLAGS] {
+        let v =
+            g.b.ins()
+                .load(types::I64, mem, cp, offset_of!(Ctx, flags) as i32);
+        g.b.def_var(Variable::from_u32(FLAGS as u32), v);
+    }
+    let zero = g.b.ins().iconst(ptr, 0);
+    g.b.def_var(Variable::from_u32(STEPS as u32), zero);
+    g.b.ins().jump(head, &[]);
+
+    // 出口：写回改过的寄存器
+    g.b.switch_to_block(exit);
+    let pc = g.b.block_params(exit)[0];
+    let steps = g.b.block_params(exit)[1];
+    let (ub, fb) = (u_base(&mut g, 0), u_base(&mut g, 8));
+    for i in 0..16 {
+        if g.written[i] {
+            let v = g.b.use_var(Variable::from_u32(i as u32));
+            let base = if i < 8 { ub } else { fb };
+            g.b.ins().store(mem, v, base, (i % 8 * 8) as i32);
+        }
+    }
+    if g.written[FLAGS] {
+        let v = g.b.use_var(Variable::from_u32(FLAGS as u32));
+        g.b.ins().store(mem, v, cp, offset_of!(Ctx, flags) as i32);
+    }
+    g.b.ins().store(mem, pc, cp, offset_of!(Ctx, pc) as i32);
+    g.b.ins()
+        .store(mem, steps, cp, offset_of!(Ctx, steps) as i32);
+    g.b.ins().return_(&[]);
+    g.b.seal_all_blocks();
+    g.b.finalize();
+
+    module
+        .define_function(id, &mut ctx)
+        .map_err(|e| e.to_string())?;
+    module.clear_context(&mut ctx);
+    Ok(id)
+}
+
+#[cfg(test)]
+#[test]
+fn test_same_as_interpreter() {
+    use crate::asm::assemble;
+    use crate::port::PortBus;
+
+    let code = assemble(
+        "
+        mu u2, 5000
+        md f1, 0.5
+        storedh f1, u3
+        mu u4, 1
+        mu u6, 0xffff_ffff_ffff_ffff
+    loop:
+        loaddh f3, u3
+        muld f4, f3, f1
+        addd f2, f2, f4
+        adddi f1, 0.001
+        storedh f1, u3
+        addui u1, 1
+        addu u7, u6, u4         ; 溢出
+        mu u5, loop
+        jne u5, u1, u2
+        mu u3, 1
+        storeuh u1, u3
+        pushu u1
+        loaddh f5, u3           ; 类型不符
+        ",
+    )
+    .unwrap();
+    for limit in [None, Some(0), Some(7), Some(12_345)] {
+        let mut results = Vec::new();
+        for jit in [false, true] {
+            let (mut core, mut mem, mut bus) = (CpuCore::new(), Memory::new(), PortBus::new());
+            mem.store(Some(code.clone()), None, None);
+            let r = if jit {
+                let r = core.start(&mut mem, &mut bus, limit);
+                assert_eq!(mem.jit().compiled() > 0, limit.is_none_or(|n| n > 7));
+                r.map_err(|f| format!("{:?} {}", f.err, f.pc))
+            } else {
+                let mut steps = 0;
+                loop {
+                    if limit.is_some_and(|n| steps >= n) {
+                        break Ok(super::ExitReason::StepLimit);
+                    }
+                    match core.step(&mut mem, &mut bus) {
+                        Ok(None) => steps += 1,
+                        Ok(Some(reason)) => break Ok(reason),
+                        Err(f) => break Err(format!("{:?} {}", f.err, f.pc)),
+                    }
+                }
+                .map(|reason| super::ExitStatus {
+                    reason,
+                    value: core.get_u_reg(demo_isa::reg::UsizeReg::U1),
+                    steps,
+                })
+            };
+            results.push(format!(
+                "{:?} {:?} {:?} {:?}",
+                r,
+                core,
+                mem.load(),
+                mem.heap_segment()
+            ));
+        }
+        assert_eq!(results[0], results[1]);
+    }
+}
@@ -0,0 +1,154 @@
+//! 执行观察者
+//!
+//! `CpuCore::start_with`在每条指令执行前后，以及访问内存、调用、返回、系统调用和出错时通知观察者。
+//! 所有方法默认什么也不做。`NoopObserver`的`ACTIVE`为假，分派循环中的观察代码在编译时整体去掉，
+//! 与`CpuCore::start`完全相同；有观察者时逐条执行，不融合指令也不进入JIT，每条指令都会被观察到。
+use demo_isa::reg::UsizeRegType;
+use demo_isa::Inst;
+use log::debug;
+
+use super::{CpuCore, Fault};
+use crate::memory::Memory;
+
+/// 被访问的内存段
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Segment {
+    Heap,
+    Stack,
+}
+/// 一次数据内存访问
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemAccess {
+    pub segment: Segment,
+    /// 段内的绝对地址，栈上的地址已加上bp
+    pub addr: UsizeRegType,
+    pub write: bool,
+}
+
+/// 观察指令的执行
+pub trait ExecObserver {
+    /// 为假时分派循环不调用任何方法
+    const ACTIVE: bool = true;
+    /// 执行`pc`处的指令之前，此时`core`中的pc尚未前移
+    fn before_inst(&mut self, _pc: UsizeRegType, _inst: &Inst, _core: &CpuCore) {}
+    /// 指令正常执行之后，出错或停机的指令不调用
+    fn after_inst(&mut self, _pc: UsizeRegType, _inst: &Inst, _core: &CpuCore) {}
+    /// 指令读写堆或栈之前，`mem`为访问前的内存，访问本身仍可能因地址无效而出错
+    fn on_mem(&mut self, _access: MemAccess, _mem: &Memory) {}
+    /// `Call`执行之后，`core`的pc和bp已是被调用者的
+    fn on_call(&mut self, _pc: UsizeRegType, _core: &CpuCore) {}
+    /// `Ret`执行之后，`core`的pc为返回地址
+    fn on_ret(&mut self, _pc: UsizeRegType, _core: &CpuCore) {}
+    /// 执行系统调用之前，`num`为系统调用号
+    fn on_syscall(&mut self, _pc: UsizeRegType, _num: UsizeRegType, _core: &CpuCore) {}
+    /// 执行出错，此后不再执行
+    fn on_fault(&mut self, _fault: &Fault) {}
+}
+
+/// 什么也不观察，`CpuCore::start`使用
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoopObserver;
+impl ExecObserver for NoopObserver {
+    const ACTIVE: bool = false;
+}
+
+/// 以debug级别输出每条指令和执行后的寄存器
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LogObserver;
+impl ExecObserver for LogObserver {
+    fn before_inst(&mut self, pc: UsizeRegType, inst: &Inst, _core: &CpuCore) {
+        debug!("pc: {:?}, inst: {:?}", pc, inst);
+    }
+    fn after_inst(&mut self, _pc: UsizeRegType, _inst: &Inst, core: &CpuCore) {
+        debug!("regs: {:?}", core.regs);
+        debug!("flags: {:?}", core.flags);
+    }
+    fn on_fault(&mut self, fault: &Fault) {
+        debug!("fault: {}", fault);
+    }
+}
+
+/// 按执行前的状态算出指令将访问的数据内存，`Call`和`Ret`对栈的访问不计在内
+pub(crate) fn mem_access(inst: &Inst, core: &CpuCore, mem: &Memory) -> Option<MemAccess> {
+    let access = |segment, addr, write| {
+        Some(MemAccess {
+            segment,
+            addr,
+            write,
+        })
+    };
+    let stack = |a| core.get_bp().wrapping_add(core.get_u_reg(a));
+    match *inst {
+        Inst::LoadUH(_, a) | Inst::LoadDH(_, a) => access(Segment::Heap, core.get_u_reg(a), false),
+        Inst::StoreUH(_, a) | Inst::StoreDH(_, a) => access(Segment::Heap, core.get_u_reg(a), true),
+        Inst::LoadUS(_, a) | Inst::LoadDS(_, a) => access(Segment::Stack, stack(a), false),
+        Inst::StoreUS(_, a) | Inst::StoreDS(_, a) => access(Segment::Stack, stack(a), true),
+        Inst::PushU(_) | Inst::PushD(_) => access(Segment::Stack, mem.stack_segment().len(), true),
+        Inst::PopU(_) | Inst::PopD(_) => access(
+            Segment::Stack,
+            mem.stack_segment().len().checked_sub(1)?,
+            false,
+        ),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_observer() {
+    use crate::asm::assemble;
+    use crate::port::PortBus;
+
+    #[derive(Default)]
+    struct Recorder(Vec<String>);
+    impl ExecObserver for Recorder {
+        fn before_inst(&mut self, pc: UsizeRegType, _inst: &Inst, _core: &CpuCore) {
+            self.0.push(format!("b{}", pc));
+        }
+        fn after_inst(&mut self, pc: UsizeRegType, _inst: &Inst, _core: &CpuCore) {
+            self.0.push(format!("a{}", pc));
+        }
+        fn on_mem(&mut self, access: MemAccess, _mem: &Memory) {
+            let rw = if access.write { "w" } else { "r" };
+            self.0
+                .push(format!("{:?}{}{}", access.segment, rw, access.addr));
+        }
+        fn on_call(&mut self, pc: UsizeRegType, core: &CpuCore) {
+            self.0.push(format!("call{}->{}", pc, core.get_pc()));
+        }
+        fn on_ret(&mut self, pc: UsizeRegType, core: &CpuCore) {
+            self.0.push(format!("ret{}->{}", pc, core.get_pc()));
+        }
+        fn on_fault(&mut self, fault: &Fault) {
+            self.0.push(format!("fault{}", fault.pc));
+        }
+    }
+
+    let code = assemble(
+        "
+        mu u1, 5
+        mu u2, 0
+        storeuh u1, u2
+        mu u8, f
+        call u8
+        loaddh f1, u2           ; 类型不符
+    f:
+        pushu u1
+        popu u3
+        ret
+        ",
+    )
+    .unwrap();
+    let (mut core, mut mem, mut bus) = (CpuCore::new(), Memory::new(), PortBus::new());
+    mem.store(Some(code), None, None);
+    let mut rec = Recorder::default();
+    let fault = core
+        .start_with(&mut mem, &mut bus, None, &mut rec)
+        .unwrap_err();
+    assert_eq!(fault.pc, 5);
+    assert_eq!(
+        rec.0.join(" "),
+        "b0 a0 b1 a1 b2 Heapw0 a2 b3 a3 b4 call4->6 a4 b6 Stackw2 a6 b7 Stackr2 a7 \
+         b8 ret8->5 a8 b5 Heapr0 fault5"
+    );
+}
@@ -0,0 +1,760 @@
+//! 记录与重放
+//!
+//! 记录时按执行顺序记下虚拟机交给程序的所有不确定的值：系统调用返回后被改变的寄存器，
+//! 以及`InU`/`InD`从设备读到的值，`OutU`/`OutD`只在写入出错时记下错误。重放时不再执行系统调用，
+//! 也不访问设备，
+//! 而是依次把记下的值交给程序，所以不依赖输入、时间或随机数，每次执行的结果都相同。
+//! 重放到的指令与记录不符（地址、系统调用号或端口不同，或记录已经用完）时，
+//! 该指令以`CpuErr::Diverged`出错，`Fault::pc`就是第一个分歧的地址。
+//! 还有记录没有重放时程序就停机或越过代码末尾，同样在停止处以`CpuErr::Diverged`出错。
+//!
+//! 系统调用出错时记下出错前被改变的寄存器和错误，读端口出错时记下错误，重放时返回同样的错误。
+//! IO错误只记下`std::io::ErrorKind`。
+//!
+//! 只记录结果取决于外部环境的系统调用和异步系统调用，原子操作和线程等其余系统调用在重放时
+//! 照常执行。重放异步系统调用时不调用宿主，也不等待它的future。
+//! 系统调用写入内存的内容不在记录中。
+//!
+//! 记录的编码与镜像相同，所有整数均为小端序：
+//! ```text
+//! magic    b"DVMR"
+//! version  u16
+//! entries  u64 数量，随后是每条记录：u64 pc + u8 标签 + 内容
+//!          0 SysCall: u64 调用号，u64 数量 + (u8 寄存器 + u64)，u64 数量 + (u8 寄存器 + f64)，u64 燃料，
+//!                     err
+//!          1 InU:     u64 端口 + u64 读到的值 + err
+//!          2 InD:     u64 端口 + f64 读到的值 + err
+//!          3 Out:     u64 端口 + err，只在写入出错时记录
+//! err      u8 0 没有出错，1 MemoryErr + u8 种类 + u64 参数，2 ISAErr + u8 种类，
+//!          3 PortErr + u8 种类 + u64 参数（端口或IO错误的种类）
+//! ```
+use std::io::ErrorKind;
+
+use demo_isa::err::ISAErr;
+use demo_isa::reg::{F64Reg, F64RegType, UsizeReg, UsizeRegType};
+
+use super::{CpuCore, CpuErr};
+use crate::asm::{F_REGS, U_REGS};
+use crate::image::{ImageErr, Reader, Writer};
+use crate::memory::{Memory, MemoryErr};
+use crate::port::{PortBus, PortErr};
+use crate::sys_call::{AsyncSysCall, SysCall, SysCallErr};
+
+pub const RECORDING_MAGIC: &[u8; 4] = b"DVMR";
+pub const RECORDING_VERSION: u16 = 3;
+
+/// 可以记录的`ISAErr`，按下标编码
+const ISA_ERRS: [ISAErr; 8] = [
+    ISAErr::Halt,
+    ISAErr::DivByZero,
+    ISAErr::InvalidSysCall,
+    ISAErr::InvalidSysCallArg,
+    ISAErr::SysCallErr,
+    ISAErr::TypeMismatch,
+    ISAErr::InvalidStackAddr,
+    ISAErr::InvalidHeapType,
+];
+
+/// 可以记录的IO错误种类，按下标编码，其余的种类记为`Other`
+const IO_ERR_KINDS: [ErrorKind; 20] = [
+    ErrorKind::NotFound,
+    ErrorKind::PermissionDenied,
+    ErrorKind::ConnectionRefused,
+    ErrorKind::ConnectionReset,
+    ErrorKind::ConnectionAborted,
+    ErrorKind::NotConnected,
+    ErrorKind::AddrInUse,
+    ErrorKind::AddrNotAvailable,
+    ErrorKind::BrokenPipe,
+    ErrorKind::AlreadyExists,
+    ErrorKind::WouldBlock,
+    ErrorKind::InvalidInput,
+    ErrorKind::InvalidData,
+    ErrorKind::TimedOut,
+    ErrorKind::WriteZero,
+    ErrorKind::Interrupted,
+    ErrorKind::Unsupported,
+    ErrorKind::UnexpectedEof,
+    ErrorKind::OutOfMemory,
+    ErrorKind::Other,
+];
+
+/// 系统调用或读端口出错时的错误，重放时原样返回
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    MemoryErr(MemoryErr),
+    ISAErr(ISAErr),
+    PortErr(PortFailure),
+}
+/// 可以记录的`PortErr`，IO错误只保留种类
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortFailure {
+    UnmappedPort(UsizeRegType),
+    Unsupported,
+    IOError(ErrorKind),
+}
+impl From<&CpuErr> for Failure {
+    fn from(err: &CpuErr) -> Failure {
+        match err {
+            CpuErr::MemoryErr(e) => Failure::MemoryErr(*e),
+            CpuErr::ISAErr(e) => Failure::ISAErr(*e),
+            CpuErr::PortErr(e) => Failure::PortErr(e.into()),
+            _ => Failure::ISAErr(ISAErr::SysCallErr),
+        }
+    }
+}
+impl From<Failure> for CpuErr {
+    fn from(err: Failure) -> CpuErr {
+        match err {
+            Failure::MemoryErr(e) => CpuErr::MemoryErr(e),
+            Failure::ISAErr(e) => CpuErr::ISAErr(e),
+            Failure::PortErr(e) => CpuErr::PortErr(e.into()),
+        }
+    }
+}
+impl From<&PortErr> for PortFailure {
+    fn from(err: &PortErr) -> PortFailure {
+        match err {
+            PortErr::UnmappedPort(port) => PortFailure::UnmappedPort(*port),
+            PortErr::Unsupported => PortFailure::Unsupported,
+            PortErr::IOError(e) => PortFailure::IOError(e.kind()),
+        }
+    }
+}
+impl From<PortFailure> for PortErr {
+    fn from(err: PortFailure) -> PortErr {
+        match err {
+            PortFailure::UnmappedPort(port) => PortErr::UnmappedPort(port),
+            PortFailure::Unsupported => PortErr::Unsupported,
+            PortFailure::IOError(kind) => PortErr::IOError(kind.into()),
+        }
+    }
+}
+
+/// 一个不确定的值
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// 系统调用`num`返回后被改变的寄存器，它按工作量收取的燃料，以及出错时的错误
+    SysCall {
+        num: UsizeRegType,
+        u: Vec<(UsizeReg, UsizeRegType)>,
+        f: Vec<(F64Reg, F64RegType)>,
+        fuel: u64,
+        err: Option<Failure>,
+    },
+    /// 从端口读到的值，出错时为0并记下错误
+    InU {
+        port: UsizeRegType,
+        val: UsizeRegType,
+        err: Option<Failure>,
+    },
+    InD {
+        port: UsizeRegType,
+        val: F64RegType,
+        err: Option<Failure>,
+    },
+    /// 向端口写入时的错误，写入成功时不记录
+    Out { port: UsizeRegType, err: Failure },
+}
+
+/// 一条记录，`pc`为产生该值的指令的地址
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub pc: UsizeRegType,
+    pub event: Event,
+}
+
+/// 按执行顺序排列的记录
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Recording {
+    pub entries: Vec<Entry>,
+}
+
+impl Recording {
+    pub fn new() -> Recording {
+        Recording {
+            entries: Vec::new(),
+        }
+    }
+    pub fn encode(&self) -> Vec<u8> {
+        let mut w = Writer::new();
+        w.bytes(RECORDING_MAGIC);
+        w.u16(RECORDING_VERSION);
+        w.usize(self.entries.len());
+        for entry in &self.entries {
+            w.usize(entry.pc);
+            match &entry.event {
+                Event::SysCall {
+                    num,
+                    u,
+                    f,
+                    fuel,
+                    err,
+                } => {
+                    w.u8(0);
+                    w.usize(*num);
+                    w.usize(u.len());
+                    for &(r, v) in u {
+                        w.u_reg(r);
+                        w.usize(v);
+                    }
+                    w.usize(f.len());
+                    for &(r, v) in f {
+                        w.f_reg(r);
+                        w.f64(v);
+                    }
+                    w.u64(*fuel);
+                    w.failure(err);
+                }
+                Event::InU { port, val, err } => {
+                    w.u8(1);
+                    w.usize(*port);
+                    w.usize(*val);
+                    w.failure(err);
+                }
+                Event::InD { port, val, err } => {
+                    w.u8(2);
+                    w.usize(*port);
+                    w.f64(*val);
+                    w.failure(err);
+                }
+                Event::Out { port, err } => {
+                    w.u8(3);
+                    w.usize(*port);
+                    w.failure(&Some(*err));
+                }
+            }
+        }
+        w.finish()
+    }
+    pub fn decode(bytes: &[u8]) -> Result<Recording, ImageErr> {
+        let mut r = Reader::new(bytes);
+        if r.take(RECORDING_MAGIC.len())? != RECORDING_MAGIC {
+            return Err(ImageErr::BadMagic);
+        }
+        let version = r.u16()?;
+        if version != RECORDING_VERSION {
+            return Err(ImageErr::UnsupportedVersion(version));
+        }
+        let n = r.len(17)?;
+        let mut entries = Vec::with_capacity(n);
+        for _ in 0..n {
+            let pc = r.usize()?;
+            let event = match r.u8()? {
+                0 => {
+                    let num = r.usize()?;
+                    let n = r.len(9)?;
+                    let u = (0..n)
+                        .map(|_| Ok((r.u_reg()?, r.usize()?)))
+                        .collect::<Result<_, ImageErr>>()?;
+                    let n = r.len(9)?;
+                    let f = (0..n)
+                        .map(|_| Ok((r.f_reg()?, r.f64()?)))
+                        .collect::<Result<_, ImageErr>>()?;
+                    let fuel = r.u64()?;
+                    let err = r.failure()?;
+                    Event::SysCall {
+                        num,
+                        u,
+                        f,
+                        fuel,
+                        err,
+                    }
+                }
+                1 => Event::InU {
+                    port: r.usize()?,
+                    val: r.usize()?,
+                    err: r.failure()?,
+                },
+                2 => Event::InD {
+                    port: r.usize()?,
+                    val: r.f64()?,
+                    err: r.failure()?,
+                },
+                3 => Event::Out {
+                    port: r.usize()?,
+                    err: r.failure()?.ok_or(ImageErr::InvalidTag(0))?,
+                },
+                tag => return Err(ImageErr::InvalidTag(tag)),
+            };
+            entries.push(Entry { pc, event });
+        }
+        r.finish()?;
+        Ok(Recording { entries })
+    }
+}
+
+impl Writer {
+    fn failure(&mut self, err: &Option<Failure>) {
+        match err {
+            None => self.u8(0),
+            Some(Failure::MemoryErr(e)) => {
+                let (kind, arg) = match *e {
+                    MemoryErr::InvalidCodeAddr => (0, 0),
+                    MemoryErr::HeapLimit(addr) => (1, addr),
+                    MemoryErr::StackOverflow => (2, 0),
+                    MemoryErr::ArrayLimit(n) => (3, n),
+                    MemoryErr::ThreadLimit => (4, 0),
+                };
+                self.u8(1);
+                self.u8(kind);
+                self.usize(arg);
+            }
+            Some(Failure::ISAErr(e)) => {
+                let kind = ISA_ERRS.iter().position(|x| x == e).unwrap_or(4);
+                self.u8(2);
+                self.u8(kind as u8);
+            }
+            Some(Failure::PortErr(e)) => {
+                let (kind, arg) = match *e {
+                    PortFailure::UnmappedPort(port) => (0, port),
+                    PortFailure::Unsupported => (1, 0),
+                    PortFailure::IOError(k) => (
+                        2,
+                        IO_ERR_KINDS
+                            .iter()
+                            .position(|&x| x == k)
+                            .unwrap_or(IO_ERR_KINDS.len() - 1),
+                    ),
+                };
+                self.u8(3);
+                self.u8(kind);
+                self.usize(arg);
+            }
+        }
+    }
+}
+
+impl Reader<'_> {
+    fn failure(&mut self) -> Result<Option<Failure>, ImageErr> {
+        Ok(match self.u8()? {
+            0 => None,
+            1 => {
+                let kind = self.u8()?;
+                let arg = self.usize()?;
+                Some(Failure::MemoryErr(match kind {
+                    0 => MemoryErr::InvalidCodeAddr,
+                    1 => MemoryErr::HeapLimit(arg),
+                    2 => MemoryErr::StackOverflow,
+                    3 => MemoryErr::ArrayLimit(arg),
+                    4 => MemoryErr::ThreadLimit,
+                    tag => return Err(ImageErr::InvalidTag(tag)),
+                }))
+            }
+            2 => {
+                let kind = self.u8()?;
+                let e = ISA_ERRS
+                    .get(kind as usize)
+                    .ok_or(ImageErr::InvalidTag(kind))?;
+                Some(Failure::ISAErr(*e))
+            }
+            3 => {
+                let kind = self.u8()?;
+                let arg = self.usize()?;
+                Some(Failure::PortErr(match kind {
+                    0 => PortFailure::UnmappedPort(arg),
+                    1 => PortFailure::Unsupported,
+                    2 => PortFailure::IOError(
+                        *IO_ERR_KINDS
+                            .get(arg)
+                            .ok_or(ImageErr::ValueOutOfRange(arg as u64))?,
+                    ),
+                    tag => return Err(ImageErr::InvalidTag(tag)),
+                }))
+            }
+            tag => return Err(ImageErr::InvalidTag(tag)),
+        })
+    }
+}
+
+/// `CpuCore`的输入来源
+#[derive(Debug, Default)]
+pub(crate) enum Io {
+    /// 直接执行系统调用、访问设备
+    #[default]
+    Live,
+    Record(Recording),
+    /// `pos`为下一条要重放的记录
+    Replay {
+        rec: Recording,
+        pos: usize,
+    },
+}
+
+impl Io {
+    /// 取出重放的下一条记录，与`pc`处的指令不符时返回`CpuErr::Diverged`
+    fn next(
+        &mut self,
+        pc: UsizeRegType,
+        matches: impl Fn(&Event) -> bool,
+    ) -> Result<&Event, CpuErr> {
+        let Io::Replay { rec, pos } = self else {
+            unreachable!()
+        };
+        match rec.entries.get(*pos) {
+            Some(entry) if entry.pc == pc && matches(&entry.event) => {
+                *pos += 1;
+                Ok(&entry.event)
+            }
+            _ => Err(CpuErr::Diverged),
+        }
+    }
+    /// 重放的下一条记录与`pc`处的指令相符时取出它，否则留给之后的指令
+    fn next_if(&mut self, pc: UsizeRegType, matches: impl Fn(&Event) -> bool) -> Option<&Event> {
+        let Io::Replay { rec, pos } = self else {
+            unreachable!()
+        };
+        let entry = rec.entries.get(*pos)?;
+        if entry.pc != pc || !matches(&entry.event) {
+            return None;
+        }
+        *pos += 1;
+        Some(&entry.event)
+    }
+    /// 正在重放且还有记录没有用到
+    pub(crate) fn unfinished(&self) -> bool {
+        matches!(self, Io::Replay { rec, pos } if *pos < rec.entries.len())
+    }
+    fn push(&mut self, pc: UsizeRegType, event: Event) {
+        if let Io::Record(rec) = self {
+            rec.entries.push(Entry { pc, event });
+        }
+    }
+}
+
+/// 正在执行的指令的地址，`core::run`执行指令之前pc已经前移
+fn inst_pc(core: &CpuCore) -> UsizeRegType {
+    core.get_pc().wrapping_sub(1)
+}
+
+/// 执行系统调用，或者重放它的结果，`external`见`EXTERNAL_SYS_CALLS`
+pub(crate) fn sys_call(
+    core: &mut CpuCore,
+    mem: &mut Memory,
+    num: UsizeRegType,
+    call: SysCall,
+    external: bool,
+) -> Result<(), CpuErr> {
+    let pc = inst_pc(core);
+    if !external {
+        return Ok(call(core, mem)?);
+    }
+    match core.io {
+        Io::Live => Ok(call(core, mem)?),
+        Io::Record(_) => {
+            let before = Before::take(core);
+            let r = call(core, mem);
+            before.record(core, pc, num, r)
+        }
+        Io::Replay { .. } => replay_sys_call(core, pc, num),
+    }
+}
+
+/// 执行异步系统调用，或者重放它的结果，重放时不调用宿主，见`async_call`
+pub(crate) async fn async_sys_call(
+    core: &mut CpuCore,
+    mem: &mut Memory,
+    num: UsizeRegType,
+    call: AsyncSysCall,
+) -> Result<(), CpuErr> {
+    let pc = inst_pc(core);
+    match core.io {
+        Io::Live => Ok((*call)(core, mem).await?),
+        Io::Record(_) => {
+            let before = Before::take(core);
+            let r = (*call)(core, mem).await;
+            before.record(core, pc, num, r)
+        }
+        Io::Replay { .. } => replay_sys_call(core, pc, num),
+    }
+}
+
+/// 系统调用之前的寄存器和燃料，用来找出调用改变了什么
+struct Before {
+    u: [UsizeRegType; 8],
+    f: [F64RegType; 8],
+    fuel: Option<u64>,
+}
+
+impl Before {
+    fn take(core: &CpuCore) -> Before {
+        Before {
+            u: U_REGS.map(|r| core.get_u_reg(r)),
+            f: F_REGS.map(|r| core.get_f_reg(r)),
+            fuel: core.fuel(),
+        }
+    }
+    /// 记下系统调用的结果`r`，返回它的错误
+    fn record(
+        self,
+        core: &mut CpuCore,
+        pc: UsizeRegType,
+        num: UsizeRegType,
+        r: Result<(), SysCallErr>,
+    ) -> Result<(), CpuErr> {
+        let err = r.err().map(CpuErr::from);
+        let event = Event::SysCall {
+            num,
+            u: U_REGS
+                .into_iter()
+                .zip(self.u)
+                .filter(|&(r, v)| core.get_u_reg(r) != v)
+                .map(|(r, _)| (r, core.get_u_reg(r)))
+                .collect(),
+            f: F_REGS
+                .into_iter()
+                .zip(self.f)
+                .filter(|&(r, v)| core.get_f_reg(r).to_bits() != v.to_bits())
+                .map(|(r, _)| (r, core.get_f_reg(r)))
+                .collect(),
+            fuel: self.fuel.zip(core.fuel()).map_or(0, |(a, b)| a - b),
+            err: err.as_ref().map(Failure::from),
+        };
+        core.io.push(pc, event);
+        err.map_or(Ok(()), Err)
+    }
+}
+
+/// 把记下的寄存器和燃料交给程序，记录中系统调用出错时返回同样的错误
+fn replay_sys_call(core: &mut CpuCore, pc: UsizeRegType, num: UsizeRegType) -> Result<(), CpuErr> {
+    let event = core.io.next(
+        pc,
+        |e| matches!(e, Event::SysCall { num: n, .. } if *n == num),
+    )?;
+    let Event::SysCall {
+        u, f, fuel, err, ..
+    } = event.clone()
+    else {
+        unreachable!()
+    };
+    for (r, v) in u {
+        core.set_u_reg(r, v);
+    }
+    for (r, v) in f {
+        core.set_f_reg(r, v);
+    }
+    core.charge_fuel(fuel);
+    err.map_or(Ok(()), |e| Err(e.into()))
+}
+
+/// 从端口读取usize，或者重放读到的值
+pub(crate) fn read_u(
+    core: &mut CpuCore,
+    bus: &mut PortBus,
+    port: UsizeRegType,
+) -> Result<UsizeRegType, CpuErr> {
+    let pc = inst_pc(core);
+    match core.io {
+        Io::Live => Ok(bus.read_u(port)?),
+        Io::Record(_) => {
+            let r = bus.read_u(port);
+            let err = r.as_ref().err().map(|e| Failure::PortErr(e.into()));
+            let val = *r.as_ref().unwrap_or(&0);
+            core.io.push(pc, Event::InU { port, val, err });
+            Ok(r?)
+        }
+        Io::Replay { .. } => match core.io.next(
+            pc,
+            |e| matches!(e, Event::InU { port: p, .. } if *p == port),
+        )? {
+            Event::InU { err: Some(e), .. } => Err((*e).into()),
+            Event::InU { val, .. } => Ok(*val),
+            _ => unreachable!(),
+        },
+    }
+}
+
+/// 从端口读取f64，或者重放读到的值
+pub(crate) fn read_f(
+    core: &mut CpuCore,
+    bus: &mut PortBus,
+    port: UsizeRegType,
+) -> Result<F64RegType, CpuErr> {
+    let pc = inst_pc(core);
+    match core.io {
+        Io::Live => Ok(bus.read_f(port)?),
+        Io::Record(_) => {
+            let r = bus.read_f(port);
+            let err = r.as_ref().err().map(|e| Failure::PortErr(e.into()));
+            let val = *r.as_ref().unwrap_or(&0.0);
+            core.io.push(pc, Event::InD { port, val, err });
+            Ok(r?)
+        }
+        Io::Replay { .. } => match core.io.next(
+            pc,
+            |e| matches!(e, Event::InD { port: p, .. } if *p == port),
+        )? {
+            Event::InD { err: Some(e), .. } => Err((*e).into()),
+            Event::InD { val, .. } => Ok(*val),
+            _ => unreachable!(),
+        },
+    }
+}
+
+/// 向端口写入，重放时不访问设备，记录中写入出错时返回同样的错误
+pub(crate) fn write(
+    core: &mut CpuCore,
+    port: UsizeRegType,
+    write: impl FnOnce() -> Result<(), PortErr>,
+) -> Result<(), CpuErr> {
+    let pc = inst_pc(core);
+    match core.io {
+        Io::Live => Ok(write()?),
+        Io::Record(_) => {
+            let r = write();
+            if let Err(e) = &r {
+                let err = Failure::PortErr(e.into());
+                core.io.push(pc, Event::Out { port, err });
+            }
+            Ok(r?)
+        }
+        Io::Replay { .. } => match core.io.next_if(
+            pc,
+            |e| matches!(e, Event::Out { port: p, .. } if *p == port),
+        ) {
+            Some(Event::Out { err, .. }) => Err((*err).into()),
+            _ => Ok(()),
+        },
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_record_replay() {
+    use super::Fault;
+    use crate::asm::assemble;
+    use crate::port::RANDOM_PORT;
+    use crate::{VmErr, VmTmp};
+
+    let src = |port| {
+        format!(
+            "
+            mu u1, {}
+            inu u2, u1
+            ind f1, u1
+            mu u3, 0            ; 写入空字符串
+            mu u5, 99
+            mu u8, 0
+            syscall u8
+            inu u6, u1
+            halt
+            ",
+            port
+        )
+    };
+    let code = assemble(&src(RANDOM_PORT)).unwrap();
+    let mut vm = VmTmp::new();
+    vm.set_code(code.clone());
+    vm.record();
+    vm.start().unwrap();
+    let rec = vm.take_recording().unwrap();
+    assert_eq!(rec.entries.len(), 4);
+    let rec = Recording::decode(&rec.encode()).unwrap();
+
+    let mut replayed = VmTmp::new();
+    replayed.set_code(code);
+    replayed.bus_mut().unregister(RANDOM_PORT);
+    replayed.replay(rec.clone());
+    replayed.start().unwrap();
+    for r in [UsizeReg::U2, UsizeReg::U4, UsizeReg::U5, UsizeReg::U6] {
+        assert_eq!(replayed.get_u_reg(r), vm.get_u_reg(r));
+    }
+    assert_eq!(replayed.get_f_reg(F64Reg::F1), vm.get_f_reg(F64Reg::F1));
+    assert_eq!(replayed.take_recording(), Some(Recording::new()));
+
+    // 从另一个端口读取，在第一条`InU`处分歧
+    let mut diverged = VmTmp::new();
+    diverged.set_code(assemble(&src(7)).unwrap());
+    diverged.replay(rec.clone());
+    match diverged.start() {
+        Err(VmErr::Fault(fault)) => {
+            let Fault { err, pc, .. } = *fault;
+            assert!(matches!(err, CpuErr::Diverged));
+            assert_eq!(pc, 1);
+        }
+        r => panic!("unexpected {:?}", r),
+    }
+
+    // 还有记录没有重放时就停机，在停机处分歧
+    let mut code = assemble(&src(RANDOM_PORT)).unwrap();
+    code[2] = demo_isa::Inst::Halt;
+    let mut early = VmTmp::new();
+    early.set_code(code);
+    early.replay(rec);
+    assert!(matches!(
+        early.start(),
+        Err(VmErr::Fault(f)) if matches!(f.err, CpuErr::Diverged) && f.pc == 2
+    ));
+
+    // 出错的系统调用也被记录，重放时返回同样的错误
+    let code =
+        assemble("mu u2, 0\nstoreuh u1, u2\nmu u3, 0xffff_ffff_ffff_ffff\nmu u8, 0\nsyscall u8")
+            .unwrap();
+    let fault = |vm: &mut VmTmp| match vm.start() {
+        Err(VmErr::Fault(f)) => (format!("{:?}", f.err), f.pc),
+        r => panic!("unexpected {:?}", r),
+    };
+    let mut vm = VmTmp::new();
+    vm.set_code(code.clone());
+    vm.record();
+    let recorded = fault(&mut vm);
+    assert_eq!(recorded, ("ISAErr(InvalidSysCallArg)".to_string(), 4));
+    let rec = Recording::decode(&vm.take_recording().unwrap().encode()).unwrap();
+    let mut replayed = VmTmp::new();
+    replayed.set_code(code);
+    replayed.replay(rec);
+    assert_eq!(fault(&mut replayed), recorded);
+
+    // 读端口出错也被记录，重放时不需要设备就返回同样的错误
+    #[derive(Debug)]
+    struct BadInput;
+    impl crate::port::PortDevice for BadInput {
+        fn read_f(&mut self) -> Result<F64RegType, PortErr> {
+            Err(std::io::Error::new(ErrorKind::InvalidData, "not a number").into())
+        }
+    }
+    let code = assemble("mu u1, 9\nind f1, u1").unwrap();
+    let read_err = |vm: &mut VmTmp| match vm.start() {
+        Err(VmErr::Fault(f)) => match f.err {
+            CpuErr::PortErr(PortErr::IOError(e)) => (e.kind(), f.pc),
+            e => panic!("unexpected {:?}", e),
+        },
+        r => panic!("unexpected {:?}", r),
+    };
+    let mut vm = VmTmp::new();
+    vm.set_code(code.clone());
+    vm.bus_mut().register(9, Box::new(BadInput));
+    vm.record();
+    assert_eq!(read_err(&mut vm), (ErrorKind::InvalidData, 1));
+    let rec = Recording::decode(&vm.take_recording().unwrap().encode()).unwrap();
+    let mut replayed = VmTmp::new();
+    replayed.set_code(code);
+    replayed.replay(rec);
+    assert_eq!(read_err(&mut replayed), (ErrorKind::InvalidData, 1));
+
+    // 重放时写端口不访问设备，只返回记录中写入时的错误
+    let code = assemble(&format!(
+        "mu u1, {}\nmu u2, 5\noutu u2, u1\ninu u3, u1\nmu u1, 100\noutu u2, u1",
+        RANDOM_PORT
+    ))
+    .unwrap();
+    let write_err = |vm: &mut VmTmp| match vm.start() {
+        Err(VmErr::Fault(f)) => match f.err {
+            CpuErr::PortErr(PortErr::UnmappedPort(port)) => (port, f.pc),
+            e => panic!("unexpected {:?}", e),
+        },
+        r => panic!("unexpected {:?}", r),
+    };
+    let mut vm = VmTmp::new();
+    vm.set_code(code.clone());
+    vm.record();
+    assert_eq!(write_err(&mut vm), (100, 5));
+    let rec = vm.take_recording().unwrap();
+    assert_eq!(rec.entries.len(), 2);
+    let mut replayed = VmTmp::new();
+    replayed.set_code(code);
+    replayed.bus_mut().unregister(RANDOM_PORT);
+    replayed.replay(Recording::decode(&rec.encode()).unwrap());
+    assert_eq!(write_err(&mut replayed), (100, 5));
+    assert_eq!(replayed.get_u_reg(UsizeReg::U3), vm.get_u_reg(UsizeReg::U3));
+}
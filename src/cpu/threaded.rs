@@ -0,0 +1,629 @@
+//! 预解码的线程化代码
+//!
+//! 加载代码时把每条`Inst`翻译为一个`Op`：处理函数的指针加上解析好的寄存器下标和立即数。
+//! 执行时直接调用处理函数，不再逐条匹配`Inst`，也不再经过`Memory::fetch_code`。
+//! 各处理函数的语义与`core::run`完全一致，系统调用和端口读写仍交给`core::run`执行。
+//!
+//! 启用融合时，常见的两条指令组合（如`MU`加载地址后紧跟跳转或`Call`）在第一条的位置上
+//! 替换为一个融合的`Op`，一次分派执行两条。第二条的位置仍保留它自己的`Op`，跳转到那里照常执行，
+//! 所以pc编号不变。融合的`Op`在执行第二条前把pc前移一位，第二条出错时报告的pc与逐条执行一致。
+use demo_isa::err::ISAErr;
+use demo_isa::reg::Flags;
+use demo_isa::{Inst, RegType};
+use enumflags2::make_bitflags;
+
+use super::core::run;
+use super::{CpuCore, CpuErr};
+use crate::memory::Memory;
+use crate::port::PortBus;
+
+type Handler = fn(&mut CpuCore, Op, &mut Memory, &mut PortBus) -> Result<(), CpuErr>;
+
+/// 预解码的指令
+#[derive(Debug, Clone, Copy)]
+pub struct Op {
+    run: Handler,
+    a: u8,
+    b: u8,
+    c: u8,
+    /// 立即数，f64按位模式存储；交给`core::run`的指令存放其地址
+    imm: u64,
+    /// 执行的指令数，融合的`Op`为2
+    len: u8,
+}
+
+impl Op {
+    #[inline(always)]
+    pub(crate) fn exec(
+        self,
+        core: &mut CpuCore,
+        mem: &mut Memory,
+        bus: &mut PortBus,
+    ) -> Result<(), CpuErr> {
+        (self.run)(core, self, mem, bus)
+    }
+    /// 执行的指令数
+    #[inline(always)]
+    pub(crate) fn len(self) -> usize {
+        self.len as usize
+    }
+}
+
+/// 把代码段翻译为与之一一对应的`Op`，`fuse`为真时把可以融合的指令对替换为融合的`Op`
+pub(crate) fn compile(code: &[Inst], fuse: bool) -> Vec<Op> {
+    let mut ops: Vec<Op> = code
+        .iter()
+        .enumerate()
+        .map(|(pc, inst)| decode(pc, inst))
+        .collect();
+    if fuse {
+        for (pc, pair) in code.windows(2).enumerate() {
+            if let Some(op) = fuse_pair(&pair[0], &pair[1]) {
+                ops[pc] = op;
+            }
+        }
+    }
+    ops
+}
+
+/// 翻译单条指令，不融合
+pub(crate) fn decode(pc: usize, inst: &Inst) -> Op {
+    let op = |run: Handler, a: u8, b: u8, c: u8, imm: u64| Op {
+        run,
+        a,
+        b,
+        c,
+        imm,
+        len: 1,
+    };
+    match *inst {
+        Inst::Nop => op(nop, 0, 0, 0, 0),
+        Inst::MU(r, v) => op(mu, r as u8, 0, 0, v as u64),
+        Inst::MD(r, v) => op(md, r as u8, 0, 0, v.to_bits()),
+        Inst::MovU(a, b) => op(mov_u, a as u8, b as u8, 0, 0),
+        Inst::MovD(a, b) => op(mov_d, a as u8, b as u8, 0, 0),
+        Inst::Mod(a, b, c) => op(mod_u, a as u8, b as u8, c as u8, 0),
+        Inst::AddU(a, b, c) => op(add_u, a as u8, b as u8, c as u8, 0),
+        Inst::AddUI(r, v) => op(add_ui, r as u8, 0, 0, v as u64),
+        Inst::AddD(a, b, c) => op(add_d, a as u8, b as u8, c as u8, 0),
+        Inst::AddDI(r, v) => op(add_di, r as u8, 0, 0, v.to_bits()),
+        Inst::SubU(a, b, c) => op(sub_u, a as u8, b as u8, c as u8, 0),
+        Inst::SubUI(r, v) => op(sub_ui, r as u8, 0, 0, v as u64),
+        Inst::SubD(a, b, c) => op(sub_d, a as u8, b as u8, c as u8, 0),
+        Inst::SubDI(r, v) => op(sub_di, r as u8, 0, 0, v.to_bits()),
+        Inst::MulU(a, b, c) => op(mul_u, a as u8, b as u8, c as u8, 0),
+        Inst::MulD(a, b, c) => op(mul_d, a as u8, b as u8, c as u8, 0),
+        Inst::DivU(a, b, c) => op(div_u, a as u8, b as u8, c as u8, 0),
+        Inst::DivD(a, b, c) => op(div_d, a as u8, b as u8, c as u8, 0),
+        Inst::And(a, b, c) => op(and, a as u8, b as u8, c as u8, 0),
+        Inst::Or(a, b, c) => op(or, a as u8, b as u8, c as u8, 0),
+        Inst::Xor(a, b, c) => op(xor, a as u8, b as u8, c as u8, 0),
+        Inst::Not(a, b) => op(not, a as u8, b as u8, 0, 0),
+        Inst::NegU(a, b) => op(neg_u, a as u8, b as u8, 0, 0),
+        Inst::NegD(a, b) => op(neg_d, a as u8, b as u8, 0, 0),
+        Inst::Shl(a, b) => op(shl, a as u8, b as u8, 0, 0),
+        Inst::Shr(a, b) => op(shr, a as u8, b as u8, 0, 0),
+        Inst::LoadUH(v, a) => op(load_uh, v as u8, a as u8, 0, 0),
+        Inst::LoadDH(v, a) => op(load_dh, v as u8, a as u8, 0, 0),
+        Inst::StoreUH(v, a) => op(store_uh, v as u8, a as u8, 0, 0),
+        Inst::StoreDH(v, a) => op(store_dh, v as u8, a as u8, 0, 0),
+        Inst::LoadUS(v, a) => op(load_us, v as u8, a as u8, 0, 0),
+        Inst::LoadDS(v, a) => op(load_ds, v as u8, a as u8, 0, 0),
+        Inst::StoreUS(v, a) => op(store_us, v as u8, a as u8, 0, 0),
+        Inst::StoreDS(v, a) => op(store_ds, v as u8, a as u8, 0, 0),
+        Inst::Jo(a) => op(jo, a as u8, 0, 0, 0),
+        Inst::Jno(a) => op(jno, a as u8, 0, 0, 0),
+        Inst::Je(a, b, c) => op(je, a as u8, b as u8, c as u8, 0),
+        Inst::Jne(a, b, c) => op(jne, a as u8, b as u8, c as u8, 0),
+        Inst::Jz(a, b) => op(jz, a as u8, b as u8, 0, 0),
+        Inst::Jnz(a, b) => op(jnz, a as u8, b as u8, 0, 0),
+        Inst::Jmp(a) => op(jmp, a as u8, 0, 0, 0),
+        Inst::PushU(r) => op(push_u, r as u8, 0, 0, 0),
+        Inst::PushD(r) => op(push_d, r as u8, 0, 0, 0),
+        Inst::PopU(r) => op(pop_u, r as u8, 0, 0, 0),
+        Inst::PopD(r) => op(pop_d, r as u8, 0, 0, 0),
+        Inst::Call(r) => op(call, r as u8, 0, 0, 0),
+        Inst::Ret => op(ret, 0, 0, 0, 0),
+        Inst::Halt => op(halt, 0, 0, 0, 0),
+        Inst::SysCall(_) | Inst::InU(..) | Inst::InD(..) | Inst::OutU(..) | Inst::OutD(..) => {
+            op(interpret, 0, 0, 0, pc as u64)
+        }
+    }
+}
+
+/// 可以融合的指令对，两条指令的操作数放在同一个`Op`中
+fn fuse_pair(first: &Inst, second: &Inst) -> Option<Op> {
+    let op = |run: Handler, a, b, c, imm| {
+        Some(Op {
+            run,
+            a,
+            b,
+            c,
+            imm,
+            len: 2,
+        })
+    };
+    // `MU`加载的寄存器即跳转地址所在的寄存器
+    let addr = |r, t| r as u8 == t as u8;
+    match (*first, *second) {
+        (Inst::MU(r, v), Inst::Jmp(t)) if addr(r, t) => op(mu_jmp, r as u8, 0, 0, v as u64),
+        (Inst::MU(r, v), Inst::Jz(t, x)) if addr(r, t) => op(mu_jz, r as u8, x as u8, 0, v as u64),
+        (Inst::MU(r, v), Inst::Jnz(t, x)) if addr(r, t) => {
+            op(mu_jnz, r as u8, x as u8, 0, v as u64)
+        }
+        (Inst::MU(r, v), Inst::Je(t, x, y)) if addr(r, t) => {
+            op(mu_je, r as u8, x as u8, y as u8, v as u64)
+        }
+        (Inst::MU(r, v), Inst::Jne(t, x, y)) if addr(r, t) => {
+            op(mu_jne, r as u8, x as u8, y as u8, v as u64)
+        }
+        (Inst::MU(r, v), Inst::Call(t)) if addr(r, t) => op(mu_call, r as u8, 0, 0, v as u64),
+        (Inst::PushU(x), Inst::Call(t)) => op(push_u_call, x as u8, t as u8, 0, 0),
+        (Inst::PopU(x), Inst::PushU(y)) => op(pop_u_push_u, x as u8, y as u8, 0, 0),
+        _ => None,
+    }
+}
+
+#[inline(always)]
+fn overflow(core: &mut CpuCore) {
+    core.flags = make_bitflags!(Flags::{Overflow});
+}
+
+fn nop(_: &mut CpuCore, _: Op, _: &mut Memory, _: &mut PortBus) -> Result<(), CpuErr> {
+    Ok(())
+}
+fn mu(core: &mut CpuCore, op: Op, _: &mut Memory, _: &mut PortBus) -> Result<(), CpuErr> {
+    core.regs.set_u(op.a, op.imm as usize);
+    Ok(())
+}
+fn md(core: &mut CpuCore, op: Op, _: &mut Memory, _: &mut PortBus) -> Result<(), CpuErr> {
+    core.regs.set_f(op.a, f64::from_bits(op.imm));
+    Ok(())
+}
+fn mov_u(core: &mut CpuCore, op: Op, _: &mut Memory, _: &mut PortBus) -> Result<(), CpuErr> {
+    core.regs.set_u(op.a, core.regs.u(op.b));
+    Ok(())
+}
+fn mov_d(core: &mut CpuCore, op: Op, _: &mut Memory, _: &mut PortBus) -> Result<(), CpuErr> {
+    core.regs.set_f(op.a, core.regs.f(op.b));
+    Ok(())
+}
+fn mod_u(core: &mut CpuCore, op: Op, _: &mut Memory, _: &mut PortBus) -> Result<(), CpuErr> {
+    let r2 = core.regs.u(op.c);
+    if r2 == 0 {
+        return Err(ISAErr::DivByZero.into());
+    }
+    core.regs.set_u(op.a, core.regs.u(op.b) % r2);
+    Ok(())
+}
+fn add_u(core: &mut CpuCore, op: Op, _: &mut Memory, _: &mut PortBus) -> Result<(), CpuErr> {
+    match core.regs.u(op.b).overflowing_add(core.regs.u(op.c)) {
+        (v, false) => core.regs.set_u(op.a, v),
+        _ => overflow(core),
+    }
+    Ok(())
+}
+fn add_ui(core: &mut CpuCore, op: Op, _: &mut Memory, _: &mut PortBus) -> Result<(), CpuErr> {
+    match core.regs.u(op.a).overflowing_add(op.imm as usize) {
+        (v, false) => core.regs.set_u(op.a, v),
+        _ => overflow(core),
+    }
+    Ok(())
+}
+fn add_d(core: &mut CpuCore, op: Op, _: &mut Memory, _: &mut PortBus) -> Result<(), CpuErr> {
+    core.regs.set_f(op.a, core.regs.f(op.b) + core.regs.f(op.c));
+    Ok(())
+}
+fn add_di(core: &mut CpuCore, op: Op, _: &mut Memory, _: &mut PortBus) -> Result<(), CpuErr> {
+    core.regs
+        .set_f(op.a, core.regs.f(op.a) + f64::from_bits(op.imm));
+    Ok(())
+}
+fn sub_u(core: &mut CpuCore, op: Op, _: &mut Memory, _: &mut PortBus) -> Result<(), CpuErr> {
+    match core.regs.u(op.b).overflowing_sub(core.regs.u(op.c)) {
+        (v, false) => core.regs.set_u(op.a, v),
+        _ => overflow(core),
+    }
+    Ok(())
+}
+fn sub_ui(core: &mut CpuCore, op: Op, _: &mut Memory, _: &mut PortBus) -> Result<(), CpuErr> {
+    match core.regs.u(op.a).overflowing_sub(op.imm as usize) {
+        (v, false) => core.regs.set_u(op.a, v),
+        _ => overflow(core),
+    }
+    Ok(())
+}
+fn sub_d(core: &mut CpuCore, op: Op, _: &mut Memory, _: &mut PortBus) -> Result<(), CpuErr> {
+    core.regs.set_f(op.a, core.regs.f(op.b) - core.regs.f(op.c));
+    Ok(())
+}
+fn sub_di(core: &mut CpuCore, op: Op, _: &mut Memory, _: &mut PortBus) -> Result<(), CpuErr> {
+    core.regs
+        .set_f(op.a, core.regs.f(op.a) - f64::from_bits(op.imm));
+    Ok(())
+}
+fn mul_u(core: &mut CpuCore, op: Op, _: &mut Memory, _: &mut PortBus) -> Result<(), CpuErr> {
+    match core.regs.u(op.b).overflowing_mul(core.regs.u(op.c)) {
+        (v, false) => core.regs.set_u(op.a, v),
+        _ => overflow(core),
+    }
+    Ok(())
+}
+fn mul_d(core: &mut CpuCore, op: Op, _: &mut Memory, _: &mut PortBus) -> Result<(), CpuErr> {
+    core.regs.set_f(op.a, core.regs.f(op.b) * core.regs.f(op.c));
+    Ok(())
+}
+fn div_u(core: &mut CpuCore, op: Op, _: &mut Memory, _: &mut PortBus) -> Result<(), CpuErr> {
+    let r2 = core.regs.u(op.c);
+    if r2 == 0 {
+        return Err(ISAErr::DivByZero.into());
+    }
+    core.regs.set_u(op.a, core.regs.u(op.b) / r2);
+    Ok(())
+}
+fn div_d(core: &mut CpuCore, op: Op, _: &mut Memory, _: &mut PortBus) -> Result<(), CpuErr> {
+    core.regs.set_f(op.a, core.regs.f(op.b) / core.regs.f(op.c));
+    Ok(())
+}
+fn and(core: &mut CpuCore, op: Op, _: &mut Memory, _: &mut PortBus) -> Result<(), CpuErr> {
+    core.regs.set_u(op.a, core.regs.u(op.b) & core.regs.u(op.c));
+    Ok(())
+}
+fn or(core: &mut CpuCore, op: Op, _: &mut Memory, _: &mut PortBus) -> Result<(), CpuErr> {
+    core.regs.set_u(op.a, core.regs.u(op.b) | core.regs.u(op.c));
+    Ok(())
+}
+fn xor(core: &mut CpuCore, op: Op, _: &mut Memory, _: &mut PortBus) -> Result<(), CpuErr> {
+    core.regs.set_u(op.a, core.regs.u(op.b) ^ core.regs.u(op.c));
+    Ok(())
+}
+fn not(core: &mut CpuCore, op: Op, _: &mut Memory, _: &mut PortBus) -> Result<(), CpuErr> {
+    core.regs.set_u(op.a, !core.regs.u(op.b));
+    Ok(())
+}
+fn neg_u(core: &mut CpuCore, op: Op, _: &mut Memory, _: &mut PortBus) -> Result<(), CpuErr> {
+    match core.regs.u(op.b).overflowing_neg() {
+        (v, false) => core.regs.set_u(op.a, v),
+        _ => overflow(core),
+    }
+    Ok(())
+}
+fn neg_d(core: &mut CpuCore, op: Op, _: &mut Memory, _: &mut PortBus) -> Result<(), CpuErr> {
+    core.regs.set_f(op.a, -core.regs.f(op.b));
+    Ok(())
+}
+fn shl(core: &mut CpuCore, op: Op, _: &mut Memory, _: &mut PortBus) -> Result<(), CpuErr> {
+    match core.regs.u(op.b).overflowing_shl(1) {
+        (v, false) => core.regs.set_u(op.a, v),
+        _ => overflow(core),
+    }
+    Ok(())
+}
+fn shr(core: &mut CpuCore, op: Op, _: &mut Memory, _: &mut PortBus) -> Result<(), CpuErr> {
+    match core.regs.u(op.b).overflowing_shr(1) {
+        (v, false) => core.regs.set_u(op.a, v),
+        _ => overflow(core),
+    }
+    Ok(())
+}
+fn load_uh(core: &mut CpuCore, op: Op, mem: &mut Memory, _: &mut PortBus) -> Result<(), CpuErr> {
+    let v = mem.get_heap_u_type(core.regs.u(op.b))?;
+    core.regs.set_u(op.a, v);
+    Ok(())
+}
+fn load_dh(core: &mut CpuCore, op: Op, mem: &mut Memory, _: &mut PortBus) -> Result<(), CpuErr> {
+    let v = mem.get_heap_f_type(core.regs.u(op.b))?;
+    core.regs.set_f(op.a, v);
+    Ok(())
+}
+fn store_uh(core: &mut CpuCore, op: Op, mem: &mut Memory, _: &mut PortBus) -> Result<(), CpuErr> {
+    mem.set_heap(core.regs.u(op.b), &RegType::Usize(core.regs.u(op.a)))?;
+    Ok(())
+}
+fn store_dh(core: &mut CpuCore, op: Op, mem: &mut Memory, _: &mut PortBus) -> Result<(), CpuErr> {
+    mem.set_heap(core.regs.u(op.b), &RegType::F64(core.regs.f(op.a)))?;
+    Ok(())
+}
+fn load_us(core: &mut CpuCore, op: Op, mem: &mut Memory, _: &mut PortBus) -> Result<(), CpuErr> {
+    match mem.get_stack(core.regs.get_bp(), core.regs.u(op.b))? {
+        RegType::Usize(v) => core.regs.set_u(op.a, v),
+        _ => return Err(ISAErr::TypeMismatch.into()),
+    }
+    Ok(())
+}
+fn load_ds(core: &mut CpuCore, op: Op, mem: &mut Memory, _: &mut PortBus) -> Result<(), CpuErr> {
+    match mem.get_stack(core.regs.get_bp(), core.regs.u(op.b))? {
+        RegType::F64(v) => core.regs.set_f(op.a, v),
+        _ => return Err(ISAErr::TypeMismatch.into()),
+    }
+    Ok(())
+}
+fn store_us(core: &mut CpuCore, op: Op, mem: &mut Memory, _: &mut PortBus) -> Result<(), CpuErr> {
+    mem.set_stack(
+        core.regs.get_bp(),
+        core.regs.u(op.b),
+        RegType::Usize(core.regs.u(op.a)),
+    )?;
+    Ok(())
+}
+fn store_ds(core: &mut CpuCore, op: Op, mem: &mut Memory, _: &mut PortBus) -> Result<(), CpuErr> {
+    mem.set_stack(
+        core.regs.get_bp(),
+        core.regs.u(op.b),
+        RegType::F64(core.regs.f(op.a)),
+    )?;
+    Ok(())
+}
+fn jo(core: &mut CpuCore, op: Op, _: &mut Memory, _: &mut PortBus) -> Result<(), CpuErr> {
+    if core.flags.contains(Flags::Overflow) {
+        core.regs.set_pc(core.regs.u(op.a));
+    }
+    Ok(())
+}
+fn jno(core: &mut CpuCore, op: Op, _: &mut Memory, _: &mut PortBus) -> Result<(), CpuErr> {
+    if !core.flags.contains(Flags::Overflow) {
+        core.regs.set_pc(core.regs.u(op.a));
+    }
+    Ok(())
+}
+fn je(core: &mut CpuCore, op: Op, _: &mut Memory, _: &mut PortBus) -> Result<(), CpuErr> {
+    if core.regs.u(op.b) == core.regs.u(op.c) {
+        core.regs.set_pc(core.regs.u(op.a));
+    }
+    Ok(())
+}
+fn jne(core: &mut CpuCore, op: Op, _: &mut Memory, _: &mut PortBus) -> Result<(), CpuErr> {
+    if core.regs.u(op.b) != core.regs.u(op.c) {
+        core.regs.set_pc(core.regs.u(op.a));
+    }
+    Ok(())
+}
+fn jz(core: &mut CpuCore, op: Op, _: &mut Memory, _: &mut PortBus) -> Result<(), CpuErr> {
+    if core.regs.u(op.b) == 0 {
+        core.regs.set_pc(core.regs.u(op.a));
+    }
+    Ok(())
+}
+fn jnz(core: &mut CpuCore, op: Op, _: &mut Memory, _: &mut PortBus) -> Result<(), CpuErr> {
+    if core.regs.u(op.b) != 0 {
+        core.regs.set_pc(core.regs.u(op.a));
+    }
+    Ok(())
+}
+fn jmp(core: &mut CpuCore, op: Op, _: &mut Memory, _: &mut PortBus) -> Result<(), CpuErr> {
+    core.regs.set_pc(core.regs.u(op.a));
+    Ok(())
+}
+fn push_u(core: &mut CpuCore, op: Op, mem: &mut Memory, _: &mut PortBus) -> Result<(), CpuErr> {
+    mem.push_stack(RegType::Usize(core.regs.u(op.a)))?;
+    Ok(())
+}
+fn push_d(core: &mut CpuCore, op: Op, mem: &mut Memory, _: &mut PortBus) -> Result<(), CpuErr> {
+    mem.push_stack(RegType::F64(core.regs.f(op.a)))?;
+    Ok(())
+}
+fn pop_u(core: &mut CpuCore, op: Op, mem: &mut Memory, _: &mut PortBus) -> Result<(), CpuErr> {
+    match mem.pop_stack()? {
+        RegType::Usize(v) => core.regs.set_u(op.a, v),
+        _ => return Err(ISAErr::TypeMismatch.into()),
+    }
+    Ok(())
+}
+fn pop_d(core: &mut CpuCore, op: Op, mem: &mut Memory, _: &mut PortBus) -> Result<(), CpuErr> {
+    match mem.pop_stack()? {
+        RegType::F64(v) => core.regs.set_f(op.a, v),
+        _ => return Err(ISAErr::TypeMismatch.into()),
+    }
+    Ok(())
+}
+fn call(core: &mut CpuCore, op: Op, mem: &mut Memory, _: &mut PortBus) -> Result<(), CpuErr> {
+    let addr = core.regs.u(op.a);
+    mem.push_stack(RegType::Usize(core.regs.get_bp()))?;
+    mem.push_stack(RegType::Usize(core.regs.get_pc()))?;
+    core.regs.set_bp(mem.get_stack_top_addr());
+    core.regs.set_pc(addr);
+    Ok(())
+}
+fn ret(core: &mut CpuCore, _: Op, mem: &mut Memory, _: &mut PortBus) -> Result<(), CpuErr> {
+    mem.drop_stack_bp(core.regs.get_bp());
+    let pc = mem.pop_stack()?;
+    let bp = mem.pop_stack()?;
+    match (pc, bp) {
+        (RegType::Usize(pc), RegType::Usize(bp)) => {
+            core.regs.set_pc(pc);
+            core.regs.set_bp(bp);
+            Ok(())
+        }
+        _ => Err(ISAErr::TypeMismatch.into()),
+    }
+}
+fn halt(_: &mut CpuCore, _: Op, _: &mut Memory, _: &mut PortBus) -> Result<(), CpuErr> {
+    Err(ISAErr::Halt.into())
+}
+/// 融合的`Op`执行第二条指令前，把pc前移到第二条之后
+#[inline(always)]
+fn next(core: &mut CpuCore) {
+    core.regs.set_pc(core.regs.get_pc() + 1);
+}
+fn mu_jmp(core: &mut CpuCore, op: Op, mem: &mut Memory, bus: &mut PortBus) -> Result<(), CpuErr> {
+    mu(core, op, mem, bus)?;
+    next(core);
+    jmp(core, op, mem, bus)
+}
+fn mu_jz(core: &mut CpuCore, op: Op, mem: &mut Memory, bus: &mut PortBus) -> Result<(), CpuErr> {
+    mu(core, op, mem, bus)?;
+    next(core);
+    jz(core, op, mem, bus)
+}
+fn mu_jnz(core: &mut CpuCore, op: Op, mem: &mut Memory, bus: &mut PortBus) -> Result<(), CpuErr> {
+    mu(core, op, mem, bus)?;
+    next(core);
+    jnz(core, op, mem, bus)
+}
+fn mu_je(core: &mut CpuCore, op: Op, mem: &mut Memory, bus: &mut PortBus) -> Result<(), CpuErr> {
+    mu(core, op, mem, bus)?;
+    next(core);
+    je(core, op, mem, bus)
+}
+fn mu_jne(core: &mut CpuCore, op: Op, mem: &mut Memory, bus: &mut PortBus) -> Result<(), CpuErr> {
+    mu(core, op, mem, bus)?;
+    next(core);
+    jne(core, op, mem, bus)
+}
+fn mu_call(core: &mut CpuCore, op: Op, mem: &mut Memory, bus: &mut PortBus) -> Result<(), CpuErr> {
+    mu(core, op, mem, bus)?;
+    next(core);
+    call(core, op, mem, bus)
+}
+fn push_u_call(
+    core: &mut CpuCore,
+    op: Op,
+    mem: &mut Memory,
+    bus: &mut PortBus,
+) -> Result<(), CpuErr> {
+    push_u(core, op, mem, bus)?;
+    next(core);
+    call(core, Op { a: op.b, ..op }, mem, bus)
+}
+fn pop_u_push_u(
+    core: &mut CpuCore,
+    op: Op,
+    mem: &mut Memory,
+    bus: &mut PortBus,
+) -> Result<(), CpuErr> {
+    pop_u(core, op, mem, bus)?;
+    next(core);
+    push_u(core, Op { a: op.b, ..op }, mem, bus)
+}
+/// 取回原指令交给`core::run`执行
+fn interpret(
+    core: &mut CpuCore,
+    op: Op,
+    mem: &mut Memory,
+    bus: &mut PortBus,
+) -> Result<(), CpuErr> {
+    let inst = mem.code_segment()[op.imm as usize];
+    run(core, &inst, mem, bus)
+}
+
+#[cfg(test)]
+#[test]
+fn test_same_as_interpreter() {
+    use crate::asm::assemble;
+    use crate::test::fibonacci_code;
+
+    /// 用`core::run`逐条解释执行，作为对照
+    fn interpret_all(
+        core: &mut CpuCore,
+        mem: &mut Memory,
+        bus: &mut PortBus,
+    ) -> Result<(), CpuErr> {
+        while let Some(&inst) = mem.code_segment().get(core.get_pc()) {
+            core.set_pc(core.get_pc() + 1);
+            run(core, &inst, mem, bus)?;
+        }
+        Ok(())
+    }
+    let mixed = assemble(
+        "
+        mu u1, 0xffff_ffff_ffff_fff0
+        addui u1, 0x20          ; 溢出
+        mu u2, 7
+        mu u3, 3
+        mod u4, u2, u3
+        mulu u5, u2, u3
+        subu u6, u3, u2         ; 溢出
+        xor u7, u2, u3
+        shl u7, u7
+        not u8, u7
+        md f1, 1.5
+        md f2, -0.25
+        muld f3, f1, f2
+        divd f4, f1, f2
+        negd f5, f4
+        adddi f5, 2.0
+        storeuh u5, u3
+        loaduh u6, u3
+        storedh f3, u2
+        loaddh f6, u2
+        pushu u5
+        pushd f6
+        popd f7
+        popu u8
+        mu u1, done
+        jno u1
+        nop
+    done:
+        mu u2, 0
+        divu u3, u3, u2         ; 除零
+        ",
+    )
+    .unwrap();
+    for code in [mixed, fibonacci_code(8)] {
+        let mut results = Vec::new();
+        for threaded in [false, true] {
+            let (mut core, mut mem, mut bus) = (CpuCore::new(), Memory::new(), PortBus::new());
+            mem.store(Some(code.clone()), None, None);
+            let r = if threaded {
+                core.start(&mut mem, &mut bus, None)
+                    .map(|_| ())
+                    .map_err(|f| format!("{:?}", f.err))
+            } else {
+                match interpret_all(&mut core, &mut mem, &mut bus) {
+                    Err(CpuErr::ISAErr(ISAErr::Halt)) | Ok(()) => Ok(()),
+                    Err(e) => Err(format!("{:?}", e)),
+                }
+            };
+            results.push(format!("{:?} {:?} {:?}", r, core, mem.load()));
+        }
+        assert_eq!(results[0], results[1]);
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_fusion() {
+    use crate::asm::assemble;
+
+    let code = assemble(
+        "
+        mu u1, 3
+        mu u8, sub
+    loop:
+        pushu u1
+        call u8
+        popu u2
+        pushu u2
+        popu u3
+        subui u1, 1
+        mu u4, loop
+        jnz u4, u1
+        mu u6, 1
+        mu u5, second
+        jmp u5                  ; 跳到融合指令对的第二条
+        mu u5, 0
+    second:
+        jz u5, u6
+        md f1, 1.0
+        pushd f1
+        popu u1                 ; 类型不符
+        halt
+    sub:
+        ret
+        ",
+    )
+    .unwrap();
+    let ops = compile(&code, true);
+    assert_eq!(ops.iter().filter(|op| op.len() == 2).count(), 5);
+    for limit in (0..60).map(Some).chain([None]) {
+        let mut results = Vec::new();
+        for fuse in [false, true] {
+            let (mut core, mut mem, mut bus) = (CpuCore::new(), Memory::new(), PortBus::new());
+            mem.set_fusion(fuse);
+            mem.store(Some(code.clone()), None, None);
+            let r = core.start(&mut mem, &mut bus, limit);
+            results.push(format!("{:?} {:?} {:?}", r, core, mem.load()));
+        }
+        assert_eq!(results[0], results[1], "limit {:?}", limit);
+    }
+}
@@ -0,0 +1,674 @@
+//! 交互式调试器
+//!
+//! 支持代码地址上的断点、堆地址、栈槽和寄存器上的观察点、单步、跳过`Call`、
+//! 执行到当前函数返回、查看和修改寄存器以及根据`Call`保存的帧回溯调用栈。
+//! 执行时记下撤销所需的旧值（见`history`），可以反向单步、反向执行到断点或观察点，
+//! 以及回退到最后一次改变某个寄存器或堆地址的指令
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write as _;
+use std::io::{self, BufRead, Write};
+
+use demo_isa::reg::{F64Reg, F64RegType, Flags, UsizeReg, UsizeRegType};
+use demo_isa::Inst;
+use enumflags2::BitFlags;
+
+use crate::asm::disasm::format_inst;
+use crate::asm::{symbolize, F_REGS, U_REGS};
+use crate::cpu::{CpuCore, ExitReason};
+use crate::image::Writer;
+use crate::{VmErr, VmTmp};
+
+use self::history::History;
+
+pub mod history;
+
+/// 观察的位置
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Watch {
+    /// 堆地址
+    Heap(UsizeRegType),
+    /// 栈段中的绝对位置
+    Stack(UsizeRegType),
+    U(UsizeReg),
+    F(F64Reg),
+}
+
+#[derive(Debug)]
+struct Watchpoint {
+    watch: Watch,
+    /// 上一次观察到的值的编码，不存在时为`None`
+    last: Option<Vec<u8>>,
+}
+
+/// 调试器停下的原因
+#[derive(Debug)]
+pub enum Stop {
+    /// 程序停机或越过代码末尾
+    Exit(ExitReason),
+    /// 到达断点，尚未执行该处的指令
+    Breakpoint(UsizeRegType),
+    /// 观察点的值发生了变化，参数为观察点的编号
+    Watchpoint(usize),
+    /// 单步、`next`、`finish`或反向执行完成
+    Done,
+    /// 反向执行到了记录的起点
+    HistoryStart,
+    Fault(VmErr),
+}
+
+#[derive(Debug)]
+pub struct Debugger {
+    vm: VmTmp,
+    breakpoints: BTreeSet<UsizeRegType>,
+    watchpoints: Vec<Watchpoint>,
+    history: History,
+}
+
+impl Debugger {
+    /// 使用虚拟机的符号表显示地址
+    pub fn new(vm: VmTmp) -> Debugger {
+        Debugger {
+            vm,
+            breakpoints: BTreeSet::new(),
+            watchpoints: Vec::new(),
+            history: History::new(),
+        }
+    }
+    /// 使用汇编器给出的符号表显示地址
+    pub fn with_labels(mut vm: VmTmp, labels: BTreeMap<String, UsizeRegType>) -> Debugger {
+        vm.set_symbols(labels);
+        Debugger::new(vm)
+    }
+    pub fn vm(&self) -> &VmTmp {
+        &self.vm
+    }
+    /// 修改虚拟机会丢弃反向执行的记录
+    pub fn vm_mut(&mut self) -> &mut VmTmp {
+        self.history.clear();
+        &mut self.vm
+    }
+    pub fn history(&self) -> &History {
+        &self.history
+    }
+    pub fn into_vm(self) -> VmTmp {
+        self.vm
+    }
+    pub fn add_breakpoint(&mut self, addr: UsizeRegType) {
+        self.breakpoints.insert(addr);
+    }
+    pub fn remove_breakpoint(&mut self, addr: UsizeRegType) -> bool {
+        self.breakpoints.remove(&addr)
+    }
+    /// 添加观察点，返回其编号
+    pub fn add_watchpoint(&mut self, watch: Watch) -> usize {
+        let last = self.watch_value(watch);
+        self.watchpoints.push(Watchpoint { watch, last });
+        self.watchpoints.len() - 1
+    }
+    pub fn remove_watchpoint(&mut self, index: usize) -> Option<Watch> {
+        (index < self.watchpoints.len()).then(|| self.watchpoints.remove(index).watch)
+    }
+
+    /// 执行`n`条指令，`n`为0时什么也不做
+    pub fn step(&mut self, n: usize) -> Stop {
+        if n == 0 {
+            return Stop::Done;
+        }
+        let mut left = n;
+        self.run_until(|_| {
+            left -= 1;
+            left == 0
+        })
+    }
+    /// 执行一条指令，若为`Call`则一直执行到其返回
+    pub fn step_over(&mut self) -> Stop {
+        let pc = self.vm.get_pc();
+        match self.vm.code().get(pc) {
+            Some(Inst::Call(_)) => {
+                let bp = self.vm.get_bp();
+                self.run_until(|vm| vm.get_pc() == pc + 1 && vm.get_bp() == bp)
+            }
+            _ => self.step(1),
+        }
+    }
+    /// 执行到当前函数`Ret`返回调用者
+    pub fn finish(&mut self) -> Stop {
+        match self.vm.backtrace().get(1).copied() {
+            Some(caller) => {
+                self.run_until(|vm| vm.get_pc() == caller.pc && vm.get_bp() == caller.bp)
+            }
+            None => self.cont(),
+        }
+    }
+    /// 执行直到断点、观察点、程序结束或出错
+    pub fn cont(&mut self) -> Stop {
+        self.run_until(|_| false)
+    }
+
+    /// 反向执行`n`条指令
+    pub fn reverse_step(&mut self, n: usize) -> Stop {
+        let target = self.history.now().saturating_sub(n);
+        self.history.rewind(&mut self.vm, target);
+        self.sync_watchpoints();
+        if self.history.now() == target {
+            Stop::Done
+        } else {
+            Stop::HistoryStart
+        }
+    }
+    /// 反向执行直到断点、观察点或记录的起点，停在断点处的指令执行之前
+    pub fn reverse_continue(&mut self) -> Stop {
+        if self.watchpoints.is_empty() {
+            // 没有观察点时直接找到最后一次经过断点的位置，可以从检查点恢复
+            let hit = (self.history.start()..self.history.now())
+                .rev()
+                .find(|&i| self.breakpoints.contains(&self.history.pc(i).unwrap()));
+            self.history
+                .rewind(&mut self.vm, hit.unwrap_or(self.history.start()));
+            return match hit {
+                Some(_) => Stop::Breakpoint(self.vm.get_pc()),
+                None => Stop::HistoryStart,
+            };
+        }
+        while self.history.undo(&mut self.vm) {
+            if let Some(i) = self.check_watchpoints() {
+                return Stop::Watchpoint(i);
+            }
+            let pc = self.vm.get_pc();
+            if self.breakpoints.contains(&pc) {
+                return Stop::Breakpoint(pc);
+            }
+        }
+        Stop::HistoryStart
+    }
+    /// 反向执行到最后一次改变`watch`的指令执行之前
+    pub fn last_write(&mut self, watch: Watch) -> Stop {
+        let value = self.watch_value(watch);
+        let stop = loop {
+            if !self.history.undo(&mut self.vm) {
+                break Stop::HistoryStart;
+            }
+            if self.watch_value(watch) != value {
+                break Stop::Done;
+            }
+        };
+        self.sync_watchpoints();
+        stop
+    }
+
+    fn run_until(&mut self, mut done: impl FnMut(&VmTmp) -> bool) -> Stop {
+        let mut first = true;
+        loop {
+            let pc = self.vm.get_pc();
+            if !first && self.breakpoints.contains(&pc) {
+                return Stop::Breakpoint(pc);
+            }
+            first = false;
+            match self.history.step(&mut self.vm) {
+                Ok(Some(reason)) => return Stop::Exit(reason),
+                Ok(None) => {}
+                Err(e) => return Stop::Fault(e),
+            }
+            if let Some(i) = self.check_watchpoints() {
+                return Stop::Watchpoint(i);
+            }
+            if done(&self.vm) {
+                return Stop::Done;
+            }
+        }
+    }
+
+    fn watch_value(&self, watch: Watch) -> Option<Vec<u8>> {
+        let mut w = Writer::new();
+        match watch {
+            Watch::Heap(addr) => w.heap_obj(self.vm.heap().get(addr)?),
+            Watch::Stack(slot) => w.reg_type(self.vm.stack().get(slot)?),
+            Watch::U(r) => w.usize(self.vm.get_u_reg(r)),
+            Watch::F(r) => w.f64(self.vm.get_f_reg(r)),
+        }
+        Some(w.finish())
+    }
+
+    /// 反向执行后按当前的值更新观察点，此后只在再次改变时停下
+    fn sync_watchpoints(&mut self) {
+        for i in 0..self.watchpoints.len() {
+            self.watchpoints[i].last = self.watch_value(self.watchpoints[i].watch);
+        }
+    }
+
+    fn check_watchpoints(&mut self) -> Option<usize> {
+        let mut hit = None;
+        for i in 0..self.watchpoints.len() {
+            let value = self.watch_value(self.watchpoints[i].watch);
+            if value != self.watchpoints[i].last {
+                self.watchpoints[i].last = value;
+                hit.get_or_insert(i);
+            }
+        }
+        hit
+    }
+
+    fn location(&self, addr: UsizeRegType) -> String {
+        match symbolize(self.vm.symbols(), addr) {
+            Some((name, 0)) => format!("{:04} <{}>", addr, name),
+            Some((name, off)) => format!("{:04} <{}+{}>", addr, name, off),
+            None => format!("{:04}", addr),
+        }
+    }
+
+    fn describe(&self, stop: &Stop) -> String {
+        match stop {
+            Stop::Exit(reason) => format!(
+                "program exited: {:?}, u1={}",
+                reason,
+                self.vm.get_u_reg(UsizeReg::U1)
+            ),
+            Stop::Breakpoint(pc) => format!("breakpoint at {}", self.location(*pc)),
+            Stop::Watchpoint(i) => {
+                let watch = self.watchpoints[*i].watch;
+                format!("watchpoint {} {:?}: {}", i, watch, self.show_watch(watch))
+            }
+            Stop::Done => self.current_line(),
+            Stop::HistoryStart => format!("reached start of history\n{}", self.current_line()),
+            Stop::Fault(e) => format!("fault: {}", e),
+        }
+    }
+
+    fn show_watch(&self, watch: Watch) -> String {
+        match watch {
+            Watch::Heap(addr) => match self.vm.heap().get(addr) {
+                Some(obj) => format!("{:?}", obj),
+                None => "<unallocated>".to_string(),
+            },
+            Watch::Stack(slot) => match self.vm.stack().get(slot) {
+                Some(v) => format!("{:?}", v),
+                None => "<empty>".to_string(),
+            },
+            Watch::U(r) => format!("{:#x}", self.vm.get_u_reg(r)),
+            Watch::F(r) => format!("{}", self.vm.get_f_reg(r)),
+        }
+    }
+
+    fn current_line(&self) -> String {
+        let pc = self.vm.get_pc();
+        match self.vm.code().get(pc) {
+            Some(inst) => format!("{}  {}", self.location(pc), format_inst(inst)),
+            None => format!("{}  <end of code>", self.location(pc)),
+        }
+    }
+
+    /// 解析地址，可以是数字或标签
+    fn parse_addr(&self, s: &str) -> Result<UsizeRegType, String> {
+        parse_u(s)
+            .or_else(|| self.vm.symbols().get(s).copied())
+            .ok_or_else(|| format!("invalid address `{}`", s))
+    }
+
+    /// 执行一条调试命令，返回`false`表示退出
+    pub fn command(&mut self, line: &str, out: &mut impl Write) -> io::Result<bool> {
+        let args: Vec<&str> = line.split_whitespace().collect();
+        let Some((&cmd, args)) = args.split_first() else {
+            return Ok(true);
+        };
+        let result = match (cmd, args) {
+            ("q" | "quit", _) => return Ok(false),
+            ("h" | "help", _) => Ok(HELP.to_string()),
+            ("s" | "step", []) => Ok(self.run_cmd(|d| d.step(1))),
+            ("s" | "step", [n]) => match n.parse() {
+                Ok(n) if n > 0 => Ok(self.run_cmd(|d| d.step(n))),
+                _ => Err(format!("invalid count `{}`", n)),
+            },
+            ("n" | "next", []) => Ok(self.run_cmd(Debugger::step_over)),
+            ("fin" | "finish", []) => Ok(self.run_cmd(Debugger::finish)),
+            ("c" | "continue", []) => Ok(self.run_cmd(Debugger::cont)),
+            ("rs" | "rstep", []) => Ok(self.run_cmd(|d| d.reverse_step(1))),
+            ("rs" | "rstep", [n]) => match n.parse() {
+                Ok(n) if n > 0 => Ok(self.run_cmd(|d| d.reverse_step(n))),
+                _ => Err(format!("invalid count `{}`", n)),
+            },
+            ("rc" | "rcontinue", []) => Ok(self.run_cmd(Debugger::reverse_continue)),
+            ("last", loc) => parse_watch(loc).map(|w| self.run_cmd(|d| d.last_write(w))),
+            ("b" | "break", []) => Ok(self
+                .breakpoints
+                .iter()
+                .map(|&a| format!("{}\n", self.location(a)))
+                .collect()),
+            ("b" | "break", [addr]) => self.parse_addr(addr).map(|a| {
+                self.add_breakpoint(a);
+                format!("breakpoint at {}\n", self.location(a))
+            }),
+            ("d" | "delete", [addr]) => self.parse_addr(addr).and_then(|a| {
+                if self.remove_breakpoint(a) {
+                    Ok(String::new())
+                } else {
+                    Err(format!("no breakpoint at {}", self.location(a)))
+                }
+            }),
+            ("w" | "watch", []) => Ok(self
+                .watchpoints
+                .iter()
+                .enumerate()
+                .map(|(i, w)| format!("{}: {:?} = {}\n", i, w.watch, self.show_watch(w.watch)))
+                .collect()),
+            ("w" | "watch", loc) => parse_watch(loc)
+                .map(|w| format!("watchpoint {}: {:?}\n", self.add_watchpoint(w), w)),
+            ("unwatch", [i]) => match i.parse().ok().and_then(|i| self.remove_watchpoint(i)) {
+                Some(_) => Ok(String::new()),
+                None => Err(format!("no watchpoint `{}`", i)),
+            },
+            ("r" | "regs", []) => Ok(format_state(&self.vm)),
+            ("set", [reg, value]) => self.set_reg(reg, value).map(|_| String::new()),
+            ("bt" | "backtrace", []) => Ok(self
+                .vm
+                .backtrace()
+                .iter()
+                .enumerate()
+                .map(|(i, f)| format!("#{:<3}{}  bp={}\n", i, self.location(f.pc), f.bp))
+                .collect()),
+            ("l" | "list", []) => Ok(self.list()),
+            ("heap", [addr]) => parse_u(addr)
+                .map(|a| format!("{}\n", self.show_watch(Watch::Heap(a))))
+                .ok_or_else(|| format!("invalid address `{}`", addr)),
+            ("stack", []) => Ok(self
+                .vm
+                .stack()
+                .iter()
+                .enumerate()
+                .map(|(i, v)| format!("{:04}  {:?}\n", i, v))
+                .collect()),
+            _ => Err(format!("unknown command `{}`, try `help`", line.trim())),
+        };
+        match result {
+            Ok(text) => write!(out, "{}", text)?,
+            Err(msg) => writeln!(out, "error: {}", msg)?,
+        }
+        Ok(true)
+    }
+
+    fn run_cmd(&mut self, f: impl FnOnce(&mut Debugger) -> Stop) -> String {
+        let stop = f(self);
+        format!("{}\n", self.describe(&stop))
+    }
+
+    fn list(&self) -> String {
+        let pc = self.vm.get_pc();
+        let code = self.vm.code();
+        let mut out = String::new();
+        for (addr, inst) in code
+            .iter()
+            .enumerate()
+            .take(pc + 4)
+            .skip(pc.saturating_sub(3))
+        {
+            let marker = if addr == pc { "=>" } else { "  " };
+            let bp = if self.breakpoints.contains(&addr) {
+                "*"
+            } else {
+                " "
+            };
+            writeln!(
+                out,
+                "{}{} {}  {}",
+                marker,
+                bp,
+                self.location(addr),
+                format_inst(inst)
+            )
+            .unwrap();
+        }
+        out
+    }
+
+    fn set_reg(&mut self, reg: &str, value: &str) -> Result<(), String> {
+        let invalid = || format!("invalid value `{}`", value);
+        self.history.clear();
+        let reg = reg.to_ascii_lowercase();
+        match reg.as_str() {
+            "pc" => self.vm.set_pc(self.parse_addr(value)?),
+            "bp" => self.vm.set_bp(parse_u(value).ok_or_else(invalid)?),
+            "overflow" => {
+                let mut flags: BitFlags<Flags> = self.vm.get_flags();
+                match value {
+                    "1" | "on" => flags.insert(Flags::Overflow),
+                    "0" | "off" => flags.remove(Flags::Overflow),
+                    _ => return Err(invalid()),
+                }
+                self.vm.set_flags(flags);
+            }
+            _ => match (reg.as_bytes()[0], reg.get(1..).and_then(|n| n.parse().ok())) {
+                (b'u', Some(i @ 1..=8)) => self.vm.set_u_reg(
+                    U_REGS[i - 1],
+                    parse_u(value)
+                        .or_else(|| self.vm.symbols().get(value).copied())
+                        .ok_or_else(invalid)?,
+                ),
+                (b'f', Some(i @ 1..=8)) => self.vm.set_f_reg(
+                    F_REGS[i - 1],
+                    value.parse::<F64RegType>().map_err(|_| invalid())?,
+                ),
+                _ => return Err(format!("unknown register `{}`", reg)),
+            },
+        }
+        Ok(())
+    }
+
+    /// 从`input`读取命令直到输入结束或`quit`
+    pub fn repl(&mut self, input: impl BufRead, out: &mut impl Write) -> io::Result<()> {
+        writeln!(out, "{}", self.current_line())?;
+        write!(out, "(dvm) ")?;
+        out.flush()?;
+        for line in input.lines() {
+            if !self.command(&line?, out)? {
+                break;
+            }
+            write!(out, "(dvm) ")?;
+            out.flush()?;
+        }
+        Ok(())
+    }
+}
+
+const HELP: &str = "\
+s, step [n]            执行n条指令，默认为1
+n, next                单步，跳过Call
+fin, finish            执行到当前函数返回
+c, continue            继续执行
+rs, rstep [n]          反向执行n条指令，默认为1
+rc, rcontinue          反向执行到断点或观察点
+last <loc>             反向执行到最后一次改变loc的指令之前
+b, break [addr]        在地址或标签处设置断点，无参数时列出断点
+d, delete <addr>       删除断点
+w, watch [<loc>]       设置观察点，loc为heap <addr>、stack <addr>或u1-u8、f1-f8，无参数时列出观察点
+unwatch <n>            删除观察点
+r, regs                查看寄存器和标志位
+set <reg> <value>      修改u1-u8、f1-f8、pc、bp或overflow
+bt, backtrace          回溯调用栈
+l, list                反汇编pc附近的代码
+heap <addr>            查看堆
+stack                  查看栈
+q, quit                退出
+";
+
+/// 解析观察的位置：`heap <addr>`、`stack <addr>`或寄存器名
+fn parse_watch(args: &[&str]) -> Result<Watch, String> {
+    match args {
+        [kind, addr] => {
+            let addr = parse_u(addr).ok_or_else(|| format!("invalid address `{}`", addr));
+            match *kind {
+                "heap" => addr.map(Watch::Heap),
+                "stack" => addr.map(Watch::Stack),
+                _ => Err(format!("unknown watch kind `{}`", kind)),
+            }
+        }
+        [reg] => {
+            let reg = reg.to_ascii_lowercase();
+            match (reg.as_bytes()[0], reg.get(1..).and_then(|n| n.parse().ok())) {
+                (b'u', Some(i @ 1..=8)) => Ok(Watch::U(U_REGS[i - 1])),
+                (b'f', Some(i @ 1..=8)) => Ok(Watch::F(F_REGS[i - 1])),
+                _ => Err(format!("unknown register `{}`", reg)),
+            }
+        }
+        _ => Err("expected heap <addr>, stack <addr> or a register".to_string()),
+    }
+}
+
+fn parse_u(s: &str) -> Option<UsizeRegType> {
+    match s.strip_prefix("0x") {
+        Some(h) => UsizeRegType::from_str_radix(h, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+/// 寄存器和标志位的文本表示
+pub fn format_state(vm: &VmTmp) -> String {
+    format_core(&vm.core)
+}
+
+/// 同`format_state`，用于`machine::Machine`中的核心
+pub fn format_core(core: &CpuCore) -> String {
+    let mut out = String::new();
+    for (i, r) in U_REGS.iter().enumerate() {
+        write!(out, "u{}={:<#18x}", i + 1, core.get_u_reg(*r)).unwrap();
+        if i % 4 == 3 {
+            out.push('\n');
+        }
+    }
+    for (i, r) in F_REGS.iter().enumerate() {
+        write!(out, "f{}={:<18}", i + 1, core.get_f_reg(*r)).unwrap();
+        if i % 4 == 3 {
+            out.push('\n');
+        }
+    }
+    writeln!(
+        out,
+        "pc={} bp={} flags={:?}",
+        core.get_pc(),
+        core.get_bp(),
+        core.flags
+    )
+    .unwrap();
+    out
+}
+
+#[cfg(test)]
+#[test]
+fn test_debugger() {
+    use crate::asm::assemble_program;
+    use crate::cpu::Frame;
+    use demo_isa::reg::UsizeReg;
+
+    let program = assemble_program(
+        "
+            mu u1, 2
+            mu u2, 0
+            mu u8, add
+            call u8
+            call u8
+            halt
+        add:
+            storeuh u1, u2
+            addu u3, u3, u1
+            ret
+        ",
+    )
+    .unwrap();
+    let mut vm = VmTmp::new();
+    vm.set_code(program.code);
+    let mut d = Debugger::with_labels(vm, program.labels);
+
+    d.add_breakpoint(6);
+    assert!(matches!(d.cont(), Stop::Breakpoint(6)));
+    assert_eq!(
+        d.vm().backtrace(),
+        vec![Frame { pc: 6, bp: 1 }, Frame { pc: 4, bp: 0 }]
+    );
+    // 断点处的指令在继续执行时不会再次停下
+    let pc = d.vm().get_pc();
+    assert!(matches!(d.step(0), Stop::Done));
+    assert_eq!(d.vm().get_pc(), pc);
+    assert!(matches!(d.step(1), Stop::Done));
+    assert!(matches!(d.finish(), Stop::Done));
+    assert_eq!((d.vm().get_pc(), d.vm().get_bp()), (4, 0));
+    assert_eq!(d.vm().get_u_reg(UsizeReg::U3), 2);
+
+    d.remove_breakpoint(6);
+    assert!(matches!(d.step_over(), Stop::Done));
+    assert_eq!(d.vm().get_pc(), 5);
+    assert_eq!(d.vm().get_u_reg(UsizeReg::U3), 4);
+    assert!(matches!(d.step(1), Stop::Exit(_)));
+
+    // 观察点
+    let mut vm = VmTmp::new();
+    vm.set_code(vec![
+        Inst::MU(UsizeReg::U1, 5),
+        Inst::PushU(UsizeReg::U1),
+        Inst::StoreUH(UsizeReg::U1, UsizeReg::U2),
+        Inst::StoreUH(UsizeReg::U1, UsizeReg::U2),
+        Inst::AddUI(UsizeReg::U1, 1),
+        Inst::StoreUH(UsizeReg::U1, UsizeReg::U2),
+    ]);
+    let mut d = Debugger::new(vm);
+    d.add_watchpoint(Watch::Stack(0));
+    d.add_watchpoint(Watch::Heap(0));
+    assert!(matches!(d.cont(), Stop::Watchpoint(0)));
+    assert_eq!(d.vm().get_pc(), 2);
+    assert!(matches!(d.cont(), Stop::Watchpoint(1)));
+    assert_eq!(d.vm().get_pc(), 3);
+    // 写入相同的值不会触发
+    assert!(matches!(d.cont(), Stop::Watchpoint(1)));
+    assert_eq!(d.vm().get_pc(), 6);
+
+    // 命令
+    let mut out = Vec::new();
+    d.command("set u4 0x10", &mut out).unwrap();
+    d.command("set f2 2.5", &mut out).unwrap();
+    d.command("set overflow on", &mut out).unwrap();
+    d.command("set pc 1", &mut out).unwrap();
+    d.command("set u9 1", &mut out).unwrap();
+    assert!(!d.command("quit", &mut out).unwrap());
+    assert_eq!(d.vm().get_u_reg(UsizeReg::U4), 16);
+    assert_eq!(d.vm().get_f_reg(demo_isa::reg::F64Reg::F2), 2.5);
+    assert!(d.vm().get_flags().contains(Flags::Overflow));
+    assert_eq!(d.vm().get_pc(), 1);
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "error: unknown register `u9`\n"
+    );
+
+    // 反向执行
+    let mut vm = VmTmp::new();
+    vm.set_code(vec![
+        Inst::MU(UsizeReg::U1, 5),
+        Inst::MU(UsizeReg::U2, 0),
+        Inst::StoreUH(UsizeReg::U1, UsizeReg::U2),
+        Inst::AddUI(UsizeReg::U1, 1),
+        Inst::StoreUH(UsizeReg::U1, UsizeReg::U2),
+        Inst::MU(UsizeReg::U1, 0),
+    ]);
+    let mut d = Debugger::new(vm);
+    assert!(matches!(d.cont(), Stop::Exit(_)));
+    assert!(matches!(d.last_write(Watch::U(UsizeReg::U1)), Stop::Done));
+    assert_eq!(d.vm().get_pc(), 5);
+    assert!(matches!(d.last_write(Watch::Heap(0)), Stop::Done));
+    assert_eq!(d.vm().get_pc(), 4);
+    assert!(matches!(d.last_write(Watch::U(UsizeReg::U1)), Stop::Done));
+    assert_eq!((d.vm().get_pc(), d.vm().get_u_reg(UsizeReg::U1)), (3, 5));
+    assert!(matches!(d.reverse_step(1), Stop::Done));
+    assert!(d.vm().heap().is_empty());
+    d.add_breakpoint(1);
+    assert!(matches!(d.cont(), Stop::Exit(_)));
+    assert!(matches!(d.reverse_continue(), Stop::Breakpoint(1)));
+    assert!(matches!(d.reverse_continue(), Stop::HistoryStart));
+    assert_eq!(d.vm().get_pc(), 0);
+    d.remove_breakpoint(1);
+    let mut out = Vec::new();
+    d.command("s 5", &mut out).unwrap();
+    d.command("last heap 0", &mut out).unwrap();
+    d.command("last u3", &mut out).unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "0005  mu u1, 0\n\
+         0004  storeuh u1, u2\n\
+         reached start of history\n0000  mu u1, 5\n"
+    );
+}
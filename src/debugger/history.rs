@@ -0,0 +1,446 @@
+//! 反向执行的记录
+//!
+//! 每执行一条指令之前记下它将覆盖的旧值：pc、bp、标志位、燃料、被改变的寄存器、
+//! 被写入的堆对象以及被覆盖或弹出的栈槽，撤销时按相反的顺序写回。
+//! 系统调用可能写入任意内存或切换绿色线程，只在每条`SysCall`之前另外保存寄存器、堆段、
+//! 栈段和线程表的检查点，撤销系统调用时从检查点恢复。超过`capacity`条后丢弃最早的记录；检查点的总大小超过
+//! `checkpoint_bytes`时丢弃最早的检查点，连同它之前的记录，此后不能回退到那条系统调用之前。
+//!
+//! 对设备的读写无法撤销，回退后再向前执行时会重新访问设备。
+use std::collections::VecDeque;
+
+use demo_isa::reg::{F64Reg, F64RegType, Flags, UsizeReg, UsizeRegType};
+use demo_isa::{Inst, RegType};
+use enumflags2::BitFlags;
+
+use crate::asm::{F_REGS, U_REGS};
+use crate::cpu::green::Threads;
+use crate::cpu::ExitReason;
+use crate::memory::heap::HeapObj;
+use crate::memory::{Heap, Stack};
+use crate::{VmErr, VmTmp};
+
+/// 默认最多记录2^20条指令
+pub const DEFAULT_CAPACITY: usize = 1 << 20;
+/// 默认检查点共占用不超过64MiB
+pub const DEFAULT_CHECKPOINT_BYTES: usize = 64 << 20;
+
+/// 撤销一条指令所需的旧值
+#[derive(Debug)]
+struct Undo {
+    pc: UsizeRegType,
+    bp: UsizeRegType,
+    flags: BitFlags<Flags>,
+    fuel: Option<u64>,
+    /// 被改变的寄存器原来的值
+    u: Vec<(UsizeReg, UsizeRegType)>,
+    f: Vec<(F64Reg, F64RegType)>,
+    heap_len: usize,
+    /// 被覆盖的堆对象
+    heap: Option<(UsizeRegType, HeapObj)>,
+    stack_len: usize,
+    /// 被覆盖或弹出的栈槽，按地址递增
+    stack: Vec<(UsizeRegType, RegType)>,
+}
+
+impl Undo {
+    fn apply(self, vm: &mut VmTmp) {
+        vm.set_pc(self.pc);
+        vm.set_bp(self.bp);
+        vm.set_flags(self.flags);
+        vm.set_fuel(self.fuel);
+        for (r, v) in self.u {
+            vm.set_u_reg(r, v);
+        }
+        for (r, v) in self.f {
+            vm.set_f_reg(r, v);
+        }
+        let heap = vm.mem.heap_segment_mut();
+        heap.truncate(self.heap_len);
+        if let Some((addr, obj)) = self.heap {
+            heap[addr] = obj;
+        }
+        let stack = vm.mem.stack_segment_mut();
+        stack.truncate(self.stack_len);
+        for (slot, v) in self.stack {
+            match stack.get_mut(slot) {
+                Some(old) => *old = v,
+                None => stack.push(v),
+            }
+        }
+    }
+}
+
+/// 第`step`条指令执行之前的状态，不含代码段
+#[derive(Debug)]
+struct Checkpoint {
+    step: usize,
+    u: [UsizeRegType; 8],
+    f: [F64RegType; 8],
+    pc: UsizeRegType,
+    bp: UsizeRegType,
+    flags: BitFlags<Flags>,
+    fuel: Option<u64>,
+    heap: Heap,
+    stack: Stack,
+    threads: Threads,
+    /// 堆段、栈段和其他线程的栈大约占用的字节数
+    bytes: usize,
+}
+
+impl Checkpoint {
+    fn new(step: usize, vm: &VmTmp) -> Checkpoint {
+        let elems: usize = vm
+            .heap()
+            .iter()
+            .map(|obj| match obj {
+                HeapObj::UArray(a) => a.len(),
+                HeapObj::FArray(a) => a.len(),
+                HeapObj::R(_) => 0,
+            })
+            .sum();
+        let bytes = vm.heap().len() * size_of::<HeapObj>()
+            + elems * size_of::<UsizeRegType>()
+            + (vm.stack().len() + vm.core.threads().parked_stack()) * size_of::<RegType>();
+        Checkpoint {
+            step,
+            u: U_REGS.map(|r| vm.get_u_reg(r)),
+            f: F_REGS.map(|r| vm.get_f_reg(r)),
+            pc: vm.get_pc(),
+            bp: vm.get_bp(),
+            flags: vm.get_flags(),
+            fuel: vm.fuel(),
+            heap: vm.heap().clone(),
+            stack: vm.stack().clone(),
+            threads: vm.core.threads().clone(),
+            bytes,
+        }
+    }
+    fn restore(self, vm: &mut VmTmp) {
+        for (r, v) in U_REGS.into_iter().zip(self.u) {
+            vm.set_u_reg(r, v);
+        }
+        for (r, v) in F_REGS.into_iter().zip(self.f) {
+            vm.set_f_reg(r, v);
+        }
+        vm.set_pc(self.pc);
+        vm.set_bp(self.bp);
+        vm.set_flags(self.flags);
+        vm.set_fuel(self.fuel);
+        vm.mem.store(None, Some(self.heap), Some(self.stack));
+        vm.mem.set_parked_stack(self.threads.parked_stack());
+        vm.core.set_threads(self.threads);
+    }
+}
+
+/// 已执行指令的撤销记录，指令按执行的顺序从0编号
+#[derive(Debug)]
+pub struct History {
+    /// `undo[0]`撤销的是第`base`条指令
+    base: usize,
+    undo: VecDeque<Undo>,
+    /// 按`step`递增
+    checkpoints: VecDeque<Checkpoint>,
+    capacity: usize,
+    checkpoint_bytes: usize,
+    /// 现有检查点的总字节数
+    bytes: usize,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl History {
+    pub fn new() -> History {
+        History::with_limits(DEFAULT_CAPACITY, DEFAULT_CHECKPOINT_BYTES)
+    }
+    /// 最多记录`capacity`条指令，检查点共占用不超过`checkpoint_bytes`字节
+    pub fn with_limits(capacity: usize, checkpoint_bytes: usize) -> History {
+        History {
+            base: 0,
+            undo: VecDeque::new(),
+            checkpoints: VecDeque::new(),
+            capacity: capacity.max(1),
+            checkpoint_bytes,
+            bytes: 0,
+        }
+    }
+    /// 当前状态之前已执行的指令数
+    pub fn now(&self) -> usize {
+        self.base + self.undo.len()
+    }
+    /// 最早可以回退到的位置
+    pub fn start(&self) -> usize {
+        self.base
+    }
+    /// 丢弃所有记录，此后不能回退到当前状态之前
+    pub fn clear(&mut self) {
+        self.base = self.now();
+        self.undo.clear();
+        self.checkpoints.clear();
+        self.bytes = 0;
+    }
+    /// 第`step`条指令的地址
+    pub fn pc(&self, step: usize) -> Option<UsizeRegType> {
+        let i = step.checked_sub(self.base)?;
+        self.undo.get(i).map(|undo| undo.pc)
+    }
+    /// 执行`vm`的一条指令并记下撤销所需的旧值
+    pub fn step(&mut self, vm: &mut VmTmp) -> Result<Option<ExitReason>, VmErr> {
+        let pc = vm.get_pc();
+        let bp = vm.get_bp();
+        let inst = vm.code().get(pc).copied();
+        let now = self.now();
+        if matches!(inst, Some(Inst::SysCall(_)))
+            && self.checkpoints.back().is_none_or(|c| c.step != now)
+        {
+            let c = Checkpoint::new(now, vm);
+            self.bytes += c.bytes;
+            self.checkpoints.push_back(c);
+        }
+        let u = U_REGS.map(|r| vm.get_u_reg(r));
+        let f = F_REGS.map(|r| vm.get_f_reg(r));
+        let stack = vm.stack();
+        // 从`from`开始到栈顶的栈槽将被弹出
+        let tail = |from: usize| {
+            (from..stack.len())
+                .map(|slot| (slot, stack[slot]))
+                .collect()
+        };
+        let mut undo = Undo {
+            pc,
+            bp,
+            flags: vm.get_flags(),
+            fuel: vm.fuel(),
+            u: Vec::new(),
+            f: Vec::new(),
+            heap_len: vm.heap().len(),
+            heap: None,
+            stack_len: stack.len(),
+            stack: Vec::new(),
+        };
+        match inst {
+            Some(Inst::StoreUH(_, a) | Inst::StoreDH(_, a)) => {
+                let addr = vm.get_u_reg(a);
+                undo.heap = vm.heap().get(addr).map(|obj| (addr, obj.clone()));
+            }
+            Some(Inst::StoreUS(_, a) | Inst::StoreDS(_, a)) => {
+                let slot = bp.wrapping_add(vm.get_u_reg(a));
+                undo.stack = stack.get(slot).map(|&v| (slot, v)).into_iter().collect();
+            }
+            Some(Inst::PopU(_) | Inst::PopD(_)) => undo.stack = tail(stack.len().saturating_sub(1)),
+            // 返回时栈先截断到bp，再弹出返回地址和调用者的bp
+            Some(Inst::Ret) => undo.stack = tail(bp.saturating_sub(1)),
+            _ => {}
+        }
+
+        let r = vm.step();
+        // 越过代码末尾或燃料不够时没有执行指令
+        if let Ok(Some(ExitReason::EndOfCode | ExitReason::OutOfFuel)) = r {
+            return r;
+        }
+        undo.u = U_REGS
+            .into_iter()
+            .zip(u)
+            .filter(|&(r, v)| vm.get_u_reg(r) != v)
+            .collect();
+        undo.f = F_REGS
+            .into_iter()
+            .zip(f)
+            .filter(|&(r, v)| vm.get_f_reg(r).to_bits() != v.to_bits())
+            .collect();
+        self.undo.push_back(undo);
+        self.trim();
+        r
+    }
+    /// 丢弃超过`capacity`的记录和超过`checkpoint_bytes`的检查点
+    fn trim(&mut self) {
+        if self.undo.len() > self.capacity {
+            let n = self.undo.len() - self.capacity;
+            self.undo.drain(..n);
+            self.base += n;
+        }
+        while self.bytes > self.checkpoint_bytes {
+            let Some(c) = self.checkpoints.front() else {
+                break;
+            };
+            // 没有检查点就不能撤销那条系统调用，它和它之前的记录一起丢弃
+            let n = (c.step + 1).saturating_sub(self.base).min(self.undo.len());
+            self.undo.drain(..n);
+            self.base += n;
+            self.pop_checkpoint_front();
+        }
+        while self.checkpoints.front().is_some_and(|c| c.step < self.base) {
+            self.pop_checkpoint_front();
+        }
+    }
+    fn pop_checkpoint_front(&mut self) {
+        if let Some(c) = self.checkpoints.pop_front() {
+            self.bytes -= c.bytes;
+        }
+    }
+    fn pop_checkpoint_back(&mut self) -> Option<Checkpoint> {
+        let c = self.checkpoints.pop_back()?;
+        self.bytes -= c.bytes;
+        Some(c)
+    }
+    /// 撤销最近执行的一条指令，没有记录时返回假
+    pub fn undo(&mut self, vm: &mut VmTmp) -> bool {
+        let Some(undo) = self.undo.pop_back() else {
+            return false;
+        };
+        let step = self.now();
+        // 越过代码末尾等没有执行指令时也可能留下检查点
+        while self.checkpoints.back().is_some_and(|c| c.step > step) {
+            self.pop_checkpoint_back();
+        }
+        match self.checkpoints.back() {
+            Some(c) if c.step == step => self.pop_checkpoint_back().unwrap().restore(vm),
+            _ => undo.apply(vm),
+        }
+        true
+    }
+    /// 回退到第`step`条指令执行之前，早于记录的起点时回退到起点
+    pub fn rewind(&mut self, vm: &mut VmTmp, step: usize) {
+        let step = step.max(self.base);
+        if step >= self.now() {
+            return;
+        }
+        // 从不早于目标的第一个检查点恢复，再逐条撤销剩下的
+        if let Some(i) = self.checkpoints.iter().position(|c| c.step >= step) {
+            let mut later = self.checkpoints.split_off(i);
+            self.bytes -= later.iter().map(|c| c.bytes).sum::<usize>();
+            let c = later.pop_front().unwrap();
+            self.undo.truncate(c.step - self.base);
+            c.restore(vm);
+        }
+        while self.now() > step {
+            self.undo(vm);
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_rewind() {
+    use crate::asm::assemble;
+
+    let code = assemble(
+        "
+        mu u1, 0
+        mu u2, 3
+        mu u8, f
+    loop:
+        pushu u1
+        call u8
+        popu u3
+        storeuh u3, u1
+        addui u1, 1
+        mu u7, loop
+        jne u7, u1, u2
+        halt
+    f:
+        pushu u2
+        mu u4, 1
+        storeus u1, u4
+        ret
+        ",
+    )
+    .unwrap();
+    let mut vm = VmTmp::new();
+    vm.set_code(code);
+    let mut states = Vec::new();
+    let mut history = History::with_limits(1000, DEFAULT_CHECKPOINT_BYTES);
+    loop {
+        states.push(format!("{:?}", (&vm.core, vm.heap(), vm.stack())));
+        if history.step(&mut vm).unwrap().is_some() {
+            break;
+        }
+    }
+    assert_eq!(history.now(), states.len());
+    for step in [states.len() - 2, 17, 16, 9, 3] {
+        history.rewind(&mut vm, step);
+        assert_eq!(history.now(), step);
+        assert_eq!(
+            format!("{:?}", (&vm.core, vm.heap(), vm.stack())),
+            states[step]
+        );
+    }
+    while history.undo(&mut vm) {}
+    assert_eq!(
+        format!("{:?}", (&vm.core, vm.heap(), vm.stack())),
+        states[0]
+    );
+
+    // 系统调用之前才有检查点，超过总大小时最早的系统调用之前不能再回退
+    let code = assemble(
+        "
+        mu u1, 1
+        mu u2, 0
+        storeuh u1, u2
+        mu u3, 1
+        mu u8, 2
+        syscall u8          ; fetch_add
+        syscall u8
+        syscall u8
+        halt
+        ",
+    )
+    .unwrap();
+    let run = |bytes| {
+        let mut vm = VmTmp::new();
+        vm.set_code(code.clone());
+        let mut history = History::with_limits(1000, bytes);
+        while history.step(&mut vm).unwrap().is_none() {}
+        assert!(history.bytes <= bytes);
+        history.rewind(&mut vm, 0);
+        (history.start(), format!("{:?}", vm.heap()))
+    };
+    assert_eq!(run(DEFAULT_CHECKPOINT_BYTES), (0, "[]".to_string()));
+    let one = Checkpoint::new(0, &{
+        let mut vm = VmTmp::new();
+        vm.mem_store(None, Some(vec![HeapObj::R(RegType::Usize(0))]), None);
+        vm
+    })
+    .bytes;
+    // 只能保留两个检查点，第一条系统调用和它之前的记录被丢弃
+    assert_eq!(run(2 * one), (6, "[R(Usize(2))]".to_string()));
+
+    // 回退越过线程的创建、切换和结束时恢复整个线程表
+    let code = assemble(
+        "
+        mu u2, 7
+        mu u3, 5
+        mu u8, 4
+        syscall u8          ; spawn
+        mu u8, 6
+        movu u2, u5
+        syscall u8          ; join
+        pushu u1
+        mu u8, 5
+        syscall u8          ; yield
+        mu u2, 9
+        mu u8, 7
+        syscall u8          ; exit
+        ",
+    )
+    .unwrap();
+    let mut vm = VmTmp::new();
+    vm.set_code(code);
+    let mut states = Vec::new();
+    let mut history = History::new();
+    loop {
+        states.push(format!("{:?}", (&vm.core, vm.stack())));
+        if history.step(&mut vm).unwrap().is_some() {
+            break;
+        }
+    }
+    assert_eq!(vm.get_u_reg(UsizeReg::U1), 9);
+    for step in (0..states.len() - 1).rev() {
+        history.rewind(&mut vm, step);
+        assert_eq!(format!("{:?}", (&vm.core, vm.stack())), states[step]);
+    }
+}
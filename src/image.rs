@@ -0,0 +1,652 @@
+//! 二进制程序镜像
+//!
+//! 所有整数均为小端序，`usize`按u64存储，f64按其位模式存储为u64。
+//! ```text
+//! magic   b"DVMI"
+//! version u16
+//! flags   u16        bit0: 含堆段  bit1: 含栈段  bit2: 含符号表  bit3: 含行号  bit4: 含源文件名
+//! code    u64 数量，随后是每条指令：u8 操作码 + 操作数
+//! heap    u64 数量，随后是每个HeapObj：u8 标签 + 内容
+//! stack   u64 数量，随后是每个RegType：u8 标签 + u64
+//! symbols u64 数量，随后是每个符号：u64 长度 + UTF-8名字 + u64 地址
+//! lines   u64 数量，随后是每条指令所在的源码行号u64
+//! source  u64 长度 + UTF-8源文件名
+//! ```
+use std::collections::BTreeMap;
+
+use demo_isa::reg::{F64Reg, UsizeReg, UsizeRegType};
+use demo_isa::{Inst, RegType};
+
+use crate::asm::disasm::mnemonic;
+use crate::asm::{Program, F_REGS, U_REGS};
+use crate::memory::heap::HeapObj;
+use crate::memory::{Heap, Stack};
+
+pub const MAGIC: &[u8; 4] = b"DVMI";
+pub const VERSION: u16 = 1;
+
+const FLAG_HEAP: u16 = 1;
+const FLAG_STACK: u16 = 1 << 1;
+const FLAG_SYMBOLS: u16 = 1 << 2;
+const FLAG_LINES: u16 = 1 << 3;
+const FLAG_SOURCE: u16 = 1 << 4;
+
+#[derive(Debug, PartialEq)]
+pub enum ImageErr {
+    BadMagic,
+    UnsupportedVersion(u16),
+    UnknownFlags(u16),
+    Truncated,
+    InvalidOpcode(u8),
+    InvalidRegister(u8),
+    InvalidTag(u8),
+    ValueOutOfRange(u64),
+    TrailingBytes(usize),
+    InvalidUtf8,
+}
+
+/// 程序镜像，堆段、栈段、符号表和行号可选
+#[derive(Debug, Clone, Default)]
+pub struct Image {
+    pub code: Vec<Inst>,
+    pub heap: Option<Heap>,
+    pub stack: Option<Stack>,
+    pub symbols: Option<BTreeMap<String, UsizeRegType>>,
+    /// 每条指令所在的源码行号，见`Program::lines`
+    pub lines: Option<Vec<usize>>,
+    /// 汇编源码的文件名，`--lcov`用它作为`SF:`
+    pub source: Option<String>,
+}
+
+impl From<Program> for Image {
+    fn from(program: Program) -> Image {
+        Image {
+            symbols: Some(program.labels),
+            lines: Some(program.lines),
+            ..Image::new(program.code)
+        }
+    }
+}
+
+impl Image {
+    pub fn new(code: Vec<Inst>) -> Image {
+        Image {
+            code,
+            heap: None,
+            stack: None,
+            symbols: None,
+            lines: None,
+            source: None,
+        }
+    }
+    pub fn encode(&self) -> Vec<u8> {
+        let mut w = Writer::new();
+        w.bytes(MAGIC);
+        w.u16(VERSION);
+        let mut flags = 0;
+        if self.heap.is_some() {
+            flags |= FLAG_HEAP;
+        }
+        if self.stack.is_some() {
+            flags |= FLAG_STACK;
+        }
+        if self.symbols.is_some() {
+            flags |= FLAG_SYMBOLS;
+        }
+        if self.lines.is_some() {
+            flags |= FLAG_LINES;
+        }
+        if self.source.is_some() {
+            flags |= FLAG_SOURCE;
+        }
+        w.u16(flags);
+        w.code(&self.code);
+        if let Some(heap) = &self.heap {
+            w.heap(heap);
+        }
+        if let Some(stack) = &self.stack {
+            w.stack(stack);
+        }
+        if let Some(symbols) = &self.symbols {
+            w.symbols(symbols);
+        }
+        if let Some(lines) = &self.lines {
+            w.usize(lines.len());
+            for &line in lines {
+                w.usize(line);
+            }
+        }
+        if let Some(source) = &self.source {
+            w.string(source);
+        }
+        w.finish()
+    }
+    pub fn decode(bytes: &[u8]) -> Result<Image, ImageErr> {
+        let mut r = Reader::new(bytes);
+        if r.take(MAGIC.len())? != MAGIC {
+            return Err(ImageErr::BadMagic);
+        }
+        let version = r.u16()?;
+        if version != VERSION {
+            return Err(ImageErr::UnsupportedVersion(version));
+        }
+        let flags = r.u16()?;
+        if flags & !(FLAG_HEAP | FLAG_STACK | FLAG_SYMBOLS | FLAG_LINES | FLAG_SOURCE) != 0 {
+            return Err(ImageErr::UnknownFlags(flags));
+        }
+        let code = r.code()?;
+        let heap = if flags & FLAG_HEAP != 0 {
+            Some(r.heap()?)
+        } else {
+            None
+        };
+        let stack = if flags & FLAG_STACK != 0 {
+            Some(r.stack()?)
+        } else {
+            None
+        };
+        let symbols = if flags & FLAG_SYMBOLS != 0 {
+            Some(r.symbols()?)
+        } else {
+            None
+        };
+        let lines = if flags & FLAG_LINES != 0 {
+            let n = r.len(8)?;
+            Some((0..n).map(|_| r.usize()).collect::<Result<_, _>>()?)
+        } else {
+            None
+        };
+        let source = if flags & FLAG_SOURCE != 0 {
+            Some(r.string()?)
+        } else {
+            None
+        };
+        r.finish()?;
+        Ok(Image {
+            code,
+            heap,
+            stack,
+            symbols,
+            lines,
+            source,
+        })
+    }
+}
+
+pub(crate) struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    pub(crate) fn new() -> Writer {
+        Writer { buf: Vec::new() }
+    }
+    pub(crate) fn finish(self) -> Vec<u8> {
+        self.buf
+    }
+    pub(crate) fn bytes(&mut self, b: &[u8]) {
+        self.buf.extend_from_slice(b);
+    }
+    pub(crate) fn u8(&mut self, v: u8) {
+        self.buf.push(v);
+    }
+    pub(crate) fn u16(&mut self, v: u16) {
+        self.bytes(&v.to_le_bytes());
+    }
+    pub(crate) fn u64(&mut self, v: u64) {
+        self.bytes(&v.to_le_bytes());
+    }
+    pub(crate) fn usize(&mut self, v: usize) {
+        self.u64(v as u64);
+    }
+    pub(crate) fn f64(&mut self, v: f64) {
+        self.u64(v.to_bits());
+    }
+    pub(crate) fn u_reg(&mut self, r: UsizeReg) {
+        self.u8(r as u8);
+    }
+    pub(crate) fn f_reg(&mut self, r: F64Reg) {
+        self.u8(r as u8);
+    }
+    pub(crate) fn inst(&mut self, inst: &Inst) {
+        self.u8(opcode(inst));
+        match *inst {
+            Inst::Nop | Inst::Ret | Inst::Halt => {}
+            Inst::MU(r, v) | Inst::AddUI(r, v) | Inst::SubUI(r, v) => {
+                self.u_reg(r);
+                self.usize(v);
+            }
+            Inst::MD(r, v) | Inst::AddDI(r, v) | Inst::SubDI(r, v) => {
+                self.f_reg(r);
+                self.f64(v);
+            }
+            Inst::MovU(a, b)
+            | Inst::Not(a, b)
+            | Inst::NegU(a, b)
+            | Inst::Shl(a, b)
+            | Inst::Shr(a, b)
+            | Inst::LoadUH(a, b)
+            | Inst::LoadUS(a, b)
+            | Inst::StoreUH(a, b)
+            | Inst::StoreUS(a, b)
+            | Inst::Jz(a, b)
+            | Inst::Jnz(a, b)
+            | Inst::InU(a, b)
+            | Inst::OutU(a, b) => {
+                self.u_reg(a);
+                self.u_reg(b);
+            }
+            Inst::MovD(a, b) | Inst::NegD(a, b) => {
+                self.f_reg(a);
+                self.f_reg(b);
+            }
+            Inst::LoadDH(a, b)
+            | Inst::LoadDS(a, b)
+            | Inst::StoreDH(a, b)
+            | Inst::StoreDS(a, b)
+            | Inst::InD(a, b)
+            | Inst::OutD(a, b) => {
+                self.f_reg(a);
+                self.u_reg(b);
+            }
+            Inst::Mod(a, b, c)
+            | Inst::AddU(a, b, c)
+            | Inst::SubU(a, b, c)
+            | Inst::MulU(a, b, c)
+            | Inst::DivU(a, b, c)
+            | Inst::And(a, b, c)
+            | Inst::Or(a, b, c)
+            | Inst::Xor(a, b, c)
+            | Inst::Je(a, b, c)
+            | Inst::Jne(a, b, c) => {
+                self.u_reg(a);
+                self.u_reg(b);
+                self.u_reg(c);
+            }
+            Inst::AddD(a, b, c)
+            | Inst::SubD(a, b, c)
+            | Inst::MulD(a, b, c)
+            | Inst::DivD(a, b, c) => {
+                self.f_reg(a);
+                self.f_reg(b);
+                self.f_reg(c);
+            }
+            Inst::Jo(a)
+            | Inst::Jno(a)
+            | Inst::Jmp(a)
+            | Inst::PushU(a)
+            | Inst::PopU(a)
+            | Inst::Call(a)
+            | Inst::SysCall(a) => self.u_reg(a),
+            Inst::PushD(a) | Inst::PopD(a) => self.f_reg(a),
+        }
+    }
+    pub(crate) fn code(&mut self, code: &[Inst]) {
+        self.usize(code.len());
+        for inst in code {
+            self.inst(inst);
+        }
+    }
+    pub(crate) fn reg_type(&mut self, v: &RegType) {
+        match *v {
+            RegType::Usize(u) => {
+                self.u8(0);
+                self.usize(u);
+            }
+            RegType::F64(f) => {
+                self.u8(1);
+                self.f64(f);
+            }
+        }
+    }
+    pub(crate) fn heap_obj(&mut self, obj: &HeapObj) {
+        match obj {
+            HeapObj::R(r) => self.reg_type(r),
+            HeapObj::UArray(a) => {
+                self.u8(2);
+                self.usize(a.len());
+                for &u in a {
+                    self.usize(u);
+                }
+            }
+            HeapObj::FArray(a) => {
+                self.u8(3);
+                self.usize(a.len());
+                for &f in a {
+                    self.f64(f);
+                }
+            }
+        }
+    }
+    pub(crate) fn heap(&mut self, heap: &Heap) {
+        self.usize(heap.len());
+        for obj in heap {
+            self.heap_obj(obj);
+        }
+    }
+    pub(crate) fn stack(&mut self, stack: &Stack) {
+        self.usize(stack.len());
+        for v in stack {
+            self.reg_type(v);
+        }
+    }
+    pub(crate) fn symbols(&mut self, symbols: &BTreeMap<String, UsizeRegType>) {
+        self.usize(symbols.len());
+        for (name, &addr) in symbols {
+            self.string(name);
+            self.usize(addr);
+        }
+    }
+    pub(crate) fn string(&mut self, s: &str) {
+        self.usize(s.len());
+        self.bytes(s.as_bytes());
+    }
+}
+
+pub(crate) struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(buf: &'a [u8]) -> Reader<'a> {
+        Reader { buf, pos: 0 }
+    }
+    /// 确认已读到末尾
+    pub(crate) fn finish(&self) -> Result<(), ImageErr> {
+        match self.buf.len() - self.pos {
+            0 => Ok(()),
+            n => Err(ImageErr::TrailingBytes(n)),
+        }
+    }
+    pub(crate) fn take(&mut self, n: usize) -> Result<&'a [u8], ImageErr> {
+        if self.buf.len() - self.pos < n {
+            return Err(ImageErr::Truncated);
+        }
+        let b = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(b)
+    }
+    pub(crate) fn u8(&mut self) -> Result<u8, ImageErr> {
+        Ok(self.take(1)?[0])
+    }
+    pub(crate) fn u16(&mut self) -> Result<u16, ImageErr> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+    pub(crate) fn u64(&mut self) -> Result<u64, ImageErr> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+    pub(crate) fn usize(&mut self) -> Result<usize, ImageErr> {
+        let v = self.u64()?;
+        usize::try_from(v).map_err(|_| ImageErr::ValueOutOfRange(v))
+    }
+    pub(crate) fn f64(&mut self) -> Result<f64, ImageErr> {
+        Ok(f64::from_bits(self.u64()?))
+    }
+    /// 读取元素数量，每个元素至少占`min_size`字节，防止按伪造的数量分配内存
+    pub(crate) fn len(&mut self, min_size: usize) -> Result<usize, ImageErr> {
+        let n = self.usize()?;
+        if n.checked_mul(min_size)
+            .is_none_or(|size| size > self.buf.len() - self.pos)
+        {
+            return Err(ImageErr::Truncated);
+        }
+        Ok(n)
+    }
+    pub(crate) fn u_reg(&mut self) -> Result<UsizeReg, ImageErr> {
+        let r = self.u8()?;
+        U_REGS
+            .get(r as usize)
+            .copied()
+            .ok_or(ImageErr::InvalidRegister(r))
+    }
+    pub(crate) fn f_reg(&mut self) -> Result<F64Reg, ImageErr> {
+        let r = self.u8()?;
+        F_REGS
+            .get(r as usize)
+            .copied()
+            .ok_or(ImageErr::InvalidRegister(r))
+    }
+    pub(crate) fn inst(&mut self) -> Result<Inst, ImageErr> {
+        let op = self.u8()?;
+        let inst = match op {
+            0 => Inst::Nop,
+            1 => Inst::MU(self.u_reg()?, self.usize()?),
+            2 => Inst::MD(self.f_reg()?, self.f64()?),
+            3 => Inst::MovU(self.u_reg()?, self.u_reg()?),
+            4 => Inst::MovD(self.f_reg()?, self.f_reg()?),
+            5 => Inst::Mod(self.u_reg()?, self.u_reg()?, self.u_reg()?),
+            6 => Inst::AddU(self.u_reg()?, self.u_reg()?, self.u_reg()?),
+            7 => Inst::AddUI(self.u_reg()?, self.usize()?),
+            8 => Inst::AddD(self.f_reg()?, self.f_reg()?, self.f_reg()?),
+            9 => Inst::AddDI(self.f_reg()?, self.f64()?),
+            10 => Inst::SubU(self.u_reg()?, self.u_reg()?, self.u_reg()?),
+            11 => Inst::SubUI(self.u_reg()?, self.usize()?),
+            12 => Inst::SubD(self.f_reg()?, self.f_reg()?, self.f_reg()?),
+            13 => Inst::SubDI(self.f_reg()?, self.f64()?),
+            14 => Inst::MulU(self.u_reg()?, self.u_reg()?, self.u_reg()?),
+            15 => Inst::MulD(self.f_reg()?, self.f_reg()?, self.f_reg()?),
+            16 => Inst::DivU(self.u_reg()?, self.u_reg()?, self.u_reg()?),
+            17 => Inst::DivD(self.f_reg()?, self.f_reg()?, self.f_reg()?),
+            18 => Inst::And(self.u_reg()?, self.u_reg()?, self.u_reg()?),
+            19 => Inst::Or(self.u_reg()?, self.u_reg()?, self.u_reg()?),
+            20 => Inst::Xor(self.u_reg()?, self.u_reg()?, self.u_reg()?),
+            21 => Inst::Not(self.u_reg()?, self.u_reg()?),
+            22 => Inst::NegU(self.u_reg()?, self.u_reg()?),
+            23 => Inst::NegD(self.f_reg()?, self.f_reg()?),
+            24 => Inst::Shl(self.u_reg()?, self.u_reg()?),
+            25 => Inst::Shr(self.u_reg()?, self.u_reg()?),
+            26 => Inst::LoadUH(self.u_reg()?, self.u_reg()?),
+            27 => Inst::LoadDH(self.f_reg()?, self.u_reg()?),
+            28 => Inst::LoadUS(self.u_reg()?, self.u_reg()?),
+            29 => Inst::LoadDS(self.f_reg()?, self.u_reg()?),
+            30 => Inst::StoreUH(self.u_reg()?, self.u_reg()?),
+            31 => Inst::StoreDH(self.f_reg()?, self.u_reg()?),
+            32 => Inst::StoreUS(self.u_reg()?, self.u_reg()?),
+            33 => Inst::StoreDS(self.f_reg()?, self.u_reg()?),
+            34 => Inst::Jo(self.u_reg()?),
+            35 => Inst::Jno(self.u_reg()?),
+            36 => Inst::Je(self.u_reg()?, self.u_reg()?, self.u_reg()?),
+            37 => Inst::Jne(self.u_reg()?, self.u_reg()?, self.u_reg()?),
+            38 => Inst::Jz(self.u_reg()?, self.u_reg()?),
+            39 => Inst::Jnz(self.u_reg()?, self.u_reg()?),
+            40 => Inst::Jmp(self.u_reg()?),
+            41 => Inst::PushU(self.u_reg()?),
+            42 => Inst::PushD(self.f_reg()?),
+            43 => Inst::PopU(self.u_reg()?),
+            44 => Inst::PopD(self.f_reg()?),
+            45 => Inst::Call(self.u_reg()?),
+            46 => Inst::SysCall(self.u_reg()?),
+            47 => Inst::InU(self.u_reg()?, self.u_reg()?),
+            48 => Inst::InD(self.f_reg()?, self.u_reg()?),
+            49 => Inst::OutU(self.u_reg()?, self.u_reg()?),
+            50 => Inst::OutD(self.f_reg()?, self.u_reg()?),
+            51 => Inst::Ret,
+            52 => Inst::Halt,
+            _ => return Err(ImageErr::InvalidOpcode(op)),
+        };
+        Ok(inst)
+    }
+    pub(crate) fn code(&mut self) -> Result<Vec<Inst>, ImageErr> {
+        let n = self.len(1)?;
+        (0..n).map(|_| self.inst()).collect()
+    }
+    pub(crate) fn reg_type(&mut self) -> Result<RegType, ImageErr> {
+        match self.u8()? {
+            0 => Ok(RegType::Usize(self.usize()?)),
+            1 => Ok(RegType::F64(self.f64()?)),
+            t => Err(ImageErr::InvalidTag(t)),
+        }
+    }
+    pub(crate) fn heap_obj(&mut self) -> Result<HeapObj, ImageErr> {
+        match self.u8()? {
+            0 => Ok(HeapObj::R(RegType::Usize(self.usize()?))),
+            1 => Ok(HeapObj::R(RegType::F64(self.f64()?))),
+            2 => {
+                let n = self.len(8)?;
+                Ok(HeapObj::UArray(
+                    (0..n).map(|_| self.usize()).collect::<Result<_, _>>()?,
+                ))
+            }
+            3 => {
+                let n = self.len(8)?;
+                Ok(HeapObj::FArray(
+                    (0..n).map(|_| self.f64()).collect::<Result<_, _>>()?,
+                ))
+            }
+            t => Err(ImageErr::InvalidTag(t)),
+        }
+    }
+    pub(crate) fn heap(&mut self) -> Result<Heap, ImageErr> {
+        let n = self.len(9)?;
+        (0..n).map(|_| self.heap_obj()).collect()
+    }
+    pub(crate) fn stack(&mut self) -> Result<Stack, ImageErr> {
+        let n = self.len(9)?;
+        (0..n).map(|_| self.reg_type()).collect()
+    }
+    pub(crate) fn symbols(&mut self) -> Result<BTreeMap<String, UsizeRegType>, ImageErr> {
+        let n = self.len(16)?;
+        (0..n)
+            .map(|_| Ok((self.string()?, self.usize()?)))
+            .collect()
+    }
+    pub(crate) fn string(&mut self) -> Result<String, ImageErr> {
+        let len = self.len(1)?;
+        let s = std::str::from_utf8(self.take(len)?).map_err(|_| ImageErr::InvalidUtf8)?;
+        Ok(s.to_string())
+    }
+}
+
+/// 操作码的个数
+pub(crate) const OPCODES: usize = 53;
+
+/// 按操作码排列的助记符
+pub(crate) fn opcode_names() -> [&'static str; OPCODES] {
+    std::array::from_fn(|op| {
+        // 操作数全为0的指令总能解码
+        let mut bytes = [0; 32];
+        bytes[0] = op as u8;
+        mnemonic(&Reader::new(&bytes).inst().unwrap())
+    })
+}
+
+pub(crate) fn opcode(inst: &Inst) -> u8 {
+    match inst {
+        Inst::Nop => 0,
+        Inst::MU(..) => 1,
+        Inst::MD(..) => 2,
+        Inst::MovU(..) => 3,
+        Inst::MovD(..) => 4,
+        Inst::Mod(..) => 5,
+        Inst::AddU(..) => 6,
+        Inst::AddUI(..) => 7,
+        Inst::AddD(..) => 8,
+        Inst::AddDI(..) => 9,
+        Inst::SubU(..) => 10,
+        Inst::SubUI(..) => 11,
+        Inst::SubD(..) => 12,
+        Inst::SubDI(..) => 13,
+        Inst::MulU(..) => 14,
+        Inst::MulD(..) => 15,
+        Inst::DivU(..) => 16,
+        Inst::DivD(..) => 17,
+        Inst::And(..) => 18,
+        Inst::Or(..) => 19,
+        Inst::Xor(..) => 20,
+        Inst::Not(..) => 21,
+        Inst::NegU(..) => 22,
+        Inst::NegD(..) => 23,
+        Inst::Shl(..) => 24,
+        Inst::Shr(..) => 25,
+        Inst::LoadUH(..) => 26,
+        Inst::LoadDH(..) => 27,
+        Inst::LoadUS(..) => 28,
+        Inst::LoadDS(..) => 29,
+        Inst::StoreUH(..) => 30,
+        Inst::StoreDH(..) => 31,
+        Inst::StoreUS(..) => 32,
+        Inst::StoreDS(..) => 33,
+        Inst::Jo(..) => 34,
+        Inst::Jno(..) => 35,
+        Inst::Je(..) => 36,
+        Inst::Jne(..) => 37,
+        Inst::Jz(..) => 38,
+        Inst::Jnz(..) => 39,
+        Inst::Jmp(..) => 40,
+        Inst::PushU(..) => 41,
+        Inst::PushD(..) => 42,
+        Inst::PopU(..) => 43,
+        Inst::PopD(..) => 44,
+        Inst::Call(..) => 45,
+        Inst::SysCall(..) => 46,
+        Inst::InU(..) => 47,
+        Inst::InD(..) => 48,
+        Inst::OutU(..) => 49,
+        Inst::OutD(..) => 50,
+        Inst::Ret => 51,
+        Inst::Halt => 52,
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_image_round_trip() {
+    use crate::test::fibonacci_code;
+    use crate::VmTmp;
+
+    let image = Image {
+        code: fibonacci_code(5),
+        heap: Some(vec![
+            HeapObj::R(RegType::Usize(7)),
+            HeapObj::R(RegType::F64(-1.5)),
+            HeapObj::UArray(vec![1, 2, usize::MAX]),
+            HeapObj::FArray(vec![0.25, f64::INFINITY]),
+            HeapObj::UArray(vec![]),
+        ]),
+        stack: Some(vec![RegType::Usize(3), RegType::F64(2.0)]),
+        symbols: Some(BTreeMap::from([
+            ("fib".to_string(), 7),
+            ("main".to_string(), 0),
+        ])),
+        lines: Some((1..=fibonacci_code(5).len()).collect()),
+        source: Some("fib.dasm".to_string()),
+    };
+    let bytes = image.encode();
+    assert_eq!(&bytes[..4], MAGIC);
+    let decoded = Image::decode(&bytes).unwrap();
+    assert_eq!(decoded.code, image.code);
+    assert_eq!(format!("{:?}", decoded.heap), format!("{:?}", image.heap));
+    assert_eq!(format!("{:?}", decoded.stack), format!("{:?}", image.stack));
+    assert_eq!(decoded.symbols, image.symbols);
+    assert_eq!(decoded.lines, image.lines);
+    assert_eq!(decoded.source, image.source);
+
+    // 任何截断都应返回错误
+    for len in 0..bytes.len() {
+        assert!(Image::decode(&bytes[..len]).is_err());
+    }
+    let mut bad = bytes.clone();
+    bad.push(0);
+    assert_eq!(Image::decode(&bad).unwrap_err(), ImageErr::TrailingBytes(1));
+    let mut bad = bytes.clone();
+    bad[0] = b'X';
+    assert_eq!(Image::decode(&bad).unwrap_err(), ImageErr::BadMagic);
+    let mut bad = bytes.clone();
+    bad[4] = 9;
+    assert_eq!(
+        Image::decode(&bad).unwrap_err(),
+        ImageErr::UnsupportedVersion(9)
+    );
+    let mut bad = bytes.clone();
+    bad[16] = 0xff; // 第一条指令的操作码
+    assert_eq!(
+        Image::decode(&bad).unwrap_err(),
+        ImageErr::InvalidOpcode(0xff)
+    );
+
+    let mut vm = VmTmp::new();
+    vm.load_image(Image::new(fibonacci_code(6))).unwrap();
+    let _ = vm.start();
+    assert_eq!(vm.get_u_reg(UsizeReg::U1), 8);
+}
@@ -1,26 +1,58 @@
+use crate::asm::{F_REGS, U_REGS};
 use crate::cpu::CpuCore;
-use crate::memory::Memory;
-use cpu::core::Regs;
-use cpu::CpuErr;
+use crate::memory::{Limits, Memory, MemoryErr};
+use std::collections::BTreeMap;
+use std::fmt;
+use cpu::fuel::CostTable;
+use cpu::observer::{ExecObserver, NoopObserver};
+use cpu::replay::Recording;
+use cpu::{CpuErr, ExitReason, ExitStatus, Fault, Frame};
+use image::Image;
 use demo_isa::err::ISAErr;
+use demo_isa::reg::{F64Reg, F64RegType, Flags, UsizeReg, UsizeRegType};
+use enumflags2::BitFlags;
 use demo_isa::{Inst, RegType, VmRunner};
 use memory::heap::HeapObj;
 use memory::{Heap, Stack};
+use port::{PortBus, PortDevice};
+use snapshot::Snapshot;
+use sys_call::{AsyncSysCall, SysCallFuture};
 
 use mimalloc::MiMalloc;
 
 #[global_allocator]
 static GLOBAL: MiMalloc = MiMalloc;
 // #[cfg(test)]
+pub mod asm;
+pub mod coverage;
 pub mod cpu;
+pub mod debugger;
+pub mod image;
+pub mod machine;
 pub mod memory;
+pub mod port;
+pub mod profiler;
+pub mod snapshot;
 pub mod sys_call;
 pub mod test;
+pub mod trace;
 
 #[derive(Debug)]
 pub enum VmErr {
  CpuErr(CpuErr),   
  ISAErr(ISAErr),
+ /// 执行指令时出错，带有出错现场
+ Fault(Box<Fault>),
+ /// 没有停机也没有越过代码末尾就停止，例如燃料不够
+ Unfinished(ExitReason),
+}
+impl fmt::Display for VmErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VmErr::Fault(fault) => write!(f, "{}", fault),
+            e => write!(f, "{:?}", e),
+        }
+    }
 }
 impl From<ISAErr> for VmErr {
     fn from(err: ISAErr) -> VmErr {
@@ -33,90 +65,80 @@ impl From<CpuErr> for VmErr {
     }
     
 }
+impl From<MemoryErr> for VmErr {
+    fn from(err: MemoryErr) -> VmErr {
+        VmErr::CpuErr(err.into())
+    }
+}
+impl From<Fault> for VmErr {
+    fn from(fault: Fault) -> VmErr {
+        VmErr::Fault(Box::new(fault))
+    }
+}
 #[derive(Debug)]
 pub struct VmTmp {
     core: CpuCore,
     mem: Memory,
+    bus: PortBus,
+    step_limit: Option<usize>,
+    symbols: BTreeMap<String, UsizeRegType>,
 }
 
 #[derive(Debug)]
 pub struct Vm {
-    regs:Regs,
-    code_segment: Vec<Inst>,
+    core: CpuCore,
+    mem: Memory,
+    bus: PortBus,
+    /// 执行时换入内存的堆段
     pub heap_segment: Heap,
-    stack_segment: Vec<RegType>,
 }
-impl VmRunner for Vm{
+impl VmRunner for Vm {
     type VmErr = VmErr;
-    fn run(&mut self,code: &[Inst])->Result<(), VmErr> {
-        for inst in code {
-            Vm::run_inst(self,inst)?;
+    /// 从地址0执行`code`，停机或越过代码末尾以外的停止都是错误
+    fn run(&mut self, code: &[Inst]) -> Result<(), VmErr> {
+        self.mem.store(Some(code.to_vec()), None, None);
+        self.core.set_pc(0);
+        std::mem::swap(self.mem.heap_segment_mut(), &mut self.heap_segment);
+        let r = self.core.start(&mut self.mem, &mut self.bus, None);
+        std::mem::swap(self.mem.heap_segment_mut(), &mut self.heap_segment);
+        match r?.reason {
+            ExitReason::Halt | ExitReason::EndOfCode => Ok(()),
+            reason => Err(VmErr::Unfinished(reason)),
         }
-        Ok(())
     }
 }
-impl Vm{
-    fn run_inst(vm:&mut Vm,inst:&Inst) -> Result<(), ISAErr> {
-        match *inst {//TODO:implement all instructions
-            Inst::Nop => {},
-            Inst::MU(dur, uv) => {
-                vm.regs.set_u_reg(dur, uv);
-            }
-            Inst::MD(_, _) => todo!(),
-            Inst::MovU(_, _) => todo!(),
-            Inst::MovD(_, _) => todo!(),
-            Inst::Mod(_, _, _) => todo!(),
-            Inst::AddU(_, _, _) => todo!(),
-            Inst::AddUI(_, _) => todo!(),
-            Inst::AddD(_, _, _) => todo!(),
-            Inst::AddDI(_, _) => todo!(),
-            Inst::SubU(_, _, _) => todo!(),
-            Inst::SubUI(_, _) => todo!(),
-            Inst::SubD(_, _, _) => todo!(),
-            Inst::SubDI(_, _) => todo!(),
-            Inst::MulU(_, _, _) => todo!(),
-            Inst::MulD(_, _, _) => todo!(),
-            Inst::DivU(_, _, _) => todo!(),
-            Inst::DivD(_, _, _) => todo!(),
-            Inst::And(_, _, _) => todo!(),
-            Inst::Or(_, _, _) => todo!(),
-            Inst::Xor(_, _, _) => todo!(),
-            Inst::Not(_, _) => todo!(),
-            Inst::NegU(_, _) => todo!(),
-            Inst::NegD(_, _) => todo!(),
-            Inst::Shl(_, _) => todo!(),
-            Inst::Shr(_, _) => todo!(),
-            Inst::LoadUH(_, _) => todo!(),
-            Inst::LoadDH(_, _) => todo!(),
-            Inst::LoadUS(_, _) => todo!(),
-            Inst::LoadDS(_, _) => todo!(),
-            Inst::StoreUH(_, _) => todo!(),
-            Inst::StoreDH(_, _) => todo!(),
-            Inst::StoreUS(_, _) => todo!(),
-            Inst::StoreDS(_, _) => todo!(),
-            Inst::Jo(_) => todo!(),
-            Inst::Jno(_) => todo!(),
-            Inst::Je(_, _, _) => todo!(),
-            Inst::Jne(_, _, _) => todo!(),
-            Inst::Jz(_, _) => todo!(),
-            Inst::Jnz(_, _) => todo!(),
-            Inst::Jmp(_) => todo!(),
-            Inst::PushU(_) => todo!(),
-            Inst::PushD(_) => todo!(),
-            Inst::PopU(_) => todo!(),
-            Inst::PopD(_) => todo!(),
-            Inst::Call(_) => todo!(),
-            Inst::SysCall(_) => todo!(),
-            Inst::InU(_, _) => todo!(),
-            Inst::InD(_, _) => todo!(),
-            Inst::OutU(_, _) => todo!(),
-            Inst::OutD(_, _) => todo!(),
-            Inst::Ret => todo!(),
-            Inst::Halt => todo!(),
+
+impl Default for Vm {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Vm {
+    pub fn new() -> Vm {
+        Vm {
+            core: CpuCore::new(),
+            mem: Memory::new(),
+            bus: PortBus::new(),
+            heap_segment: Heap::new(),
         }
-        Ok(())
     }
-    
+    /// 设置剩余的燃料，`None`表示不计量，见`cpu::fuel`
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.core.set_fuel(fuel);
+    }
+    pub fn get_pc(&self) -> UsizeRegType {
+        self.core.get_pc()
+    }
+    pub fn get_u_reg(&self, reg: UsizeReg) -> UsizeRegType {
+        self.core.get_u_reg(reg)
+    }
+    pub fn get_f_reg(&self, reg: F64Reg) -> F64RegType {
+        self.core.get_f_reg(reg)
+    }
+    pub fn bus_mut(&mut self) -> &mut PortBus {
+        &mut self.bus
+    }
 }
 
 impl Default for VmTmp {
@@ -130,22 +152,227 @@ impl VmTmp {
         VmTmp {
             core: CpuCore::new(),
             mem: Memory::new(),
+            bus: PortBus::new(),
+            step_limit: None,
+            symbols: BTreeMap::new(),
+        }
+    }
+    /// 执行直到停机、越过代码末尾或达到指令数上限，只有真正的错误才返回`Err`
+    ///
+    /// 出错时返回`VmErr::Fault`，有符号表时调用栈带有符号
+    pub fn start(&mut self) -> Result<ExitStatus, VmErr> {
+        self.run(self.step_limit, &mut NoopObserver)
+    }
+    /// 同`start`，执行时通知观察者
+    pub fn start_with<O: ExecObserver>(&mut self, obs: &mut O) -> Result<ExitStatus, VmErr> {
+        self.run(self.step_limit, obs)
+    }
+    /// 同`start`，遇到异步系统调用时等待宿主完成再继续执行，见`cpu::async_call`
+    pub async fn run_async(&mut self) -> Result<ExitStatus, VmErr> {
+        let r = self
+            .core
+            .start_async(&mut self.mem, &mut self.bus, self.step_limit)
+            .await;
+        r.map_err(|fault| self.fault_err(fault))
+    }
+    /// 把`num`登记为异步系统调用，返回该调用号上原有的异步系统调用
+    pub fn register_async_syscall<F>(&mut self, num: usize, call: F) -> Option<AsyncSysCall>
+    where
+        F: for<'a> Fn(&'a mut CpuCore, &'a mut Memory) -> SysCallFuture<'a> + Send + Sync + 'static,
+    {
+        self.core.register_async_syscall(num, call)
+    }
+    /// 取消登记，`num`回到`SYS_CALL_TABLE`中的系统调用
+    pub fn unregister_async_syscall(&mut self, num: usize) -> Option<AsyncSysCall> {
+        self.core.unregister_async_syscall(num)
+    }
+    /// 设置`start`的指令数上限，`None`表示不限制
+    pub fn set_step_limit(&mut self, limit: Option<usize>) {
+        self.step_limit = limit;
+    }
+    /// 设置剩余的燃料，`None`表示不计量，见`cpu::fuel`
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.core.set_fuel(fuel);
+    }
+    pub fn fuel(&self) -> Option<u64> {
+        self.core.fuel()
+    }
+    /// 补充燃料，之后可以从`ExitReason::OutOfFuel`停止处继续执行
+    pub fn add_fuel(&mut self, n: u64) {
+        self.core.add_fuel(n);
+    }
+    pub fn set_cost_table(&mut self, costs: CostTable) {
+        self.core.set_cost_table(costs);
+    }
+    /// 设置绿色线程的时间片，`None`表示只在线程让出时切换，见`cpu::green`
+    pub fn set_quantum(&mut self, quantum: Option<usize>) {
+        self.core.set_quantum(quantum);
+    }
+    /// 开始记录系统调用的结果和从端口读到的值，见`cpu::replay`
+    pub fn record(&mut self) {
+        self.core.record();
+    }
+    /// 此后按`rec`重放，与记录不符时在分歧的指令处出错
+    pub fn replay(&mut self, rec: Recording) {
+        self.core.replay(rec);
+    }
+    /// 回到直接执行，返回记下的内容，重放时返回还没有重放的部分
+    pub fn take_recording(&mut self) -> Option<Recording> {
+        self.core.take_recording()
+    }
+    /// 执行pc处的一条指令，需要停止时返回停止的原因，正常执行后返回`None`
+    pub fn step(&mut self) -> Result<Option<ExitReason>, VmErr> {
+        self.core
+            .step(&mut self.mem, &mut self.bus)
+            .map_err(|fault| self.fault_err(fault))
+    }
+    /// 最多执行`n`条指令，可以从停止处继续执行
+    pub fn run_for(&mut self, n: usize) -> Result<ExitStatus, VmErr> {
+        self.run(Some(n), &mut NoopObserver)
+    }
+    fn run<O: ExecObserver>(
+        &mut self,
+        limit: Option<usize>,
+        obs: &mut O,
+    ) -> Result<ExitStatus, VmErr> {
+        self.core
+            .start_with(&mut self.mem, &mut self.bus, limit, obs)
+            .map_err(|fault| self.fault_err(fault))
+    }
+    /// 有符号表时用它解析出错的调用栈
+    fn fault_err(&self, mut fault: Fault) -> VmErr {
+        if !self.symbols.is_empty() {
+            fault.symbolize(&self.symbols);
         }
+        fault.into()
+    }
+    /// 设置用于解析出错地址的符号表
+    pub fn set_symbols(&mut self, symbols: BTreeMap<String, UsizeRegType>) {
+        self.symbols = symbols;
+    }
+    pub fn symbols(&self) -> &BTreeMap<String, UsizeRegType> {
+        &self.symbols
+    }
+    pub fn get_bp(&self) -> UsizeRegType {
+        self.core.get_bp()
+    }
+    pub fn get_flags(&self) -> BitFlags<Flags> {
+        self.core.flags
+    }
+    pub fn set_u_reg(&mut self, reg: UsizeReg, val: UsizeRegType) {
+        self.core.set_u_reg(reg, val);
+    }
+    pub fn set_f_reg(&mut self, reg: F64Reg, val: F64RegType) {
+        self.core.set_f_reg(reg, val);
+    }
+    pub fn set_pc(&mut self, pc: UsizeRegType) {
+        self.core.set_pc(pc);
     }
-    pub fn start(&mut self) -> Result<(), VmErr> {
-        Ok(self.core.start(&mut self.mem)?)
+    pub fn set_bp(&mut self, bp: UsizeRegType) {
+        self.core.set_bp(bp);
+    }
+    pub fn set_flags(&mut self, flags: BitFlags<Flags>) {
+        self.core.set_flags(flags);
+    }
+    /// 当前的调用栈，第一帧为当前帧
+    pub fn backtrace(&self) -> Vec<Frame> {
+        self.core.backtrace(&self.mem)
+    }
+    /// 在端口上挂载设备，返回该端口上原有的设备
+    pub fn register_device(
+        &mut self,
+        port: UsizeRegType,
+        device: Box<dyn PortDevice>,
+    ) -> Option<Box<dyn PortDevice>> {
+        self.bus.register(port, device)
+    }
+    pub fn bus_mut(&mut self) -> &mut PortBus {
+        &mut self.bus
+    }
+    /// 开启或关闭指令融合，见`Memory::set_fusion`
+    pub fn set_fusion(&mut self, on: bool) {
+        self.mem.set_fusion(on);
     }
     pub fn set_code(&mut self, code: Vec<Inst>) {
         self.mem.store(Some(code), None, None);
     }
+    /// 用镜像替换内存，镜像中没有的堆段和栈段会被清空，符号表也随之替换
+    ///
+    /// 堆段或栈段超过内存上限时返回`MemoryErr`，内存保持不变
+    pub fn load_image(&mut self, image: Image) -> Result<(), VmErr> {
+        let heap = image.heap.unwrap_or_default();
+        let stack = image.stack.unwrap_or_default();
+        self.mem.limits().check(&heap, &stack)?;
+        self.symbols = image.symbols.unwrap_or_default();
+        self.mem.store(Some(image.code), Some(heap), Some(stack));
+        Ok(())
+    }
+    /// 设置内存上限，见`memory::Limits`
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.mem.set_limits(limits);
+    }
     pub fn mem_store(&mut self, code: Option<Vec<Inst>>, heap: Option<Heap>, stack: Option<Stack>) {
         self.mem.store(code, heap, stack);
     }
     pub fn mem_load(&self) -> (Vec<Inst>, Vec<HeapObj>, Vec<RegType>) {
         self.mem.load()
     }
+    pub fn code(&self) -> &[Inst] {
+        self.mem.code_segment()
+    }
+    pub fn heap(&self) -> &Heap {
+        self.mem.heap_segment()
+    }
+    pub fn stack(&self) -> &Stack {
+        self.mem.stack_segment()
+    }
     pub fn reset(&mut self) {
         self.core.reset();
         self.mem.reset();
     }
+    /// 取得当前的执行状态，编码后可以保存到文件，见`snapshot`
+    pub fn snapshot(&self) -> Snapshot {
+        let (code, heap, stack) = self.mem.load();
+        Snapshot {
+            u_regs: U_REGS.map(|r| self.core.get_u_reg(r)),
+            f_regs: F_REGS.map(|r| self.core.get_f_reg(r)),
+            pc: self.core.get_pc(),
+            bp: self.core.get_bp(),
+            flags: self.core.flags,
+            fuel: self.core.fuel(),
+            costs: self.core.cost_table().clone(),
+            step_limit: self.step_limit,
+            limits: self.mem.limits(),
+            code,
+            heap,
+            stack,
+            symbols: self.symbols.clone(),
+            threads: self.core.threads().clone(),
+        }
+    }
+    /// 恢复到快照时的状态，端口上的设备保持不变
+    ///
+    /// 堆段、栈段或线程数超过快照中的内存上限时返回`MemoryErr`，状态保持不变
+    pub fn restore(&mut self, snap: Snapshot) -> Result<(), VmErr> {
+        snap.limits.check(&snap.heap, &snap.stack)?;
+        snap.threads.check(&snap.limits, &snap.stack)?;
+        for (r, v) in U_REGS.into_iter().zip(snap.u_regs) {
+            self.core.set_u_reg(r, v);
+        }
+        for (r, v) in F_REGS.into_iter().zip(snap.f_regs) {
+            self.core.set_f_reg(r, v);
+        }
+        self.core.set_pc(snap.pc);
+        self.core.set_bp(snap.bp);
+        self.core.set_flags(snap.flags);
+        self.core.set_fuel(snap.fuel);
+        self.core.set_cost_table(snap.costs);
+        self.core.set_threads(snap.threads);
+        self.step_limit = snap.step_limit;
+        self.symbols = snap.symbols;
+        self.mem.set_limits(snap.limits);
+        self.mem.store(Some(snap.code), Some(snap.heap), Some(snap.stack));
+        self.mem.set_parked_stack(self.core.threads().parked_stack());
+        Ok(())
+    }
 }
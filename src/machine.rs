@@ -0,0 +1,408 @@
+//! 多核虚拟机
+//!
+//! `Machine`有N个核心，每个核心有自己的寄存器、标志位、燃料和栈，所有核心共享同一个代码段、
+//! 堆段和端口总线。`set_code`和`load_image`之后每个核心都从地址0开始执行，U1为核心的编号。
+//!
+//! `run`在当前线程中按编号轮流让每个核心执行至多`quantum`条指令，执行的先后只取决于程序和
+//! `quantum`，每次执行的结果都相同。`run_threads`让每个核心在自己的线程中真正并行执行：
+//! 访问堆、端口和系统调用的指令持有共享的锁逐条执行，其余指令不加锁，执行的先后由操作系统决定。
+//!
+//! 两种方式下访问堆的一条指令都不会与其他核心的交错，但指令之间的先后是任意的，客户程序应当用
+//! 比较并交换（1）、取值相加（2）和内存屏障（3）这几个系统调用同步，见`sys_call`。
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+use demo_isa::reg::{UsizeReg, UsizeRegType};
+use demo_isa::Inst;
+
+use crate::cpu::{green, CpuCore, ExitReason, ExitStatus, Fault};
+use crate::image::Image;
+use crate::memory::{Heap, Limits, Memory, Stack};
+use crate::port::PortBus;
+use crate::VmErr;
+
+/// 默认的时间片长度
+pub const DEFAULT_QUANTUM: usize = 1000;
+
+/// 某个核心执行出错
+#[derive(Debug)]
+pub struct MachineErr {
+    pub core: usize,
+    pub err: VmErr,
+}
+impl fmt::Display for MachineErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "core {}: {}", self.core, self.err)
+    }
+}
+
+/// 一个核心，`mem`中的堆段只在执行时间片时换入共享的堆
+#[derive(Debug)]
+struct Core {
+    cpu: CpuCore,
+    mem: Memory,
+    /// 已执行的指令数
+    steps: usize,
+    /// 停止的原因，`None`表示还可以继续执行
+    status: Option<ExitStatus>,
+    /// 执行出错，不再执行
+    faulted: bool,
+}
+
+impl Core {
+    /// 换入`heap`执行至多`n`条指令，`limit`为该核心总的指令数上限
+    fn slice(
+        &mut self,
+        heap: &mut Heap,
+        bus: &mut PortBus,
+        n: usize,
+        limit: usize,
+    ) -> Result<(), Fault> {
+        std::mem::swap(self.mem.heap_segment_mut(), heap);
+        let r = self.cpu.start(&mut self.mem, bus, Some(n));
+        std::mem::swap(self.mem.heap_segment_mut(), heap);
+        self.faulted = r.is_err();
+        let status = r?;
+        self.steps += status.steps;
+        if status.reason != ExitReason::StepLimit || self.steps >= limit {
+            self.status = Some(ExitStatus {
+                steps: self.steps,
+                ..status
+            });
+        }
+        Ok(())
+    }
+    /// 在自己的线程中逐条执行直到停止，`failed`为真时在下一条指令之前停下
+    fn run_alone(
+        &mut self,
+        shared: &Mutex<(&mut Heap, &mut PortBus)>,
+        failed: &AtomicBool,
+        limit: usize,
+    ) -> Result<(), Fault> {
+        // 不访问堆和端口的指令换入空的堆和总线，不需要加锁
+        let (mut heap, mut bus) = (Heap::new(), PortBus::empty());
+        let quantum = self.cpu.threads().quantum;
+        let mut since_yield = 0;
+        while self.status.is_none() && !failed.load(Ordering::SeqCst) {
+            let n = limit.saturating_sub(self.steps).min(1);
+            let pc = self.cpu.get_pc();
+            if self.mem.code_segment().get(pc).is_some_and(is_shared) {
+                let mut guard = shared.lock().unwrap();
+                let (heap, bus) = &mut *guard;
+                self.slice(heap, bus, n, limit)?;
+            } else {
+                self.slice(&mut heap, &mut bus, n, limit)?;
+            }
+            // 每次只执行一条指令，`start`不会切换绿色线程，在这里按时间片切换
+            if let Some(quantum) = quantum {
+                since_yield += 1;
+                if since_yield >= quantum && self.status.is_none() {
+                    green::yield_now(&mut self.cpu, &mut self.mem);
+                    since_yield = 0;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 访问共享的堆或总线的指令，系统调用都视为访问堆
+fn is_shared(inst: &Inst) -> bool {
+    matches!(
+        inst,
+        Inst::LoadUH(..)
+            | Inst::LoadDH(..)
+            | Inst::StoreUH(..)
+            | Inst::StoreDH(..)
+            | Inst::SysCall(..)
+            | Inst::InU(..)
+            | Inst::InD(..)
+            | Inst::OutU(..)
+            | Inst::OutD(..)
+    )
+}
+
+/// 共享堆的多核虚拟机
+#[derive(Debug)]
+pub struct Machine {
+    cores: Vec<Core>,
+    heap: Heap,
+    bus: PortBus,
+    quantum: usize,
+    /// 每个核心的指令数上限
+    step_limit: Option<usize>,
+    symbols: BTreeMap<String, UsizeRegType>,
+}
+
+impl Machine {
+    /// 有`n`个核心的虚拟机，至少有一个核心
+    pub fn new(n: usize) -> Machine {
+        let cores = (0..n.max(1))
+            .map(|_| Core {
+                cpu: CpuCore::new(),
+                mem: Memory::new(),
+                steps: 0,
+                status: None,
+                faulted: false,
+            })
+            .collect();
+        Machine {
+            cores,
+            heap: Heap::new(),
+            bus: PortBus::new(),
+            quantum: DEFAULT_QUANTUM,
+            step_limit: None,
+            symbols: BTreeMap::new(),
+        }
+    }
+    /// 核心的个数
+    pub fn cores(&self) -> usize {
+        self.cores.len()
+    }
+    pub fn core(&self, i: usize) -> &CpuCore {
+        &self.cores[i].cpu
+    }
+    pub fn core_mut(&mut self, i: usize) -> &mut CpuCore {
+        &mut self.cores[i].cpu
+    }
+    /// 第`i`个核心的栈段
+    pub fn stack(&self, i: usize) -> &Stack {
+        self.cores[i].mem.stack_segment()
+    }
+    pub fn heap(&self) -> &Heap {
+        &self.heap
+    }
+    pub fn heap_mut(&mut self) -> &mut Heap {
+        &mut self.heap
+    }
+    pub fn bus_mut(&mut self) -> &mut PortBus {
+        &mut self.bus
+    }
+    pub fn symbols(&self) -> &BTreeMap<String, UsizeRegType> {
+        &self.symbols
+    }
+    /// 设置每个时间片的指令数，至少为1
+    pub fn set_quantum(&mut self, n: usize) {
+        self.quantum = n.max(1);
+    }
+    /// 设置每个核心的指令数上限，`None`表示不限制
+    pub fn set_step_limit(&mut self, limit: Option<usize>) {
+        self.step_limit = limit;
+    }
+    /// 设置每个核心的内存上限，堆的上限对共享的堆同样有效
+    pub fn set_limits(&mut self, limits: Limits) {
+        for core in &mut self.cores {
+            core.mem.set_limits(limits);
+        }
+    }
+    /// 开启或关闭指令融合，见`Memory::set_fusion`
+    pub fn set_fusion(&mut self, on: bool) {
+        for core in &mut self.cores {
+            core.mem.set_fusion(on);
+        }
+    }
+    pub fn set_code(&mut self, code: Vec<Inst>) {
+        for core in &mut self.cores {
+            core.mem.store(Some(code.clone()), None, None);
+        }
+        self.reset_cores();
+    }
+    /// 用镜像替换内存，堆段由所有核心共享，每个核心各得到一份栈段
+    ///
+    /// 堆段或栈段超过内存上限时返回`MemoryErr`，内存保持不变
+    pub fn load_image(&mut self, image: Image) -> Result<(), VmErr> {
+        let heap = image.heap.unwrap_or_default();
+        let stack = image.stack.unwrap_or_default();
+        self.cores[0].mem.limits().check(&heap, &stack)?;
+        for core in &mut self.cores {
+            core.mem
+                .store(Some(image.code.clone()), None, Some(stack.clone()));
+        }
+        self.heap = heap;
+        self.symbols = image.symbols.unwrap_or_default();
+        self.reset_cores();
+        Ok(())
+    }
+    /// 每个核心从地址0开始，U1为核心的编号
+    fn reset_cores(&mut self) {
+        for (i, core) in self.cores.iter_mut().enumerate() {
+            core.cpu.reset();
+            core.mem.set_parked_stack(0);
+            core.cpu.set_u_reg(UsizeReg::U1, i);
+            core.steps = 0;
+            core.status = None;
+            core.faulted = false;
+        }
+    }
+    /// 轮流执行各个核心直到全部停止，返回每个核心停止的原因，出过错的核心为`None`
+    ///
+    /// 某个核心出错时立即返回，其余核心停在各自的时间片之间。出错的核心不再执行，
+    /// 再次调用时其余核心继续执行
+    pub fn run(&mut self) -> Result<Vec<Option<ExitStatus>>, MachineErr> {
+        let limit = self.step_limit.unwrap_or(usize::MAX);
+        loop {
+            let mut running = false;
+            for (i, core) in self.cores.iter_mut().enumerate() {
+                if core.status.is_some() || core.faulted {
+                    continue;
+                }
+                running = true;
+                let n = self.quantum.min(limit.saturating_sub(core.steps));
+                core.slice(&mut self.heap, &mut self.bus, n, limit)
+                    .map_err(|fault| fault_err(i, fault, &self.symbols))?;
+            }
+            if !running {
+                return Ok(self.statuses());
+            }
+        }
+    }
+    /// 每个核心在自己的线程中执行直到全部停止，返回值同`run`
+    ///
+    /// 某个核心出错时其余核心在下一条指令之前停下，返回最先出错的核心。出错的核心不再执行，
+    /// 再次调用时其余核心继续执行
+    pub fn run_threads(&mut self) -> Result<Vec<Option<ExitStatus>>, MachineErr> {
+        let limit = self.step_limit.unwrap_or(usize::MAX);
+        let shared = Mutex::new((&mut self.heap, &mut self.bus));
+        let failed = AtomicBool::new(false);
+        let symbols = &self.symbols;
+        let errs: Vec<_> = thread::scope(|s| {
+            let threads: Vec<_> = self
+                .cores
+                .iter_mut()
+                .enumerate()
+                .filter(|(_, core)| core.status.is_none() && !core.faulted)
+                .map(|(i, core)| {
+                    let (shared, failed) = (&shared, &failed);
+                    s.spawn(move || {
+                        let fault = core.run_alone(shared, failed, limit).err()?;
+                        // 几个核心先后出错时只报告第一个
+                        let first = !failed.swap(true, Ordering::SeqCst);
+                        first.then(|| fault_err(i, fault, symbols))
+                    })
+                })
+                .collect();
+            threads.into_iter().map(|t| t.join().unwrap()).collect()
+        });
+        match errs.into_iter().flatten().next() {
+            Some(err) => Err(err),
+            None => Ok(self.statuses()),
+        }
+    }
+    fn statuses(&self) -> Vec<Option<ExitStatus>> {
+        self.cores.iter().map(|core| core.status).collect()
+    }
+}
+
+fn fault_err(
+    core: usize,
+    mut fault: Fault,
+    symbols: &BTreeMap<String, UsizeRegType>,
+) -> MachineErr {
+    if !symbols.is_empty() {
+        fault.symbolize(symbols);
+    }
+    MachineErr {
+        core,
+        err: fault.into(),
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_machine() {
+    use crate::asm::assemble;
+    use crate::cpu::CpuErr;
+    use crate::memory::heap::HeapObj;
+    use demo_isa::err::ISAErr;
+    use demo_isa::RegType;
+
+    // 每个核心用取值相加给计数器加100次，再用比较并交换登记自己的编号加一
+    let code = assemble(
+        "
+        mu u6, 100
+        mu u7, 0
+    loop:
+        mu u2, 0
+        mu u3, 1
+        mu u8, 2
+        syscall u8          ; fetch_add
+        addui u7, 1
+        mu u8, loop
+        jne u8, u7, u6
+        mu u8, 3
+        syscall u8          ; fence
+        movu u4, u1
+        addui u4, 1
+        mu u2, 1
+        mu u3, 0
+        mu u8, 1
+        syscall u8          ; compare_and_swap，只有第一个核心成功
+        mu u2, 2
+        mu u3, 1
+        mu u8, 2
+        syscall u8          ; 完成的核心数
+        halt
+        ",
+    )
+    .unwrap();
+    let mut machine = Machine::new(4);
+    machine.set_code(code.clone());
+    machine.set_quantum(7);
+    let statuses = machine.run().unwrap();
+    assert!(statuses
+        .iter()
+        .all(|s| s.unwrap().reason == ExitReason::Halt));
+    let heap = format!("{:?}", machine.heap());
+    // 轮转的顺序是确定的，核心0最先登记
+    assert_eq!(heap, "[R(Usize(400)), R(Usize(1)), R(Usize(4))]");
+
+    // 每个核心一个线程时先登记的核心不确定，但计数不会丢失
+    for _ in 0..10 {
+        machine.set_code(code.clone());
+        machine.heap_mut().clear();
+        let statuses = machine.run_threads().unwrap();
+        assert!(statuses
+            .iter()
+            .all(|s| s.unwrap().reason == ExitReason::Halt));
+        let heap = machine.heap();
+        assert_eq!(format!("{:?}", heap[0]), "R(Usize(400))");
+        assert!(matches!(heap[1], HeapObj::R(RegType::Usize(1..=4))));
+        assert_eq!(format!("{:?}", heap[2]), "R(Usize(4))");
+    }
+
+    // 死循环的核心达到上限后停止，不影响其他核心
+    machine.set_code(assemble("mu u8, 0\njnz u8, u1\nhalt").unwrap());
+    machine.set_step_limit(Some(50));
+    let statuses = machine.run().unwrap();
+    assert_eq!(statuses[0].unwrap().reason, ExitReason::Halt);
+    assert_eq!(statuses[1].unwrap().reason, ExitReason::StepLimit);
+    assert_eq!(statuses[1].unwrap().steps, 50);
+
+    // 对F64单元比较并交换报告具体的错误，而不是笼统的`SysCallErr`
+    let mut machine = Machine::new(1);
+    machine.set_code(assemble("mu u2, 0\nstoredh f1, u2\nmu u8, 1\nsyscall u8").unwrap());
+    assert!(matches!(
+        machine.run(),
+        Err(MachineErr { core: 0, err: VmErr::Fault(f) })
+            if matches!(f.err, CpuErr::ISAErr(ISAErr::InvalidHeapType))
+    ));
+
+    // 出错的核心不再执行，其余核心继续执行到停止
+    let mut machine = Machine::new(2);
+    machine.set_code(assemble("mu u8, 3\njnz u8, u1\nhalt\nmu u8, 99\nsyscall u8").unwrap());
+    assert!(matches!(machine.run(), Err(MachineErr { core: 1, .. })));
+    let statuses = machine.run().unwrap();
+    assert_eq!(statuses[0].unwrap().reason, ExitReason::Halt);
+    assert!(statuses[1].is_none());
+    assert!(machine.run().is_ok());
+
+    machine.set_code(assemble("mu u8, 3\njnz u8, u1\nhalt\nmu u8, 99\nsyscall u8").unwrap());
+    assert!(matches!(
+        machine.run_threads(),
+        Err(MachineErr { core: 1, .. })
+    ));
+    assert!(machine.run_threads().unwrap()[1].is_none());
+}
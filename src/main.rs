@@ -1,38 +1,570 @@
 extern crate alloc;
 
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::ops::Range;
+use std::process::ExitCode;
+use std::str::FromStr;
 
-fn main() {}
+use demo_isa::Inst;
+use demo_vm::{
+    asm::{self, disasm::format_inst},
+    coverage::Coverage,
+    cpu::{
+        observer::{ExecObserver, MemAccess},
+        replay::Recording,
+        CpuCore, ExitReason, Fault,
+    },
+    debugger::{format_core, format_state, Debugger},
+    image::{Image, MAGIC},
+    machine::Machine,
+    memory::Memory,
+    profiler::Profiler,
+    trace::{ChromeTracer, Filter, JsonTracer},
+    VmErr, VmTmp,
+};
+
+const USAGE: &str = "\
+用法: demo_vm [选项] <文件>
+
+<文件> 为.dasm汇编源码或二进制镜像（按文件头识别）
+
+选项:
+    -n, --limit <N>    最多执行N条指令
+        --fuel <N>     最多消耗N单位燃料，每条指令消耗1
+        --preempt <N>  绿色线程每执行N条指令切换一次
+        --record <F>   把系统调用的结果和从端口读到的值记录到文件F
+        --replay <F>   按文件F中的记录重放，与记录不符时在分歧处出错
+    -t, --trace        执行前把每条指令输出到标准错误
+        --trace-json <F>     把每条指令的执行结果以JSON Lines格式写入文件F
+        --chrome-trace <F>   把函数调用以Chrome trace event格式写入文件F
+        --trace-pcs <A..B>   只导出地址在[A, B)中的指令，或入口在其中的函数
+        --trace-steps <A..B> 只导出执行序号在[A, B)中的指令，省略的一端不限
+    -p, --profile      结束时把性能分析报告输出到标准错误
+        --folded <F>   把折叠栈格式的性能分析结果写入文件F
+        --coverage <F> 把覆盖率累加到文件F，文件不存在时新建
+        --lcov <F>     把覆盖率以lcov格式写入文件F
+    -d, --debug        启动交互式调试器
+        --cores <N>          在共享堆的N个核心上执行，U1为核心的编号
+        --threads            与--cores一起使用，每个核心在自己的线程中执行
+        --dump-heap    结束时输出堆段
+        --dump-stack   结束时输出栈段
+    -h, --help         输出帮助";
+
+/// 执行失败
+const EXIT_FAULT: u8 = 1;
+/// 参数错误或无法加载程序
+const EXIT_USAGE: u8 = 2;
+/// 达到指令数上限或燃料耗尽
+const EXIT_LIMIT: u8 = 3;
+
+#[derive(Debug, Default)]
+struct Options {
+    path: String,
+    limit: Option<usize>,
+    fuel: Option<u64>,
+    preempt: Option<usize>,
+    record: Option<String>,
+    replay: Option<String>,
+    trace: bool,
+    trace_json: Option<String>,
+    chrome_trace: Option<String>,
+    filter: Filter,
+    profile: bool,
+    folded: Option<String>,
+    coverage: Option<String>,
+    lcov: Option<String>,
+    debug: bool,
+    cores: Option<usize>,
+    threads: bool,
+    dump_heap: bool,
+    dump_stack: bool,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut opts = Options::default();
+    let mut path = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-n" | "--limit" => {
+                let n = args.next().ok_or(format!("{} 需要一个参数", arg))?;
+                opts.limit = Some(n.parse().map_err(|_| format!("无效的指令数 `{}`", n))?);
+            }
+            "--fuel" => {
+                let n = args.next().ok_or(format!("{} 需要一个参数", arg))?;
+                opts.fuel = Some(n.parse().map_err(|_| format!("无效的燃料数 `{}`", n))?);
+            }
+            "--preempt" => {
+                let n = args.next().ok_or(format!("{} 需要一个参数", arg))?;
+                opts.preempt = Some(n.parse().map_err(|_| format!("无效的指令数 `{}`", n))?);
+            }
+            "--record" => {
+                opts.record = Some(args.next().ok_or(format!("{} 需要一个参数", arg))?);
+            }
+            "--replay" => {
+                opts.replay = Some(args.next().ok_or(format!("{} 需要一个参数", arg))?);
+            }
+            "-t" | "--trace" => opts.trace = true,
+            "--trace-json" => {
+                opts.trace_json = Some(args.next().ok_or(format!("{} 需要一个参数", arg))?);
+            }
+            "--chrome-trace" => {
+                opts.chrome_trace = Some(args.next().ok_or(format!("{} 需要一个参数", arg))?);
+            }
+            "--trace-pcs" => {
+                let r = args.next().ok_or(format!("{} 需要一个参数", arg))?;
+                opts.filter.pcs = parse_range(&r, Filter::new().pcs)?;
+            }
+            "--trace-steps" => {
+                let r = args.next().ok_or(format!("{} 需要一个参数", arg))?;
+                opts.filter.steps = parse_range(&r, Filter::new().steps)?;
+            }
+            "-p" | "--profile" => opts.profile = true,
+            "--folded" => {
+                opts.folded = Some(args.next().ok_or(format!("{} 需要一个参数", arg))?);
+            }
+            "--coverage" => {
+                opts.coverage = Some(args.next().ok_or(format!("{} 需要一个参数", arg))?);
+            }
+            "--lcov" => {
+                opts.lcov = Some(args.next().ok_or(format!("{} 需要一个参数", arg))?);
+            }
+            "-d" | "--debug" => opts.debug = true,
+            "--cores" => {
+                let n = args.next().ok_or(format!("{} 需要一个参数", arg))?;
+                opts.cores = Some(n.parse().map_err(|_| format!("无效的核心数 `{}`", n))?);
+            }
+            "--threads" => opts.threads = true,
+            "--dump-heap" => opts.dump_heap = true,
+            "--dump-stack" => opts.dump_stack = true,
+            "-h" | "--help" => return Err(String::new()),
+            _ if arg.starts_with('-') => return Err(format!("未知选项 `{}`", arg)),
+            _ if path.is_some() => return Err(format!("多余的参数 `{}`", arg)),
+            _ => path = Some(arg),
+        }
+    }
+    if opts.record.is_some() && opts.replay.is_some() {
+        return Err("--record 和 --replay 不能同时使用".to_string());
+    }
+    if opts.threads && opts.cores.is_none() {
+        return Err("--threads 需要与 --cores 一起使用".to_string());
+    }
+    // 观察者、记录和调试器只支持单个核心
+    let single = opts.debug
+        || opts.record.is_some()
+        || opts.replay.is_some()
+        || opts.trace
+        || opts.trace_json.is_some()
+        || opts.chrome_trace.is_some()
+        || opts.profile
+        || opts.folded.is_some()
+        || opts.coverage.is_some()
+        || opts.lcov.is_some();
+    if opts.cores.is_some() && single {
+        return Err("--cores 不能与调试、记录、轨迹、性能分析或覆盖率选项同时使用".to_string());
+    }
+    opts.path = path.ok_or("缺少输入文件")?;
+    Ok(opts)
+}
+
+/// 解析`A..B`，省略的一端取`all`的一端
+fn parse_range<T: FromStr>(s: &str, all: Range<T>) -> Result<Range<T>, String> {
+    let (a, b) = s.split_once("..").ok_or(format!("无效的范围 `{}`", s))?;
+    let bound = |v: &str, default| match v {
+        "" => Ok(default),
+        _ => v.parse().map_err(|_| format!("无效的范围 `{}`", s)),
+    };
+    Ok(bound(a, all.start)?..bound(b, all.end)?)
+}
+
+/// 加载镜像或汇编源码，汇编源码的标签作为符号表，文件名作为镜像的源文件名
+fn load(path: &str) -> Result<Image, String> {
+    let bytes = std::fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+    if bytes.starts_with(MAGIC) {
+        return Image::decode(&bytes).map_err(|e| format!("{}: {:?}", path, e));
+    }
+    let src = String::from_utf8(bytes).map_err(|e| format!("{}: {}", path, e))?;
+    let program = asm::assemble_program(&src).map_err(|e| format!("{}:{}", path, e))?;
+    Ok(Image {
+        source: Some(path.to_string()),
+        ..program.into()
+    })
+}
+
+/// `--trace`、轨迹导出、性能分析和覆盖率使用的观察者
+struct Observer {
+    /// 执行前把每条指令输出到标准错误
+    trace: bool,
+    json: Option<JsonTracer<BufWriter<File>>>,
+    chrome: Option<ChromeTracer>,
+    profiler: Option<Profiler>,
+    coverage: Option<Coverage>,
+}
+impl Observer {
+    fn active(&self) -> bool {
+        self.trace
+            || self.json.is_some()
+            || self.chrome.is_some()
+            || self.profiler.is_some()
+            || self.coverage.is_some()
+    }
+}
+impl ExecObserver for Observer {
+    fn before_inst(&mut self, pc: usize, inst: &Inst, core: &CpuCore) {
+        if self.trace {
+            eprintln!("{:04}  {}", pc, format_inst(inst));
+        }
+        if let Some(json) = &mut self.json {
+            json.before_inst(pc, inst, core);
+        }
+        if let Some(chrome) = &mut self.chrome {
+            chrome.before_inst(pc, inst, core);
+        }
+        if let Some(profiler) = &mut self.profiler {
+            profiler.before_inst(pc, inst, core);
+        }
+        if let Some(coverage) = &mut self.coverage {
+            coverage.before_inst(pc, inst, core);
+        }
+    }
+    fn after_inst(&mut self, pc: usize, inst: &Inst, core: &CpuCore) {
+        if let Some(json) = &mut self.json {
+            json.after_inst(pc, inst, core);
+        }
+    }
+    fn on_mem(&mut self, access: MemAccess, mem: &Memory) {
+        if let Some(json) = &mut self.json {
+            json.on_mem(access, mem);
+        }
+    }
+    fn on_fault(&mut self, fault: &Fault) {
+        if let Some(json) = &mut self.json {
+            json.on_fault(fault);
+        }
+    }
+    fn on_call(&mut self, pc: usize, core: &CpuCore) {
+        if let Some(chrome) = &mut self.chrome {
+            chrome.on_call(pc, core);
+        }
+        if let Some(profiler) = &mut self.profiler {
+            profiler.on_call(pc, core);
+        }
+    }
+    fn on_ret(&mut self, pc: usize, core: &CpuCore) {
+        if let Some(chrome) = &mut self.chrome {
+            chrome.on_ret(pc, core);
+        }
+        if let Some(profiler) = &mut self.profiler {
+            profiler.on_ret(pc, core);
+        }
+    }
+}
+
+/// 输出`--profile`的报告，写入`--folded`的文件
+fn write_profile(vm: &VmTmp, profiler: &Profiler, opts: &Options) {
+    if opts.profile {
+        eprint!("{}", profiler.report(vm.code(), vm.symbols()));
+    }
+    if let Some(path) = &opts.folded {
+        if let Err(e) = std::fs::write(path, profiler.folded(vm.symbols())) {
+            eprintln!("错误: {}: {}", path, e);
+        }
+    }
+}
+
+/// 把本次的覆盖率累加到`--coverage`的文件，写入`--lcov`的报告
+///
+/// `source`为镜像中的源文件名，没有时报告中使用输入文件名
+fn write_coverage(
+    vm: &VmTmp,
+    coverage: &mut Coverage,
+    lines: &[usize],
+    source: Option<&str>,
+    opts: &Options,
+) {
+    if let Some(path) = &opts.coverage {
+        // 文件不存在时从零开始，无法解析时不覆盖它
+        match std::fs::read(path) {
+            Ok(bytes) => match Coverage::decode(&bytes) {
+                Ok(old) => coverage.merge(&old),
+                Err(e) => {
+                    eprintln!("错误: {}: {:?}", path, e);
+                    return;
+                }
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => {
+                eprintln!("错误: {}: {}", path, e);
+                return;
+            }
+        }
+        if let Err(e) = std::fs::write(path, coverage.encode()) {
+            eprintln!("错误: {}: {}", path, e);
+        }
+    }
+    if let Some(path) = &opts.lcov {
+        let report = coverage.lcov(source.unwrap_or(&opts.path), vm.code(), lines, vm.symbols());
+        if let Err(e) = std::fs::write(path, report) {
+            eprintln!("错误: {}: {}", path, e);
+        }
+    }
+}
+
+/// 创建`--trace-json`的文件，失败时不导出
+fn json_tracer(opts: &Options) -> Option<JsonTracer<BufWriter<File>>> {
+    let path = opts.trace_json.as_ref()?;
+    match File::create(path) {
+        Ok(file) => Some(JsonTracer::new(BufWriter::new(file), opts.filter.clone())),
+        Err(e) => {
+            eprintln!("错误: {}: {}", path, e);
+            None
+        }
+    }
+}
+
+/// 读取`--replay`的记录文件
+fn load_recording(path: &str) -> Result<Recording, String> {
+    let bytes = std::fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+    Recording::decode(&bytes).map_err(|e| format!("{}: {:?}", path, e))
+}
+
+/// `lines`和`source`为镜像中的行号和源文件名，用于`--lcov`
+fn run(
+    vm: &mut VmTmp,
+    lines: &[usize],
+    source: Option<&str>,
+    opts: &Options,
+) -> Result<ExitReason, VmErr> {
+    vm.set_step_limit(opts.limit);
+    vm.set_fuel(opts.fuel);
+    let profiling = opts.profile || opts.folded.is_some();
+    let mut obs = Observer {
+        trace: opts.trace,
+        json: json_tracer(opts),
+        chrome: opts
+            .chrome_trace
+            .is_some()
+            .then(|| ChromeTracer::new(opts.filter.clone())),
+        profiler: profiling.then(Profiler::new),
+        coverage: (opts.coverage.is_some() || opts.lcov.is_some()).then(Coverage::new),
+    };
+    let status = if obs.active() {
+        vm.start_with(&mut obs)
+    } else {
+        vm.start()
+    };
+    if let (Some(path), Some(json)) = (&opts.trace_json, obs.json) {
+        if let Err(e) = json.finish() {
+            eprintln!("错误: {}: {}", path, e);
+        }
+    }
+    if let (Some(path), Some(chrome)) = (&opts.chrome_trace, &obs.chrome) {
+        if let Err(e) = std::fs::write(path, chrome.to_json(vm.symbols())) {
+            eprintln!("错误: {}: {}", path, e);
+        }
+    }
+    if let Some(profiler) = &obs.profiler {
+        write_profile(vm, profiler, opts);
+    }
+    if let Some(coverage) = &mut obs.coverage {
+        write_coverage(vm, coverage, lines, source, opts);
+    }
+    // 出错时也保存记录，以便重现出错的执行
+    if let (Some(path), Some(rec)) = (&opts.record, vm.take_recording()) {
+        if let Err(e) = std::fs::write(path, rec.encode()) {
+            eprintln!("错误: {}: {}", path, e);
+        }
+    }
+    Ok(status?.reason)
+}
+
+fn main() -> ExitCode {
+    env_logger::init();
+    let opts = match parse_args(std::env::args().skip(1)) {
+        Ok(opts) => opts,
+        Err(msg) => {
+            if !msg.is_empty() {
+                eprintln!("错误: {}\n", msg);
+            }
+            eprintln!("{}", USAGE);
+            return ExitCode::from(EXIT_USAGE);
+        }
+    };
+    let image = match load(&opts.path) {
+        Ok(loaded) => loaded,
+        Err(msg) => {
+            eprintln!("错误: {}", msg);
+            return ExitCode::from(EXIT_USAGE);
+        }
+    };
+
+    if let Some(n) = opts.cores {
+        return run_machine(n, image, &opts);
+    }
+    let lines = image.lines.clone().unwrap_or_default();
+    let source = image.source.clone();
+    let mut vm = VmTmp::new();
+    if let Err(e) = vm.load_image(image) {
+        eprintln!("错误: {}: {}", opts.path, e);
+        return ExitCode::from(EXIT_USAGE);
+    }
+    vm.set_quantum(opts.preempt);
+    if opts.record.is_some() {
+        vm.record();
+    }
+    if let Some(path) = &opts.replay {
+        match load_recording(path) {
+            Ok(rec) => vm.replay(rec),
+            Err(msg) => {
+                eprintln!("错误: {}", msg);
+                return ExitCode::from(EXIT_USAGE);
+            }
+        }
+    }
+    if opts.debug {
+        let mut debugger = Debugger::new(vm);
+        return match debugger.repl(io::stdin().lock(), &mut io::stdout()) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                eprintln!("错误: {}", e);
+                ExitCode::from(EXIT_FAULT)
+            }
+        };
+    }
+    let exit = run(&mut vm, &lines, source.as_deref(), &opts);
+
+    print!("{}", format_state(&vm));
+    if opts.dump_heap {
+        println!("heap:");
+        for (addr, obj) in vm.heap().iter().enumerate() {
+            println!("  {:04}  {:?}", addr, obj);
+        }
+    }
+    if opts.dump_stack {
+        println!("stack:");
+        for (addr, v) in vm.stack().iter().enumerate() {
+            println!("  {:04}  {:?}", addr, v);
+        }
+    }
+    match exit {
+        Ok(reason) => {
+            let (msg, code) = describe(reason);
+            println!("exit: {}", msg);
+            ExitCode::from(code)
+        }
+        Err(e) => {
+            println!("exit: error {}", e);
+            ExitCode::from(EXIT_FAULT)
+        }
+    }
+}
+
+/// 停止原因的说明和退出码
+fn describe(reason: ExitReason) -> (&'static str, u8) {
+    match reason {
+        ExitReason::Halt => ("halt", 0),
+        ExitReason::EndOfCode => ("end of code", 0),
+        ExitReason::StepLimit => ("instruction limit reached", EXIT_LIMIT),
+        ExitReason::OutOfFuel => ("out of fuel", EXIT_LIMIT),
+        ExitReason::Suspended => ("suspended at async syscall", EXIT_LIMIT),
+    }
+}
+
+/// `--cores`：在多核虚拟机上执行，任一核心出错或达到上限时以相应的退出码结束
+fn run_machine(cores: usize, image: Image, opts: &Options) -> ExitCode {
+    let mut machine = Machine::new(cores);
+    if let Err(e) = machine.load_image(image) {
+        eprintln!("错误: {}: {}", opts.path, e);
+        return ExitCode::from(EXIT_USAGE);
+    }
+    machine.set_step_limit(opts.limit);
+    for i in 0..machine.cores() {
+        machine.core_mut(i).set_fuel(opts.fuel);
+        machine.core_mut(i).set_quantum(opts.preempt);
+    }
+    let exit = if opts.threads {
+        machine.run_threads()
+    } else {
+        machine.run()
+    };
+
+    for i in 0..machine.cores() {
+        print!("core {}:\n{}", i, format_core(machine.core(i)));
+    }
+    if opts.dump_heap {
+        println!("heap:");
+        for (addr, obj) in machine.heap().iter().enumerate() {
+            println!("  {:04}  {:?}", addr, obj);
+        }
+    }
+    if opts.dump_stack {
+        for i in 0..machine.cores() {
+            println!("stack {}:", i);
+            for (addr, v) in machine.stack(i).iter().enumerate() {
+                println!("  {:04}  {:?}", addr, v);
+            }
+        }
+    }
+    match exit {
+        Ok(statuses) => {
+            let mut code = 0;
+            for (i, status) in statuses.iter().enumerate() {
+                let (msg, c) = match status {
+                    Some(status) => describe(status.reason),
+                    None => ("error", EXIT_FAULT),
+                };
+                println!("exit {}: {}", i, msg);
+                code = code.max(c);
+            }
+            ExitCode::from(code)
+        }
+        Err(e) => {
+            println!("exit: error {}", e);
+            ExitCode::from(EXIT_FAULT)
+        }
+    }
+}
 
 #[cfg(test)]
 #[test]
 fn test_vm() {
     env_logger::init();
-    use demo_isa::{err::ISAErr, Inst::*};
-    use demo_vm::{cpu::CpuErr, memory::MemoryErr, VmTmp, VmErr};
+    use demo_isa::Inst::*;
+    use demo_vm::{cpu::ExitReason, VmTmp};
     use log::debug;
     let mut v = VmTmp::new();
     let start_code = vec![Nop; 1000];
     println!("{:?}", start_code);
     v.set_code(start_code);
 
-    match v.start() {
-        Ok(_) => {}
-        Err(e) => match e {
-            VmErr::CpuErr(CpuErr::MemoryErr(MemoryErr::InvalidCodeAddr)) => {
-                debug!("InvalidCodeAddr");
-                debug!("Code {:?}", v.mem_load().0);
-            }
-            VmErr::CpuErr(CpuErr::ISAErr(err)) => {
-                if err == ISAErr::Halt {
-                    debug!("Halt");
-                    debug!("Memory {:?}", v.mem_load());
-                } else {
-                    debug!("ISAErr {:?}", err);
-                }
-            }
-            e => {
-                debug!("Err {:?}", e);
-            }
-        },
-    }
+    let status = v.start().unwrap();
+    debug!("{:?}", status);
+    assert_eq!(status.reason, ExitReason::EndOfCode);
+    assert_eq!(status.steps, 1000);
+}
+#[cfg(test)]
+#[test]
+fn test_parse_args() {
+    let args = |s: &str| parse_args(s.split_whitespace().map(String::from));
+    let opts = args("-n 100 --fuel 7 --trace --dump-heap --folded out.txt prog.dasm").unwrap();
+    assert_eq!(opts.path, "prog.dasm");
+    assert_eq!((opts.limit, opts.fuel), (Some(100), Some(7)));
+    assert!(opts.trace && opts.dump_heap && !opts.dump_stack);
+    assert_eq!(opts.folded.as_deref(), Some("out.txt"));
+    let opts = args("--coverage a.cov --lcov a.info prog.dasm").unwrap();
+    assert_eq!(opts.coverage.as_deref(), Some("a.cov"));
+    assert_eq!(opts.lcov.as_deref(), Some("a.info"));
+    assert!(args("-n x prog.dasm").is_err());
+    assert!(args("--limit").is_err());
+    assert!(args("a b").is_err());
+    assert!(args("--record a --replay b prog.dasm").is_err());
+    let opts = args("--trace-pcs 4..9 --trace-steps ..100 prog.dasm").unwrap();
+    assert_eq!((opts.filter.pcs, opts.filter.steps), (4..9, 0..100));
+    assert!(args("--trace-pcs 4 prog.dasm").is_err());
+    let opts = args("--cores 4 --threads prog.dasm").unwrap();
+    assert_eq!((opts.cores, opts.threads), (Some(4), true));
+    assert_eq!(args("--preempt 50 prog.dasm").unwrap().preempt, Some(50));
+    assert!(args("--threads prog.dasm").is_err());
+    assert!(args("--cores 2 --profile prog.dasm").is_err());
+    assert!(args("").is_err());
 }
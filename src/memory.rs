@@ -6,16 +6,99 @@ use demo_isa::reg::UsizeRegType;
 use demo_isa::{Inst, RegType};
 
 use self::heap::HeapObj;
+#[cfg(feature = "jit")]
+use crate::cpu::jit::{BlockFn, Jit};
+use crate::cpu::threaded::{self, Op};
+use crate::cpu::CpuErr;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MemoryErr {
     InvalidCodeAddr,
+    /// 堆地址达到`Limits::max_heap`
+    HeapLimit(UsizeRegType),
+    /// 栈的深度超过`Limits::max_stack`
+    StackOverflow,
+    /// 堆中数组的元素总数超过`Limits::max_array_elems`
+    ArrayLimit(usize),
+    /// 线程数超过`Limits::max_threads`
+    ThreadLimit,
+}
+
+/// 内存的资源上限，超过时返回`MemoryErr`，而不是任由客户程序让堆和栈无限增长
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// 堆中对象的个数
+    pub max_heap: usize,
+    /// 栈中元素的个数，包括其他绿色线程的栈
+    pub max_stack: usize,
+    /// 堆中所有`UArray`和`FArray`的元素总数
+    pub max_array_elems: usize,
+    /// 创建过的绿色线程数，包括主线程和已结束的线程
+    pub max_threads: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Limits {
+    /// 堆和栈各2^20个，数组元素共2^24个，线程2^12个
+    pub fn new() -> Limits {
+        Limits {
+            max_heap: 1 << 20,
+            max_stack: 1 << 20,
+            max_array_elems: 1 << 24,
+            max_threads: 1 << 12,
+        }
+    }
+    pub fn unlimited() -> Limits {
+        Limits {
+            max_heap: usize::MAX,
+            max_stack: usize::MAX,
+            max_array_elems: usize::MAX,
+            max_threads: usize::MAX,
+        }
+    }
+    /// 检查加载的堆段和栈段是否在上限之内
+    pub fn check(&self, heap: &Heap, stack: &Stack) -> Result<(), MemoryErr> {
+        if heap.len() > self.max_heap {
+            return Err(MemoryErr::HeapLimit(heap.len() - 1));
+        }
+        if stack.len() > self.max_stack {
+            return Err(MemoryErr::StackOverflow);
+        }
+        let elems = heap
+            .iter()
+            .map(|obj| match obj {
+                HeapObj::UArray(a) => a.len(),
+                HeapObj::FArray(a) => a.len(),
+                HeapObj::R(_) => 0,
+            })
+            .fold(0usize, |n, len| n.saturating_add(len));
+        if elems > self.max_array_elems {
+            return Err(MemoryErr::ArrayLimit(elems));
+        }
+        Ok(())
+    }
 }
+
 #[derive(Debug)]
 pub struct Memory {
     code_segment: Vec<Inst>,
+    /// 与代码段一一对应的预解码指令，随代码段一起更新
+    ops: Vec<Op>,
+    /// 预解码时是否融合常见的指令对
+    fusion: bool,
+    /// 已编译为本机代码的块，随代码段一起丢弃
+    #[cfg(feature = "jit")]
+    jit: Jit,
     heap_segment: Heap,
     stack_segment: Vec<RegType>,
+    /// 其他绿色线程的栈中元素的总数，与栈段一起受`max_stack`限制，见`cpu::green`
+    parked_stack: usize,
+    limits: Limits,
 }
 
 impl Default for Memory {
@@ -28,8 +111,14 @@ impl Memory {
     pub fn new() -> Memory {
         Memory {
             code_segment: Vec::new(),
+            ops: Vec::new(),
+            fusion: true,
+            #[cfg(feature = "jit")]
+            jit: Jit::default(),
             heap_segment: Vec::new(),
             stack_segment: Vec::new(),
+            parked_stack: 0,
+            limits: Limits::new(),
         }
     }
     pub fn store(
@@ -39,6 +128,11 @@ impl Memory {
         stack: Option<Vec<RegType>>,
     ) {
         if let Some(c) = code {
+            self.ops = threaded::compile(&c, self.fusion);
+            #[cfg(feature = "jit")]
+            {
+                self.jit = Jit::default();
+            }
             self.code_segment = c;
         }
         if let Some(h) = heap {
@@ -55,14 +149,67 @@ impl Memory {
             self.stack_segment.clone(),
         )
     }
+    pub fn code_segment(&self) -> &[Inst] {
+        &self.code_segment
+    }
+    pub(crate) fn ops(&self) -> &[Op] {
+        &self.ops
+    }
+    /// 开启或关闭指令融合，默认开启；执行结果与是否融合无关
+    pub fn set_fusion(&mut self, on: bool) {
+        if self.fusion != on {
+            self.fusion = on;
+            self.ops = threaded::compile(&self.code_segment, on);
+        }
+    }
+    pub fn fusion(&self) -> bool {
+        self.fusion
+    }
+    #[cfg(all(feature = "jit", test))]
+    pub(crate) fn jit(&self) -> &Jit {
+        &self.jit
+    }
+    #[cfg(feature = "jit")]
+    #[inline]
+    pub(crate) fn jit_enter(&mut self, pc: UsizeRegType) -> Option<(BlockFn, usize)> {
+        self.jit.enter(&self.code_segment, pc)
+    }
+    /// 设置资源上限，只约束此后的增长，已有的内容可以用`Limits::check`检查
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+    pub fn stack_segment(&self) -> &Stack {
+        &self.stack_segment
+    }
+    pub fn heap_segment(&self) -> &Heap {
+        &self.heap_segment
+    }
+    pub fn heap_segment_mut(&mut self) -> &mut Heap {
+        &mut self.heap_segment
+    }
+    pub fn stack_segment_mut(&mut self) -> &mut Stack {
+        &mut self.stack_segment
+    }
+    pub(crate) fn set_parked_stack(&mut self, n: usize) {
+        self.parked_stack = n;
+    }
     pub fn reset(&mut self) {
         self.code_segment.clear();
+        self.ops.clear();
+        #[cfg(feature = "jit")]
+        {
+            self.jit = Jit::default();
+        }
         self.heap_segment.clear();
         self.stack_segment.clear();
+        self.parked_stack = 0;
     }
 }
 
-impl  Memory {
+impl Memory {
     // fn clear_heap(&mut self) {
     //     self.heap_segment.clear();
     // }
@@ -76,15 +223,23 @@ impl  Memory {
     // }
 
     pub fn get_stack(&self, bp: UsizeRegType, addr: UsizeRegType) -> Result<RegType, ISAErr> {
-        if let Some(val) = self.stack_segment.get(bp + addr) {
+        // `addr`来自客户程序，相加溢出时同样是非法地址
+        let slot = bp.checked_add(addr).ok_or(ISAErr::InvalidStackAddr)?;
+        if let Some(val) = self.stack_segment.get(slot) {
             Ok(*val)
         } else {
             Err(ISAErr::InvalidStackAddr)
         }
     }
 
-    pub fn set_stack(&mut self, bp: UsizeRegType, addr: UsizeRegType, val: RegType) -> Result<(), ISAErr> {
-        if let Some(obj) = self.stack_segment.get_mut(bp + addr) {
+    pub fn set_stack(
+        &mut self,
+        bp: UsizeRegType,
+        addr: UsizeRegType,
+        val: RegType,
+    ) -> Result<(), ISAErr> {
+        let slot = bp.checked_add(addr).ok_or(ISAErr::InvalidStackAddr)?;
+        if let Some(obj) = self.stack_segment.get_mut(slot) {
             *obj = val;
             Ok(())
         } else {
@@ -92,8 +247,12 @@ impl  Memory {
         }
     }
 
-    pub fn push_stack(&mut self, val: RegType) {
+    pub fn push_stack(&mut self, val: RegType) -> Result<(), MemoryErr> {
+        if self.stack_segment.len() + self.parked_stack >= self.limits.max_stack {
+            return Err(MemoryErr::StackOverflow);
+        }
         self.stack_segment.push(val);
+        Ok(())
     }
 
     pub fn pop_stack(&mut self) -> Result<RegType, ISAErr> {
@@ -128,16 +287,27 @@ impl  Memory {
     //     self.stack_segment.extend(stack);
     // }
 
+    /// 把堆扩展到包含`addr`，新的对象为0
+    fn grow_heap(&mut self, addr: UsizeRegType) -> Result<(), MemoryErr> {
+        if addr >= self.limits.max_heap {
+            return Err(MemoryErr::HeapLimit(addr));
+        }
+        if addr >= self.heap_segment.len() {
+            self.heap_segment
+                .resize(addr + 1, HeapObj::R(RegType::Usize(0)));
+        }
+        Ok(())
+    }
+
     pub fn get_heap_u_type(
         &mut self,
         addr: demo_isa::reg::UsizeRegType,
-    ) -> Result<demo_isa::reg::UsizeRegType, ISAErr> {
+    ) -> Result<demo_isa::reg::UsizeRegType, CpuErr> {
         if self.heap_segment.len() <= addr {
-            self.heap_segment
-                .resize(addr + 1, HeapObj::R(RegType::Usize(0)));
+            self.grow_heap(addr)?;
             return Ok(0);
         }
-        self.heap_segment[addr].get_reg_u_type().copied()
+        Ok(*self.heap_segment[addr].get_reg_u_type()?)
         // if let Some(h) = self.heap_segment.get(addr) {
         //     h.get_reg_u_type()
         // } else {
@@ -150,33 +320,32 @@ impl  Memory {
     pub fn get_heap_f_type(
         &mut self,
         addr: demo_isa::reg::UsizeRegType,
-    ) -> Result<demo_isa::reg::F64RegType, ISAErr> {
+    ) -> Result<demo_isa::reg::F64RegType, CpuErr> {
         if self.heap_segment.len() <= addr {
-            self.heap_segment
-                .resize(addr + 1, HeapObj::R(RegType::Usize(0)));
+            self.grow_heap(addr)?;
             return Ok(0.0);
         }
-        self.heap_segment[addr].get_reg_f_type().copied()   
+        Ok(*self.heap_segment[addr].get_reg_f_type()?)
     }
 
-    pub fn set_heap(&mut self, addr: demo_isa::reg::UsizeRegType, val: &RegType) {
-        if let Some(h) = self.heap_segment.get_mut(addr) {
-            *h = HeapObj::R(*val);
-        } else {
-            self.heap_segment
-                .resize(addr + 1, HeapObj::R(RegType::Usize(0)));
-            self.heap_segment[addr] = HeapObj::R(*val);
-        }
+    pub fn set_heap(
+        &mut self,
+        addr: demo_isa::reg::UsizeRegType,
+        val: &RegType,
+    ) -> Result<(), MemoryErr> {
+        self.grow_heap(addr)?;
+        self.heap_segment[addr] = HeapObj::R(*val);
+        Ok(())
     }
 }
 
 impl Memory {
-    pub fn get_heap_obj(&mut self, addr: demo_isa::reg::UsizeRegType) -> &HeapObj {
-        if addr >= self.heap_segment.len() {
-            self.heap_segment
-                .resize(addr + 1, HeapObj::R(RegType::Usize(0)));
-        }
-        &self.heap_segment[addr]
+    pub fn get_heap_obj(
+        &mut self,
+        addr: demo_isa::reg::UsizeRegType,
+    ) -> Result<&HeapObj, MemoryErr> {
+        self.grow_heap(addr)?;
+        Ok(&self.heap_segment[addr])
     }
 }
 
@@ -184,3 +353,112 @@ impl Memory {
 pub type Heap = Vec<HeapObj>;
 
 pub type Stack = Vec<RegType>;
+
+#[cfg(test)]
+#[test]
+fn test_limits() {
+    use crate::asm::assemble;
+    use crate::cpu::CpuErr;
+    use crate::image::Image;
+    use crate::{VmErr, VmTmp};
+
+    let limit_err = |src: &str| {
+        let mut vm = VmTmp::new();
+        vm.set_code(assemble(src).unwrap());
+        match vm.start() {
+            Err(VmErr::Fault(fault)) => match fault.err {
+                CpuErr::MemoryErr(e) => e,
+                e => panic!("unexpected {:?}", e),
+            },
+            r => panic!("unexpected {:?}", r),
+        }
+    };
+    assert_eq!(
+        limit_err("mu u2, 0xffff_ffff_ffff_fffe\nstoreuh u1, u2"),
+        MemoryErr::HeapLimit(usize::MAX - 1)
+    );
+    assert_eq!(
+        limit_err("mu u2, 0x100000\nloaddh f1, u2"),
+        MemoryErr::HeapLimit(1 << 20)
+    );
+    // 无限递归
+    assert_eq!(limit_err("mu u1, 0\ncall u1"), MemoryErr::StackOverflow);
+    // 无限创建线程
+    assert_eq!(
+        limit_err("mu u8, 4\nsyscall u8\nmu u1, 0\njmp u1"),
+        MemoryErr::ThreadLimit
+    );
+    // 其他线程的栈也计入上限：主线程压入60个后让出，新线程递归到共100个时溢出
+    let mut vm = VmTmp::new();
+    vm.set_limits(Limits {
+        max_stack: 100,
+        ..Limits::new()
+    });
+    vm.set_code(
+        assemble(
+            "
+            mu u2, t
+            mu u8, 4
+            syscall u8          ; spawn
+            mu u1, 60
+        l:
+            pushu u1
+            subui u1, 1
+            mu u3, l
+            jnz u3, u1
+            mu u8, 5
+            syscall u8          ; yield
+        t:
+            mu u1, t
+            call u1
+            ",
+        )
+        .unwrap(),
+    );
+    assert!(matches!(
+        vm.start(),
+        Err(VmErr::Fault(f)) if matches!(f.err, CpuErr::MemoryErr(MemoryErr::StackOverflow))
+    ));
+    assert_eq!(vm.stack().len(), 40);
+
+    // 客户程序给出的长度和地址不能让宿主崩溃
+    let isa_err = |src: &str| {
+        let mut vm = VmTmp::new();
+        vm.set_code(assemble(src).unwrap());
+        match vm.start() {
+            Err(VmErr::Fault(fault)) => match fault.err {
+                CpuErr::ISAErr(e) => e,
+                e => panic!("unexpected {:?}", e),
+            },
+            r => panic!("unexpected {:?}", r),
+        }
+    };
+    assert_eq!(
+        isa_err("mu u2, 0\nstoreuh u1, u2\nmu u3, 0xffff_ffff_ffff_ffff\nmu u8, 0\nsyscall u8"),
+        ISAErr::InvalidSysCallArg
+    );
+    assert_eq!(
+        isa_err("mu u8, f\ncall u8\nf:\nmu u2, 0xffff_ffff_ffff_ffff\nloadus u1, u2"),
+        ISAErr::InvalidStackAddr
+    );
+
+    let mut vm = VmTmp::new();
+    let mut image = Image::new(vec![]);
+    image.heap = Some(vec![
+        HeapObj::UArray(vec![0; 1000]),
+        HeapObj::FArray(vec![0.0; 24]),
+    ]);
+    vm.set_limits(Limits {
+        max_array_elems: 1023,
+        ..Limits::new()
+    });
+    assert!(matches!(
+        vm.load_image(image.clone()),
+        Err(VmErr::CpuErr(CpuErr::MemoryErr(MemoryErr::ArrayLimit(
+            1024
+        ))))
+    ));
+    vm.set_limits(Limits::unlimited());
+    vm.load_image(image).unwrap();
+    assert_eq!(vm.heap().len(), 2);
+}
@@ -86,7 +86,7 @@ fn test_get_u8_vec() {
     // 测试对于usize数组的正确性
     for _ in 0..100 {
         let mut rand_len: usize = random();
-        rand_len = rand_len % 1000;
+        rand_len %= 1000;
         rand_len += 1;
         let mut u_array = Vec::with_capacity(rand_len);
         for _ in 0..rand_len {
@@ -106,7 +106,7 @@ fn test_get_u8_vec() {
     // 测试对于f64数组的正确性
     for _ in 0..100 {
         let mut rand_len: usize = random();
-        rand_len = rand_len % 1000;
+        rand_len %= 1000;
         rand_len += 1;
         let mut f_array = Vec::with_capacity(rand_len);
         for _ in 0..rand_len {
@@ -0,0 +1,169 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+
+use demo_isa::reg::{F64RegType, UsizeRegType};
+
+pub use self::console::Console;
+pub use self::random::Random;
+pub use self::timer::Timer;
+
+mod console;
+mod random;
+mod timer;
+
+/// 控制台设备的默认端口
+pub const CONSOLE_PORT: UsizeRegType = 0;
+/// 计时器设备的默认端口
+pub const TIMER_PORT: UsizeRegType = 1;
+/// 随机数设备的默认端口
+pub const RANDOM_PORT: UsizeRegType = 2;
+
+#[derive(Debug)]
+pub enum PortErr {
+    UnmappedPort(UsizeRegType),
+    Unsupported,
+    IOError(std::io::Error),
+}
+impl From<std::io::Error> for PortErr {
+    fn from(err: std::io::Error) -> PortErr {
+        PortErr::IOError(err)
+    }
+}
+
+/// 挂在端口上的设备
+///
+/// 未实现的读写方法默认返回`PortErr::Unsupported`
+pub trait PortDevice: Debug + Send {
+    fn read_u(&mut self) -> Result<UsizeRegType, PortErr> {
+        Err(PortErr::Unsupported)
+    }
+    fn read_f(&mut self) -> Result<F64RegType, PortErr> {
+        Err(PortErr::Unsupported)
+    }
+    fn write_u(&mut self, _val: UsizeRegType) -> Result<(), PortErr> {
+        Err(PortErr::Unsupported)
+    }
+    fn write_f(&mut self, _val: F64RegType) -> Result<(), PortErr> {
+        Err(PortErr::Unsupported)
+    }
+}
+
+/// 端口映射的设备总线，`InU`/`InD`/`OutU`/`OutD`通过它访问设备
+#[derive(Debug)]
+pub struct PortBus {
+    devices: HashMap<UsizeRegType, Box<dyn PortDevice>>,
+}
+
+impl Default for PortBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PortBus {
+    /// 挂载了控制台、计时器和随机数设备的总线
+    pub fn new() -> PortBus {
+        let mut bus = PortBus::empty();
+        bus.register(CONSOLE_PORT, Box::new(Console::new()));
+        bus.register(TIMER_PORT, Box::new(Timer::new()));
+        bus.register(RANDOM_PORT, Box::new(Random::new()));
+        bus
+    }
+    /// 没有任何设备的总线
+    pub fn empty() -> PortBus {
+        PortBus {
+            devices: HashMap::new(),
+        }
+    }
+    /// 在端口上挂载设备，返回该端口上原有的设备
+    pub fn register(
+        &mut self,
+        port: UsizeRegType,
+        device: Box<dyn PortDevice>,
+    ) -> Option<Box<dyn PortDevice>> {
+        self.devices.insert(port, device)
+    }
+    pub fn unregister(&mut self, port: UsizeRegType) -> Option<Box<dyn PortDevice>> {
+        self.devices.remove(&port)
+    }
+    fn device(&mut self, port: UsizeRegType) -> Result<&mut Box<dyn PortDevice>, PortErr> {
+        self.devices
+            .get_mut(&port)
+            .ok_or(PortErr::UnmappedPort(port))
+    }
+    pub fn read_u(&mut self, port: UsizeRegType) -> Result<UsizeRegType, PortErr> {
+        self.device(port)?.read_u()
+    }
+    pub fn read_f(&mut self, port: UsizeRegType) -> Result<F64RegType, PortErr> {
+        self.device(port)?.read_f()
+    }
+    pub fn write_u(&mut self, port: UsizeRegType, val: UsizeRegType) -> Result<(), PortErr> {
+        self.device(port)?.write_u(val)
+    }
+    pub fn write_f(&mut self, port: UsizeRegType, val: F64RegType) -> Result<(), PortErr> {
+        self.device(port)?.write_f(val)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_port_io() {
+    use crate::{cpu::CpuErr, VmErr, VmTmp};
+    use demo_isa::{reg::F64Reg, reg::UsizeReg, Inst};
+
+    /// 记录写入的值，读出时加一
+    #[derive(Debug, Default)]
+    struct Latch {
+        u: UsizeRegType,
+        f: F64RegType,
+    }
+    impl PortDevice for Latch {
+        fn read_u(&mut self) -> Result<UsizeRegType, PortErr> {
+            Ok(self.u + 1)
+        }
+        fn read_f(&mut self) -> Result<F64RegType, PortErr> {
+            Ok(self.f + 1.0)
+        }
+        fn write_u(&mut self, val: UsizeRegType) -> Result<(), PortErr> {
+            self.u = val;
+            Ok(())
+        }
+        fn write_f(&mut self, val: F64RegType) -> Result<(), PortErr> {
+            self.f = val;
+            Ok(())
+        }
+    }
+    let mut vm = VmTmp::new();
+    vm.register_device(7, Box::<Latch>::default());
+    vm.set_code(vec![
+        Inst::MU(UsizeReg::U1, 7),
+        Inst::MU(UsizeReg::U2, 41),
+        Inst::MD(F64Reg::F1, 1.5),
+        Inst::OutU(UsizeReg::U2, UsizeReg::U1),
+        Inst::OutD(F64Reg::F1, UsizeReg::U1),
+        Inst::InU(UsizeReg::U3, UsizeReg::U1),
+        Inst::InD(F64Reg::F2, UsizeReg::U1),
+        Inst::MU(UsizeReg::U1, 100),
+        Inst::InU(UsizeReg::U4, UsizeReg::U1),
+    ]);
+    match vm.start() {
+        Err(VmErr::Fault(fault)) => match fault.err {
+            CpuErr::PortErr(PortErr::UnmappedPort(port)) => assert_eq!(port, 100),
+            e => panic!("unexpected {:?}", e),
+        },
+        r => panic!("unexpected {:?}", r),
+    }
+    assert_eq!(vm.get_u_reg(UsizeReg::U3), 42);
+    assert_eq!(vm.get_f_reg(F64Reg::F2), 2.5);
+    assert_eq!(vm.get_pc(), 9);
+}
+#[cfg(test)]
+#[test]
+fn test_random_seed() {
+    let mut bus = PortBus::empty();
+    bus.register(RANDOM_PORT, Box::new(Random::with_seed(0)));
+    let first = bus.read_u(RANDOM_PORT).unwrap();
+    bus.write_u(RANDOM_PORT, 0).unwrap();
+    assert_eq!(bus.read_u(RANDOM_PORT).unwrap(), first);
+    assert!(matches!(bus.read_u(CONSOLE_PORT), Err(PortErr::UnmappedPort(0))));
+}
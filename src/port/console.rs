@@ -0,0 +1,46 @@
+use std::io::{self, Read, Write};
+
+use demo_isa::reg::{F64RegType, UsizeRegType};
+
+use super::{PortDevice, PortErr};
+
+/// 控制台设备
+///
+/// 读：
+///     usize: 从标准输入读取一个字节，读到末尾时为`usize::MAX`
+///     f64: 从标准输入读取一行并解析为f64
+///
+/// 写：
+///     usize: 低8位作为一个字节写入标准输出
+///     f64: 以文本形式写入标准输出
+#[derive(Debug, Default)]
+pub struct Console;
+
+impl Console {
+    pub fn new() -> Console {
+        Console
+    }
+}
+
+impl PortDevice for Console {
+    fn read_u(&mut self) -> Result<UsizeRegType, PortErr> {
+        let mut buf = [0u8; 1];
+        match io::stdin().read(&mut buf)? {
+            0 => Ok(UsizeRegType::MAX),
+            _ => Ok(buf[0] as UsizeRegType),
+        }
+    }
+    fn read_f(&mut self) -> Result<F64RegType, PortErr> {
+        let mut line = String::new();
+        io::stdin().read_line(&mut line)?;
+        line.trim().parse().map_err(|_| PortErr::Unsupported)
+    }
+    fn write_u(&mut self, val: UsizeRegType) -> Result<(), PortErr> {
+        io::stdout().write_all(&[val as u8])?;
+        Ok(())
+    }
+    fn write_f(&mut self, val: F64RegType) -> Result<(), PortErr> {
+        write!(io::stdout(), "{}", val)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,50 @@
+use demo_isa::reg::{F64RegType, UsizeRegType};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use super::{PortDevice, PortErr};
+
+/// 随机数设备
+///
+/// 读：
+///     usize: 随机的usize
+///     f64: [0, 1)内随机的f64
+///
+/// 写：
+///     usize: 以写入的值作为种子重新初始化
+#[derive(Debug)]
+pub struct Random {
+    rng: StdRng,
+}
+
+impl Default for Random {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Random {
+    pub fn new() -> Random {
+        Random {
+            rng: StdRng::from_entropy(),
+        }
+    }
+    pub fn with_seed(seed: u64) -> Random {
+        Random {
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+}
+
+impl PortDevice for Random {
+    fn read_u(&mut self) -> Result<UsizeRegType, PortErr> {
+        Ok(self.rng.gen())
+    }
+    fn read_f(&mut self) -> Result<F64RegType, PortErr> {
+        Ok(self.rng.gen())
+    }
+    fn write_u(&mut self, val: UsizeRegType) -> Result<(), PortErr> {
+        self.rng = StdRng::seed_from_u64(val as u64);
+        Ok(())
+    }
+}
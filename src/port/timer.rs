@@ -0,0 +1,49 @@
+use std::time::Instant;
+
+use demo_isa::reg::{F64RegType, UsizeRegType};
+
+use super::{PortDevice, PortErr};
+
+/// 计时器设备
+///
+/// 读：
+///     usize: 自上次重置以来经过的微秒数
+///     f64: 自上次重置以来经过的秒数
+///
+/// 写：
+///     任意值都会重置计时器
+#[derive(Debug)]
+pub struct Timer {
+    start: Instant,
+}
+
+impl Default for Timer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Timer {
+    pub fn new() -> Timer {
+        Timer {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl PortDevice for Timer {
+    fn read_u(&mut self) -> Result<UsizeRegType, PortErr> {
+        Ok(self.start.elapsed().as_micros() as UsizeRegType)
+    }
+    fn read_f(&mut self) -> Result<F64RegType, PortErr> {
+        Ok(self.start.elapsed().as_secs_f64())
+    }
+    fn write_u(&mut self, _val: UsizeRegType) -> Result<(), PortErr> {
+        self.start = Instant::now();
+        Ok(())
+    }
+    fn write_f(&mut self, _val: F64RegType) -> Result<(), PortErr> {
+        self.start = Instant::now();
+        Ok(())
+    }
+}
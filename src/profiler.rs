@@ -0,0 +1,286 @@
+//! 客户程序的性能分析
+//!
+//! `Profiler`作为观察者按pc和指令种类计数，并沿`Call`/`Ret`维护调用栈，
+//! 把每条指令计入当前函数所在的调用路径。函数以被调用的地址标识，顶层代码记为`top`，
+//! 有符号表时显示为汇编器给出的标签。
+//!
+//! 分析结果可以输出为按计数排序的文本报告，也可以输出为flamegraph等工具使用的折叠栈格式：
+//! 每行为`top;f;g 123`，即一条调用路径和在该路径上执行的指令数。
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+
+use demo_isa::reg::UsizeRegType;
+use demo_isa::Inst;
+
+use crate::asm::disasm::format_inst;
+use crate::asm::symbolize;
+use crate::cpu::observer::ExecObserver;
+use crate::cpu::CpuCore;
+use crate::image::{opcode, opcode_names, OPCODES};
+
+/// 调用路径上的一个节点
+#[derive(Debug)]
+struct Node {
+    parent: usize,
+    /// 函数的入口地址，根节点为`None`
+    func: Option<UsizeRegType>,
+    /// 在这条路径上执行的指令数，不含被调用者
+    count: u64,
+    children: Vec<usize>,
+}
+
+/// 按pc、指令种类和调用路径统计执行的指令数
+#[derive(Debug)]
+pub struct Profiler {
+    pcs: Vec<u64>,
+    kinds: [u64; OPCODES],
+    /// 调用树，`nodes[0]`为顶层
+    nodes: Vec<Node>,
+    edges: HashMap<(usize, UsizeRegType), usize>,
+    current: usize,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ExecObserver for Profiler {
+    fn before_inst(&mut self, pc: UsizeRegType, inst: &Inst, _core: &CpuCore) {
+        if self.pcs.len() <= pc {
+            self.pcs.resize(pc + 1, 0);
+        }
+        self.pcs[pc] += 1;
+        let op = opcode(inst) as usize;
+        self.kinds[op] += 1;
+        self.nodes[self.current].count += 1;
+    }
+    fn on_call(&mut self, _pc: UsizeRegType, core: &CpuCore) {
+        let func = core.get_pc();
+        let parent = self.current;
+        let next = self.nodes.len();
+        self.current = *self.edges.entry((parent, func)).or_insert(next);
+        if self.current == next {
+            self.nodes.push(Node {
+                parent,
+                func: Some(func),
+                count: 0,
+                children: Vec::new(),
+            });
+            self.nodes[parent].children.push(next);
+        }
+    }
+    fn on_ret(&mut self, _pc: UsizeRegType, _core: &CpuCore) {
+        self.current = self.nodes[self.current].parent;
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler {
+            pcs: Vec::new(),
+            kinds: [0; OPCODES],
+            nodes: vec![Node {
+                parent: 0,
+                func: None,
+                count: 0,
+                children: Vec::new(),
+            }],
+            edges: HashMap::new(),
+            current: 0,
+        }
+    }
+    /// 执行的指令总数
+    pub fn total(&self) -> u64 {
+        self.pcs.iter().sum()
+    }
+    /// pc处的指令执行的次数
+    pub fn count(&self, pc: UsizeRegType) -> u64 {
+        self.pcs.get(pc).copied().unwrap_or(0)
+    }
+    /// 折叠栈格式，每行为一条调用路径和在该路径上执行的指令数
+    pub fn folded(&self, symbols: &BTreeMap<String, UsizeRegType>) -> String {
+        let mut out = String::new();
+        for (i, node) in self.nodes.iter().enumerate() {
+            if node.count == 0 {
+                continue;
+            }
+            let mut path = Vec::new();
+            let mut n = i;
+            loop {
+                path.push(func_name(symbols, self.nodes[n].func));
+                if n == 0 {
+                    break;
+                }
+                n = self.nodes[n].parent;
+            }
+            path.reverse();
+            writeln!(out, "{} {}", path.join(";"), node.count).unwrap();
+        }
+        out
+    }
+    /// 按函数、pc和指令种类分别列出计数，从多到少排序
+    ///
+    /// 函数的`self`只计函数自身的指令，`total`另含它调用的函数，递归调用只计一次
+    pub fn report(&self, code: &[Inst], symbols: &BTreeMap<String, UsizeRegType>) -> String {
+        let total = self.total();
+        let percent = |n: u64| n as f64 * 100.0 / total.max(1) as f64;
+        let mut out = String::new();
+        writeln!(out, "instructions: {}", total).unwrap();
+
+        let mut funcs: Vec<_> = self.functions().into_iter().collect();
+        funcs.sort_by_key(|&(func, (own, all))| (std::cmp::Reverse((own, all)), func));
+        writeln!(
+            out,
+            "\nfunctions:\n{:>7} {:>12} {:>7} {:>12}  name",
+            "self%", "self", "total%", "total"
+        )
+        .unwrap();
+        for (func, (own, all)) in funcs {
+            writeln!(
+                out,
+                "{:>6.2}% {:>12} {:>6.2}% {:>12}  {}",
+                percent(own),
+                own,
+                percent(all),
+                all,
+                func_name(symbols, func)
+            )
+            .unwrap();
+        }
+
+        let mut pcs: Vec<_> = (0..self.pcs.len()).filter(|&pc| self.pcs[pc] > 0).collect();
+        pcs.sort_by_key(|&pc| (std::cmp::Reverse(self.pcs[pc]), pc));
+        writeln!(out, "\npcs:").unwrap();
+        for pc in pcs {
+            let location = match symbolize(symbols, pc) {
+                Some((name, 0)) => format!("{:04} <{}>", pc, name),
+                Some((name, off)) => format!("{:04} <{}+{}>", pc, name, off),
+                None => format!("{:04}", pc),
+            };
+            let inst = code.get(pc).map(format_inst).unwrap_or_default();
+            writeln!(
+                out,
+                "{:>6.2}% {:>12}  {}  {}",
+                percent(self.pcs[pc]),
+                self.pcs[pc],
+                location,
+                inst
+            )
+            .unwrap();
+        }
+
+        let mut kinds: Vec<_> = (0..OPCODES).filter(|&op| self.kinds[op] > 0).collect();
+        let names = opcode_names();
+        kinds.sort_by_key(|&op| (std::cmp::Reverse(self.kinds[op]), names[op]));
+        writeln!(out, "\ninstructions by kind:").unwrap();
+        for op in kinds {
+            writeln!(
+                out,
+                "{:>6.2}% {:>12}  {}",
+                percent(self.kinds[op]),
+                self.kinds[op],
+                names[op]
+            )
+            .unwrap();
+        }
+        out
+    }
+    /// 每个函数的自身计数和含被调用者的计数
+    fn functions(&self) -> HashMap<Option<UsizeRegType>, (u64, u64)> {
+        // 子节点总在父节点之后加入，倒序累加即得每棵子树的指令数
+        let mut all: Vec<u64> = self.nodes.iter().map(|node| node.count).collect();
+        for n in (1..self.nodes.len()).rev() {
+            all[self.nodes[n].parent] += all[n];
+        }
+        // 深度优先遍历并记下路径上每个函数出现的次数，递归时只在最外层的调用处计入
+        let mut funcs: HashMap<_, (u64, u64)> = HashMap::new();
+        let mut active: HashMap<_, usize> = HashMap::new();
+        let mut stack = vec![(0, 0)];
+        while let Some(&mut (n, ref mut next)) = stack.last_mut() {
+            let node = &self.nodes[n];
+            if *next == 0 {
+                let depth = active.entry(node.func).or_default();
+                let entry = funcs.entry(node.func).or_default();
+                entry.0 += node.count;
+                if *depth == 0 {
+                    entry.1 += all[n];
+                }
+                *depth += 1;
+            }
+            match node.children.get(*next) {
+                Some(&child) => {
+                    *next += 1;
+                    stack.push((child, 0));
+                }
+                None => {
+                    *active.get_mut(&node.func).unwrap() -= 1;
+                    stack.pop();
+                }
+            }
+        }
+        funcs
+    }
+}
+
+pub(crate) fn func_name(
+    symbols: &BTreeMap<String, UsizeRegType>,
+    func: Option<UsizeRegType>,
+) -> String {
+    match func {
+        None => "top".to_string(),
+        Some(addr) => match symbolize(symbols, addr) {
+            Some((name, 0)) => name.to_string(),
+            Some((name, off)) => format!("{}+{}", name, off),
+            None => format!("{:04}", addr),
+        },
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_profiler() {
+    use crate::asm::assemble_program;
+    use crate::VmTmp;
+
+    let program = assemble_program(
+        "
+        mu u8, fib
+        mu u1, 4
+        call u8
+        halt
+    fib:
+        mu u2, recurse
+        jnz u2, u1
+        ret
+    recurse:
+        subui u1, 1
+        pushu u1
+        call u8
+        popu u1
+        ret
+        ",
+    )
+    .unwrap();
+    let mut vm = VmTmp::new();
+    vm.load_image(program.into()).unwrap();
+    let mut profiler = Profiler::new();
+    let status = vm.start_with(&mut profiler).unwrap();
+    assert_eq!(profiler.total(), status.steps as u64);
+    assert_eq!(profiler.count(4), 5);
+    assert_eq!(
+        profiler.folded(vm.symbols()),
+        "top 4\n\
+         top;fib 7\n\
+         top;fib;fib 7\n\
+         top;fib;fib;fib 7\n\
+         top;fib;fib;fib;fib 7\n\
+         top;fib;fib;fib;fib;fib 3\n"
+    );
+    let report = profiler.report(vm.code(), vm.symbols());
+    assert!(report.contains(" 88.57%           31  88.57%           31  fib\n"));
+    assert!(report.contains(" 11.43%            4 100.00%           35  top\n"));
+    assert!(report.contains("           5  0004 <fib>  mu u2, 7\n"));
+    assert!(report.contains(" 14.29%            5  ret\n"));
+}
@@ -0,0 +1,269 @@
+//! 虚拟机快照
+//!
+//! 保存`VmTmp`的全部执行状态，可以在之后或另一台机器上用`VmTmp::restore`继续执行。
+//! 编码与镜像相同，所有整数均为小端序，`usize`按u64存储，f64按其位模式存储：
+//! ```text
+//! magic    b"DVMS"
+//! version  u16        只接受与SNAPSHOT_VERSION相同的版本
+//! regs     U1..U8 各u64，F1..F8 各f64，pc u64，bp u64，flags u8
+//! fuel     u8 是否计量 + u64 剩余的燃料
+//! costs    每个操作码u64，随后u64 数量 + 每个系统调用号加收的u64
+//! limit    u8 是否限制 + u64 每次start的指令数上限
+//! limits   u64 max_heap，u64 max_stack，u64 max_array_elems，u64 max_threads
+//! code heap stack symbols 同镜像
+//! threads  u64 当前线程号，u8 是否有时间片 + u64 时间片，u64 线程数，随后是每个线程：
+//!          u8 状态 0 执行中，1 就绪 + 上下文，2 等待 + u64 线程号 + 上下文，3 已结束 + u64 退出值
+//!          上下文为U1..U8，F1..F8，pc，bp，flags，随后是栈段，同上
+//! ```
+//! 端口上的设备不在快照中，恢复后仍使用当前挂载的设备。
+use std::collections::BTreeMap;
+
+use demo_isa::reg::{F64RegType, Flags, UsizeRegType};
+use demo_isa::Inst;
+use enumflags2::BitFlags;
+
+use crate::cpu::fuel::CostTable;
+use crate::cpu::green::Threads;
+use crate::image::{ImageErr, Reader, Writer, OPCODES};
+use crate::memory::{Heap, Limits, Stack};
+
+pub const SNAPSHOT_MAGIC: &[u8; 4] = b"DVMS";
+pub const SNAPSHOT_VERSION: u16 = 3;
+
+/// `VmTmp`的执行状态，由`VmTmp::snapshot`取得
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub u_regs: [UsizeRegType; 8],
+    pub f_regs: [F64RegType; 8],
+    pub pc: UsizeRegType,
+    pub bp: UsizeRegType,
+    pub flags: BitFlags<Flags>,
+    pub fuel: Option<u64>,
+    pub costs: CostTable,
+    pub step_limit: Option<usize>,
+    pub limits: Limits,
+    pub code: Vec<Inst>,
+    pub heap: Heap,
+    pub stack: Stack,
+    pub symbols: BTreeMap<String, UsizeRegType>,
+    /// 绿色线程表，见`cpu::green`
+    pub threads: Threads,
+}
+
+impl Snapshot {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut w = Writer::new();
+        w.bytes(SNAPSHOT_MAGIC);
+        w.u16(SNAPSHOT_VERSION);
+        for &v in &self.u_regs {
+            w.usize(v);
+        }
+        for &v in &self.f_regs {
+            w.f64(v);
+        }
+        w.usize(self.pc);
+        w.usize(self.bp);
+        w.u8(self.flags.bits());
+        w.u8(self.fuel.is_some() as u8);
+        w.u64(self.fuel.unwrap_or(0));
+        let (inst, syscall) = self.costs.raw();
+        for &c in inst {
+            w.u64(c);
+        }
+        w.usize(syscall.len());
+        for &c in syscall {
+            w.u64(c);
+        }
+        w.u8(self.step_limit.is_some() as u8);
+        w.usize(self.step_limit.unwrap_or(0));
+        w.usize(self.limits.max_heap);
+        w.usize(self.limits.max_stack);
+        w.usize(self.limits.max_array_elems);
+        w.usize(self.limits.max_threads);
+        w.code(&self.code);
+        w.heap(&self.heap);
+        w.stack(&self.stack);
+        w.symbols(&self.symbols);
+        self.threads.encode(&mut w);
+        w.finish()
+    }
+    pub fn decode(bytes: &[u8]) -> Result<Snapshot, ImageErr> {
+        let mut r = Reader::new(bytes);
+        if r.take(SNAPSHOT_MAGIC.len())? != SNAPSHOT_MAGIC {
+            return Err(ImageErr::BadMagic);
+        }
+        let version = r.u16()?;
+        if version != SNAPSHOT_VERSION {
+            return Err(ImageErr::UnsupportedVersion(version));
+        }
+        let mut u_regs = [0; 8];
+        for v in &mut u_regs {
+            *v = r.usize()?;
+        }
+        let mut f_regs = [0.0; 8];
+        for v in &mut f_regs {
+            *v = r.f64()?;
+        }
+        let pc = r.usize()?;
+        let bp = r.usize()?;
+        let bits = r.u8()?;
+        let flags =
+            BitFlags::from_bits(bits).map_err(|_| ImageErr::ValueOutOfRange(bits as u64))?;
+        let metered = r.option()?;
+        let fuel = r.u64()?;
+        let mut inst = [0; OPCODES];
+        for c in &mut inst {
+            *c = r.u64()?;
+        }
+        let n = r.len(8)?;
+        let syscall = (0..n).map(|_| r.u64()).collect::<Result<_, _>>()?;
+        let limited = r.option()?;
+        let step_limit = r.usize()?;
+        let limits = Limits {
+            max_heap: r.usize()?,
+            max_stack: r.usize()?,
+            max_array_elems: r.usize()?,
+            max_threads: r.usize()?,
+        };
+        let code = r.code()?;
+        let heap = r.heap()?;
+        let stack = r.stack()?;
+        let symbols = r.symbols()?;
+        let threads = Threads::decode(&mut r)?;
+        r.finish()?;
+        Ok(Snapshot {
+            u_regs,
+            f_regs,
+            pc,
+            bp,
+            flags,
+            fuel: metered.then_some(fuel),
+            costs: CostTable::from_raw(inst, syscall),
+            step_limit: limited.then_some(step_limit),
+            limits,
+            code,
+            heap,
+            stack,
+            symbols,
+            threads,
+        })
+    }
+}
+
+impl Reader<'_> {
+    /// 可选值前的标记字节，只能是0或1
+    fn option(&mut self) -> Result<bool, ImageErr> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            tag => Err(ImageErr::InvalidTag(tag)),
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_snapshot() {
+    use crate::asm::assemble_program;
+    use crate::cpu::ExitReason;
+    use crate::image::Image;
+    use crate::memory::heap::HeapObj;
+    use crate::VmTmp;
+    use demo_isa::RegType;
+
+    let program = assemble_program(
+        "
+        mu u1, 1
+        mu u4, 40
+        md f1, 0.5
+        mu u5, loop
+    loop:
+        pushu u4
+        storeuh u4, u1
+        addd f2, f2, f1
+        subui u4, 1
+        mu u6, 20
+        jne u5, u4, u6
+        halt
+        ",
+    )
+    .unwrap();
+    let mut image: Image = program.into();
+    image.heap = Some(vec![
+        HeapObj::FArray(vec![1.5, -2.0]),
+        HeapObj::R(RegType::Usize(0)),
+    ]);
+    let load = || {
+        let mut vm = VmTmp::new();
+        vm.load_image(image.clone()).unwrap();
+        vm.set_fuel(Some(1000));
+        vm
+    };
+    let mut vm = load();
+    vm.start().unwrap();
+    let expected = format!("{:?}", (&vm.core, vm.heap(), vm.stack()));
+
+    let mut vm = load();
+    vm.run_for(23).unwrap();
+    let bytes = vm.snapshot().encode();
+    let mut resumed = VmTmp::new();
+    resumed.restore(Snapshot::decode(&bytes).unwrap()).unwrap();
+    assert_eq!(resumed.snapshot().encode(), bytes);
+    assert_eq!(resumed.start().unwrap().reason, ExitReason::Halt);
+    let got = format!("{:?}", (&resumed.core, resumed.heap(), resumed.stack()));
+    assert_eq!(got, expected);
+
+    let mut old = bytes.clone();
+    old[4..6].copy_from_slice(&(SNAPSHOT_VERSION + 1).to_le_bytes());
+    assert_eq!(
+        Snapshot::decode(&old).unwrap_err(),
+        ImageErr::UnsupportedVersion(SNAPSHOT_VERSION + 1)
+    );
+    assert_eq!(
+        Snapshot::decode(&bytes[..bytes.len() - 1]).unwrap_err(),
+        ImageErr::Truncated
+    );
+
+    // 快照包含所有绿色线程，在任何一步恢复后结果都相同
+    let code = assemble_program(
+        "
+        mu u2, t
+        mu u3, 5
+        mu u8, 4
+        syscall u8          ; spawn
+        pushu u5
+        mu u8, 6
+        movu u2, u5
+        syscall u8          ; join
+        popu u6
+        addu u2, u5, u6
+        mu u8, 7
+        syscall u8          ; exit
+    t:
+        pushu u1
+        mu u8, 5
+        syscall u8          ; yield
+        popu u2
+        addui u2, 10
+        mu u8, 7
+        syscall u8
+        ",
+    )
+    .unwrap()
+    .code;
+    let mut vm = VmTmp::new();
+    vm.set_code(code.clone());
+    let status = vm.start().unwrap();
+    assert_eq!(status.value, 16);
+    for n in 0..status.steps {
+        let mut vm = VmTmp::new();
+        vm.set_code(code.clone());
+        vm.run_for(n).unwrap();
+        let mut resumed = VmTmp::new();
+        resumed
+            .restore(Snapshot::decode(&vm.snapshot().encode()).unwrap())
+            .unwrap();
+        let rest = resumed.start().unwrap();
+        assert_eq!((rest.value, n + rest.steps), (16, status.steps));
+    }
+}
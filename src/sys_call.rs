@@ -1,10 +1,20 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
 
 use demo_isa::err::ISAErr;
 
-use crate::{cpu::CpuCore, memory::Memory};
+use crate::{
+    cpu::CpuCore,
+    memory::{Memory, MemoryErr},
+};
 
+use self::atomic::{compare_and_swap, fence, fetch_add};
+use self::thread::{exit, join, spawn, yield_now};
 use self::write::write_std;
 
+mod atomic;
+mod thread;
 mod write;
 
 #[derive(Debug)]
@@ -13,6 +23,9 @@ pub enum SysCallErr {
     InvalidSysCallArg,
     WriteErr(write::WriteErr),
     ISAErr(ISAErr),
+    MemoryErr(MemoryErr),
+    /// 剩下的线程都在等待
+    Deadlock,
 }
 impl From<SysCallErr> for ISAErr {
     fn from(err: SysCallErr) -> ISAErr {
@@ -23,10 +36,38 @@ impl From<SysCallErr> for ISAErr {
         }
     }
 }
+impl From<MemoryErr> for SysCallErr {
+    fn from(err: MemoryErr) -> SysCallErr {
+        SysCallErr::MemoryErr(err)
+    }
+}
 impl From<ISAErr> for SysCallErr {
     fn from(err: ISAErr) -> SysCallErr {
         SysCallErr::ISAErr(err)
     }
 }
-type SysCall = fn(&mut CpuCore, &mut Memory) -> Result<(), SysCallErr>;
-pub const SYS_CALL_TABLE: &[SysCall] = &[write_std];
+pub type SysCall = fn(&mut CpuCore, &mut Memory) -> Result<(), SysCallErr>;
+/// 异步系统调用返回的future，可以借用核心和内存
+pub type SysCallFuture<'a> = Pin<Box<dyn Future<Output = Result<(), SysCallErr>> + Send + 'a>>;
+/// 等待宿主完成的系统调用，见`cpu::async_call`
+///
+/// 可以是捕获了宿主状态（通道、客户端、运行时句柄等）的闭包
+pub type AsyncSysCall =
+    Arc<dyn for<'a> Fn(&'a mut CpuCore, &'a mut Memory) -> SysCallFuture<'a> + Send + Sync>;
+/// 按调用号排列的系统调用：0 写标准输出，1 比较并交换，2 取值相加，3 内存屏障，
+/// 4 创建线程，5 让出，6 等待线程，7 结束线程
+pub const SYS_CALL_TABLE: &[SysCall] = &[
+    write_std,
+    compare_and_swap,
+    fetch_add,
+    fence,
+    spawn,
+    yield_now,
+    join,
+    exit,
+];
+/// 与`SYS_CALL_TABLE`一一对应，结果是否取决于外部环境。
+/// 记录和重放只针对这些系统调用，其余的在重放时照常执行
+pub(crate) const EXTERNAL_SYS_CALLS: &[bool] =
+    &[true, false, false, false, false, false, false, false];
+const _: () = assert!(EXTERNAL_SYS_CALLS.len() == SYS_CALL_TABLE.len());
@@ -0,0 +1,51 @@
+use demo_isa::reg::UsizeReg;
+use demo_isa::RegType;
+
+use crate::cpu::CpuCore;
+use crate::memory::Memory;
+
+use super::SysCallErr;
+
+/// 比较并交换堆中的usize，堆中的值等于期望的值时写入新的值
+///
+/// 参数：
+///     U2: 地址
+///     U3: 期望的值
+///     U4: 新的值
+///
+/// 返回值：
+///     U5: 原来的值，等于U3表示已写入
+pub fn compare_and_swap(core: &mut CpuCore, mem: &mut Memory) -> Result<(), SysCallErr> {
+    let addr = core.get_u_reg(UsizeReg::U2);
+    let old = *mem.get_heap_obj(addr)?.get_reg_u_type()?;
+    if old == core.get_u_reg(UsizeReg::U3) {
+        mem.set_heap(addr, &RegType::Usize(core.get_u_reg(UsizeReg::U4)))?;
+    }
+    core.set_u_reg(UsizeReg::U5, old);
+    Ok(())
+}
+
+/// 把堆中的usize加上一个值，溢出时回绕
+///
+/// 参数：
+///     U2: 地址
+///     U3: 加上的值
+///
+/// 返回值：
+///     U5: 原来的值
+pub fn fetch_add(core: &mut CpuCore, mem: &mut Memory) -> Result<(), SysCallErr> {
+    let addr = core.get_u_reg(UsizeReg::U2);
+    let old = *mem.get_heap_obj(addr)?.get_reg_u_type()?;
+    let new = old.wrapping_add(core.get_u_reg(UsizeReg::U3));
+    mem.set_heap(addr, &RegType::Usize(new))?;
+    core.set_u_reg(UsizeReg::U5, old);
+    Ok(())
+}
+
+/// 内存屏障，之前对堆的写入对之后执行的所有核心可见
+///
+/// 各核心对堆的访问总是依次发生，`Machine::run_threads`中系统调用在共享的锁内执行，
+/// 锁的获取和释放已是完整的屏障，这里不需要做任何事，保留它是为了让客户程序写明同步的位置
+pub fn fence(_core: &mut CpuCore, _mem: &mut Memory) -> Result<(), SysCallErr> {
+    Ok(())
+}
@@ -0,0 +1,49 @@
+use demo_isa::reg::UsizeReg;
+
+use crate::cpu::{green, CpuCore};
+use crate::memory::Memory;
+
+use super::SysCallErr;
+
+/// 创建绿色线程，见`cpu::green`
+///
+/// 参数：
+///     U2: 新线程开始执行的地址
+///     U3: 新线程的U1
+///
+/// 返回值：
+///     U5: 新线程的线程号
+pub fn spawn(core: &mut CpuCore, mem: &mut Memory) -> Result<(), SysCallErr> {
+    let entry = core.get_u_reg(UsizeReg::U2);
+    let arg = core.get_u_reg(UsizeReg::U3);
+    let id = green::spawn(core, mem, entry, arg)?;
+    core.set_u_reg(UsizeReg::U5, id);
+    Ok(())
+}
+
+/// 切换到下一个就绪的线程，没有时继续执行
+pub fn yield_now(core: &mut CpuCore, mem: &mut Memory) -> Result<(), SysCallErr> {
+    green::yield_now(core, mem);
+    Ok(())
+}
+
+/// 等待线程结束
+///
+/// 参数：
+///     U2: 线程号，不能是当前线程
+///
+/// 返回值：
+///     U5: 该线程的退出值
+pub fn join(core: &mut CpuCore, mem: &mut Memory) -> Result<(), SysCallErr> {
+    let id = core.get_u_reg(UsizeReg::U2);
+    green::join(core, mem, id)
+}
+
+/// 结束当前线程，最后一个线程结束时停机
+///
+/// 参数：
+///     U2: 退出值，停机时写入U1
+pub fn exit(core: &mut CpuCore, mem: &mut Memory) -> Result<(), SysCallErr> {
+    let val = core.get_u_reg(UsizeReg::U2);
+    green::exit(core, mem, val)
+}
@@ -26,7 +26,9 @@ impl From<WriteErr> for SysCallErr {
         SysCallErr::WriteErr(err)
     }
 }
-/// 写入标准输出
+/// 写入标准输出，每个字符另消耗1单位燃料
+///
+/// 写入的字节数不能超过堆中数组元素的上限所能容纳的字节数，否则返回`InvalidSysCallArg`
 ///
 /// 参数：
 ///     U2: 写入的字符串的地址
@@ -38,11 +40,24 @@ impl From<WriteErr> for SysCallErr {
 pub fn write_std(core: &mut CpuCore, mem: &mut Memory) -> Result<(), SysCallErr> {
     let addr = core.get_u_reg(demo_isa::reg::UsizeReg::U2);
     let len = core.get_u_reg(demo_isa::reg::UsizeReg::U3);
-    let mut buf = Vec::with_capacity(len);
-    for _ in 0..len {
-        let c = mem.get_heap_obj(addr).get_u8_vec();
-        buf.extend_from_slice(c);
+    let mut buf = Vec::new();
+    if len > 0 {
+        // `len`来自客户程序，分配之前先检查大小
+        let max = mem
+            .limits()
+            .max_array_elems
+            .saturating_mul(size_of::<usize>());
+        let c = mem.get_heap_obj(addr)?.get_u8_vec();
+        let size = len
+            .checked_mul(c.len())
+            .filter(|&n| n <= max)
+            .ok_or(SysCallErr::InvalidSysCallArg)?;
+        buf.reserve_exact(size);
+        for _ in 0..len {
+            buf.extend_from_slice(c);
+        }
     }
+    core.charge_fuel(len as u64);
     match io::stdout().write(&buf) {
         Ok(l) => {
             core.set_u_reg(demo_isa::reg::UsizeReg::U4, 0);
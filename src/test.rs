@@ -1,13 +1,12 @@
 //! 测试的模块，编译去除
 use criterion::black_box;
 use demo_isa::{
-    err::ISAErr,
     reg::{F64Reg, F64RegType, UsizeReg, UsizeRegType},
     Inst,
 };
 use log::debug;
 
-use crate::{cpu::CpuErr, memory::MemoryErr, VmTmp, VmErr};
+use crate::VmTmp;
 
 impl VmTmp {
     pub fn get_pc(&self) -> UsizeRegType {
@@ -20,9 +19,9 @@ impl VmTmp {
         self.core.get_f_reg(reg)
     }
 }
-pub fn vm_fibonacci(n: usize) -> usize {
-    let mut vm = VmTmp::new();
-    let code = vec![
+/// 递归计算fibonacci的程序，结果保存在U1
+pub fn fibonacci_code(n: usize) -> Vec<Inst> {
+    vec![
         Inst::MU(UsizeReg::U8, 4),
         Inst::MU(UsizeReg::U1, n),
         Inst::Call(UsizeReg::U8), //U1=fib(n)
@@ -47,21 +46,14 @@ pub fn vm_fibonacci(n: usize) -> usize {
         Inst::PopU(UsizeReg::U2),                             // U2=fib(n-1)
         Inst::AddU(UsizeReg::U1, UsizeReg::U2, UsizeReg::U1), //U1=fib(n-1)+fib(n-2)
         Inst::Ret,
-    ];
-    vm.set_code(code);
-    match vm.start() {
-        Ok(_) => {}
-        Err(VmErr::CpuErr(CpuErr::ISAErr(ISAErr::Halt))) => {
-            debug!("Halt")
-        }
-        Err(VmErr::CpuErr(CpuErr::MemoryErr(MemoryErr::InvalidCodeAddr))) => {
-            panic!("InvalidCodeAddr")
-        }
-        Err(e) => {
-            panic!("ISAErr: {:?}", e)
-        }
-    }
-    vm.get_u_reg(demo_isa::reg::UsizeReg::U1)
+    ]
+}
+pub fn vm_fibonacci(n: usize) -> usize {
+    let mut vm = VmTmp::new();
+    vm.set_code(fibonacci_code(n));
+    let status = vm.start().unwrap();
+    debug!("{:?}", status);
+    status.value
 }
 #[cfg(test)]
 #[test]
@@ -71,6 +63,159 @@ pub fn test_vm_fibonacci() {
         assert_eq!(vm_fibonacci(i), fibonacci(i));
     }
 }
+#[cfg(test)]
+#[test]
+pub fn test_vm_runner_fibonacci() {
+    use crate::Vm;
+    use demo_isa::VmRunner;
+    for i in 0..10 {
+        let mut vm = Vm::new();
+        vm.run(&fibonacci_code(i)).unwrap();
+        assert_eq!(vm.get_u_reg(UsizeReg::U1), fibonacci(i));
+    }
+}
+#[cfg(test)]
+#[test]
+pub fn test_vm_runner_end_of_code() {
+    use crate::Vm;
+    use demo_isa::VmRunner;
+    let mut vm = Vm::new();
+    let code = vec![
+        Inst::MU(UsizeReg::U1, 3),
+        Inst::MU(UsizeReg::U2, 2),
+        Inst::MulU(UsizeReg::U3, UsizeReg::U1, UsizeReg::U2),
+    ];
+    vm.run(&code).unwrap();
+    assert_eq!(vm.get_u_reg(UsizeReg::U3), 6);
+    assert_eq!(vm.get_pc(), code.len());
+
+    // 燃料不够时不能当作正常结束
+    use crate::cpu::ExitReason;
+    use crate::memory::heap::HeapObj;
+    use crate::VmErr;
+    use demo_isa::RegType;
+    vm.set_fuel(Some(2));
+    assert!(matches!(
+        vm.run(&code),
+        Err(VmErr::Unfinished(ExitReason::OutOfFuel))
+    ));
+    // 堆段在两次执行之间保留在公开的字段中
+    vm.set_fuel(None);
+    vm.heap_segment.push(HeapObj::R(RegType::Usize(5)));
+    vm.run(&[
+        Inst::MU(UsizeReg::U2, 0),
+        Inst::LoadUH(UsizeReg::U1, UsizeReg::U2),
+    ])
+    .unwrap();
+    assert_eq!(vm.get_u_reg(UsizeReg::U1), 5);
+    assert_eq!(vm.heap_segment.len(), 1);
+}
+#[cfg(test)]
+#[test]
+pub fn test_exit_status() {
+    use crate::cpu::{ExitReason, ExitStatus};
+    let mut vm = VmTmp::new();
+    vm.set_code(fibonacci_code(6));
+    assert_eq!(
+        vm.start().unwrap(),
+        ExitStatus {
+            reason: ExitReason::Halt,
+            value: 8,
+            // fib(6)中n>=2的调用12次，每次17条；n==1的8次，每次7条；n==0的5次，每次3条
+            steps: 4 + 12 * 17 + 8 * 7 + 5 * 3,
+        }
+    );
+    assert_eq!(vm.get_pc(), 4);
+
+    let mut vm = VmTmp::new();
+    vm.set_code(vec![Inst::MU(UsizeReg::U1, 1); 10]);
+    vm.set_step_limit(Some(4));
+    let status = vm.start().unwrap();
+    assert_eq!((status.reason, status.steps), (ExitReason::StepLimit, 4));
+    vm.set_step_limit(None);
+    let status = vm.start().unwrap();
+    assert_eq!(status.reason, ExitReason::EndOfCode);
+    assert_eq!((status.value, status.steps), (1, 6));
+}
+#[cfg(test)]
+#[test]
+pub fn test_step_and_run_for() {
+    use crate::cpu::ExitReason;
+    let mut vm = VmTmp::new();
+    vm.set_code(fibonacci_code(3));
+    // MU U8, MU U1, Call U8
+    for pc in 1..=2 {
+        assert_eq!(vm.step().unwrap(), None);
+        assert_eq!(vm.get_pc(), pc);
+    }
+    assert_eq!(vm.get_u_reg(UsizeReg::U1), 3);
+    assert_eq!(vm.get_u_reg(UsizeReg::U8), 4);
+    vm.step().unwrap();
+    assert_eq!(vm.get_pc(), 4);
+    assert_eq!(vm.get_bp(), 1);
+
+    let status = vm.run_for(5).unwrap();
+    assert_eq!((status.reason, status.steps), (ExitReason::StepLimit, 5));
+    assert_eq!(vm.get_pc(), 12);
+    assert_eq!(vm.get_u_reg(UsizeReg::U1), 2);
+
+    let status = vm.run_for(usize::MAX).unwrap();
+    assert_eq!((status.reason, status.value), (ExitReason::Halt, 2));
+    assert_eq!(status.steps, 4 + 2 * 17 + 2 * 7 + 3 - 8);
+
+    let mut vm = VmTmp::new();
+    vm.set_code(vec![Inst::Nop]);
+    vm.step().unwrap();
+    assert_eq!(vm.step().unwrap(), Some(ExitReason::EndOfCode));
+}
+#[cfg(test)]
+#[test]
+pub fn test_fault_backtrace() {
+    use crate::asm::assemble_program;
+    use crate::cpu::{CpuErr, Frame};
+    use crate::VmErr;
+    use demo_isa::err::ISAErr;
+    let src = "
+main:
+    mu u8, outer
+    call u8
+    halt
+outer:
+    mu u8, inner
+    call u8
+    ret
+inner:
+    mu u2, 0
+    divu u1, u1, u2
+    ret
+";
+    let mut vm = VmTmp::new();
+    vm.load_image(assemble_program(src).unwrap().into())
+        .unwrap();
+    let fault = match vm.start() {
+        Err(VmErr::Fault(fault)) => fault,
+        r => panic!("unexpected {:?}", r),
+    };
+    assert!(matches!(fault.err, CpuErr::ISAErr(ISAErr::DivByZero)));
+    assert_eq!(fault.pc, 7);
+    assert_eq!(
+        fault.inst,
+        Some(Inst::DivU(UsizeReg::U1, UsizeReg::U1, UsizeReg::U2))
+    );
+    assert_eq!(
+        fault.backtrace,
+        vec![
+            Frame { pc: 7, bp: 3 },
+            Frame { pc: 5, bp: 1 },
+            Frame { pc: 2, bp: 0 },
+        ]
+    );
+    let symbols: Vec<_> = fault.symbols.iter().map(|s| s.as_deref()).collect();
+    assert_eq!(symbols, [Some("inner+1"), Some("outer+2"), Some("main+2")]);
+    assert!(fault
+        .to_string()
+        .starts_with("ISAErr(DivByZero) at 0007 <inner+1>: divu u1, u1, u2"));
+}
 pub fn fibonacci(n: usize) -> usize {
     if n == 0 {
         return 0;
@@ -0,0 +1,418 @@
+//! 执行轨迹的导出
+//!
+//! `JsonTracer`作为观察者，每执行一条指令向输出写一行JSON（JSON Lines），包括执行序号、pc、
+//! 指令、执行后被改变的寄存器和bp、标志位以及对堆栈的访问，出错的指令另有`fault`。
+//! 访问中的`val`为读出或写入的值，出错的指令没有执行完，`val`为`null`：
+//! ```text
+//! {"step":2,"pc":2,"inst":"storeuh u1, u2","regs":{},"flags":[],"mem":[{"seg":"heap","addr":0,"write":true,"val":5}]}
+//! ```
+//! `ChromeTracer`沿`Call`/`Ret`记下每次调用的起止，输出Chrome的trace event格式，
+//! 可以用chrome://tracing或Perfetto打开。时间轴以执行的指令数为单位，每条指令记为1微秒。
+//!
+//! 两者都可以用`Filter`只保留部分pc和部分执行序号：`JsonTracer`按指令所在的pc过滤，
+//! `ChromeTracer`按函数的入口地址过滤，并把调用截取到序号范围之内。
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io;
+use std::ops::Range;
+
+use demo_isa::reg::{F64RegType, Flags, UsizeRegType};
+use demo_isa::Inst;
+use enumflags2::BitFlags;
+
+use crate::asm::disasm::format_inst;
+use crate::asm::{F_REGS, U_REGS};
+use crate::cpu::observer::{ExecObserver, MemAccess, Segment};
+use crate::cpu::{CpuCore, Fault};
+use crate::memory::Memory;
+use crate::profiler::func_name;
+
+/// 要记录的pc和执行序号，执行序号为指令按执行顺序从0开始的编号
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Filter {
+    pub pcs: Range<UsizeRegType>,
+    pub steps: Range<u64>,
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Filter {
+    /// 记录所有指令
+    pub fn new() -> Filter {
+        Filter {
+            pcs: 0..UsizeRegType::MAX,
+            steps: 0..u64::MAX,
+        }
+    }
+    pub fn matches(&self, pc: UsizeRegType, step: u64) -> bool {
+        self.pcs.contains(&pc) && self.steps.contains(&step)
+    }
+}
+
+/// 正在执行的指令及其执行前的寄存器
+#[derive(Debug)]
+struct Pending {
+    step: u64,
+    pc: UsizeRegType,
+    inst: Inst,
+    u: [UsizeRegType; 8],
+    f: [F64RegType; 8],
+    bp: UsizeRegType,
+    flags: BitFlags<Flags>,
+    mem: Vec<MemAccess>,
+}
+
+/// 把每条指令写为一行JSON
+///
+/// 观察者不能返回错误，写入出错后不再写入，错误由`finish`返回
+#[derive(Debug)]
+pub struct JsonTracer<W: io::Write> {
+    out: W,
+    filter: Filter,
+    steps: u64,
+    pending: Option<Pending>,
+    err: Option<io::Error>,
+}
+
+impl<W: io::Write> ExecObserver for JsonTracer<W> {
+    fn before_inst(&mut self, pc: UsizeRegType, inst: &Inst, core: &CpuCore) {
+        let step = self.steps;
+        self.steps += 1;
+        if !self.filter.matches(pc, step) {
+            return;
+        }
+        self.pending = Some(Pending {
+            step,
+            pc,
+            inst: *inst,
+            u: U_REGS.map(|r| core.get_u_reg(r)),
+            f: F_REGS.map(|r| core.get_f_reg(r)),
+            bp: core.get_bp(),
+            flags: core.get_flags(),
+            mem: Vec::new(),
+        });
+    }
+    fn after_inst(&mut self, _pc: UsizeRegType, _inst: &Inst, core: &CpuCore) {
+        if let Some(p) = self.pending.take() {
+            self.write(p, Some(core), None);
+        }
+    }
+    fn on_mem(&mut self, access: MemAccess, _mem: &Memory) {
+        if let Some(p) = &mut self.pending {
+            p.mem.push(access);
+        }
+    }
+    fn on_fault(&mut self, fault: &Fault) {
+        if let Some(p) = self.pending.take() {
+            self.write(p, None, Some(fault));
+        }
+    }
+}
+
+impl<W: io::Write> JsonTracer<W> {
+    pub fn new(out: W, filter: Filter) -> JsonTracer<W> {
+        JsonTracer {
+            out,
+            filter,
+            steps: 0,
+            pending: None,
+            err: None,
+        }
+    }
+    /// 写出停机指令的记录并返回输出，期间写入出错时返回第一个错误
+    pub fn finish(mut self) -> io::Result<W> {
+        // 停机的指令不调用`after_inst`，它不改变任何寄存器
+        if let Some(p) = self.pending.take() {
+            self.write(p, None, None);
+        }
+        if let Some(e) = self.err {
+            return Err(e);
+        }
+        self.out.flush()?;
+        Ok(self.out)
+    }
+    /// `core`为执行后的状态，没有时视为没有改变
+    fn write(&mut self, p: Pending, core: Option<&CpuCore>, fault: Option<&Fault>) {
+        if self.err.is_some() {
+            return;
+        }
+        let mut line = format!(
+            "{{\"step\":{},\"pc\":{},\"inst\":{},\"regs\":{{",
+            p.step,
+            p.pc,
+            json_str(&format_inst(&p.inst))
+        );
+        let mut regs = Vec::new();
+        let mut flags = p.flags;
+        if let Some(core) = core {
+            for (i, (r, v)) in U_REGS.into_iter().zip(p.u).enumerate() {
+                if core.get_u_reg(r) != v {
+                    regs.push(format!("\"u{}\":{}", i + 1, core.get_u_reg(r)));
+                }
+            }
+            for (i, (r, v)) in F_REGS.into_iter().zip(p.f).enumerate() {
+                if core.get_f_reg(r).to_bits() != v.to_bits() {
+                    regs.push(format!("\"f{}\":{}", i + 1, json_f64(core.get_f_reg(r))));
+                }
+            }
+            if core.get_bp() != p.bp {
+                regs.push(format!("\"bp\":{}", core.get_bp()));
+            }
+            flags = core.get_flags();
+        }
+        line += &regs.join(",");
+        let val = core.and_then(|core| mem_value(&p.inst, core));
+        let val = val.as_deref().unwrap_or("null");
+        let flags: Vec<_> = flags.iter().map(|f| format!("\"{:?}\"", f)).collect();
+        write!(line, "}},\"flags\":[{}],\"mem\":[", flags.join(",")).unwrap();
+        let mem: Vec<_> = p
+            .mem
+            .iter()
+            .map(|m| {
+                let seg = match m.segment {
+                    Segment::Heap => "heap",
+                    Segment::Stack => "stack",
+                };
+                format!(
+                    "{{\"seg\":\"{}\",\"addr\":{},\"write\":{},\"val\":{}}}",
+                    seg, m.addr, m.write, val
+                )
+            })
+            .collect();
+        line += &mem.join(",");
+        line.push(']');
+        if let Some(fault) = fault {
+            write!(line, ",\"fault\":{}", json_str(&format!("{:?}", fault.err))).unwrap();
+        }
+        line += "}\n";
+        if let Err(e) = self.out.write_all(line.as_bytes()) {
+            self.err = Some(e);
+        }
+    }
+}
+
+/// 一次调用，`start`和`end`为执行序号，顶层代码的`func`为`None`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Span {
+    func: Option<UsizeRegType>,
+    depth: usize,
+    start: u64,
+    end: u64,
+}
+
+/// 按`Call`/`Ret`记录调用的起止，输出Chrome的trace event格式
+#[derive(Debug)]
+pub struct ChromeTracer {
+    filter: Filter,
+    steps: u64,
+    /// 尚未返回的调用，`stack[0]`为顶层
+    stack: Vec<Span>,
+    spans: Vec<Span>,
+}
+
+impl Default for ChromeTracer {
+    fn default() -> Self {
+        Self::new(Filter::new())
+    }
+}
+
+impl ExecObserver for ChromeTracer {
+    fn before_inst(&mut self, _pc: UsizeRegType, _inst: &Inst, _core: &CpuCore) {
+        self.steps += 1;
+    }
+    fn on_call(&mut self, _pc: UsizeRegType, core: &CpuCore) {
+        self.stack.push(Span {
+            func: Some(core.get_pc()),
+            depth: self.stack.len(),
+            start: self.steps,
+            end: self.steps,
+        });
+    }
+    fn on_ret(&mut self, _pc: UsizeRegType, _core: &CpuCore) {
+        // 没有对应`Call`的`Ret`不结束顶层
+        if self.stack.len() > 1 {
+            let span = self.stack.pop().unwrap();
+            self.close(span);
+        }
+    }
+}
+
+impl ChromeTracer {
+    pub fn new(filter: Filter) -> ChromeTracer {
+        ChromeTracer {
+            filter,
+            steps: 0,
+            stack: vec![Span {
+                func: None,
+                depth: 0,
+                start: 0,
+                end: 0,
+            }],
+            spans: Vec::new(),
+        }
+    }
+    /// 已记录的调用，尚未返回的调用截止到当前，`symbols`用于显示函数名
+    pub fn to_json(&self, symbols: &BTreeMap<String, UsizeRegType>) -> String {
+        let mut this = ChromeTracer {
+            filter: self.filter.clone(),
+            steps: self.steps,
+            stack: Vec::new(),
+            spans: self.spans.clone(),
+        };
+        for &span in self.stack.iter().rev() {
+            this.close(span);
+        }
+        this.spans.sort_by_key(|s| (s.start, s.depth));
+        let events: Vec<_> = this
+            .spans
+            .iter()
+            .map(|s| {
+                let args = match s.func {
+                    Some(addr) => format!(",\"args\":{{\"addr\":{}}}", addr),
+                    None => String::new(),
+                };
+                format!(
+                    "{{\"name\":{},\"ph\":\"X\",\"ts\":{},\"dur\":{},\"pid\":1,\"tid\":1{}}}",
+                    json_str(&func_name(symbols, s.func)),
+                    s.start,
+                    s.end - s.start,
+                    args
+                )
+            })
+            .collect();
+        format!("{{\"traceEvents\":[\n{}\n]}}\n", events.join(",\n"))
+    }
+    /// 结束一次调用，按过滤条件截取后保存
+    fn close(&mut self, mut span: Span) {
+        let pc_ok = span.func.is_none_or(|f| self.filter.pcs.contains(&f));
+        span.start = span.start.max(self.filter.steps.start);
+        span.end = self.steps.min(self.filter.steps.end);
+        if pc_ok && span.start < span.end {
+            self.spans.push(span);
+        }
+    }
+}
+
+/// 访存指令读出或写入的值，即执行后指令中数据寄存器的值
+///
+/// 写入不改变数据寄存器，读出的值在执行后已在其中，因此两者都可以在`after_inst`时取得
+fn mem_value(inst: &Inst, core: &CpuCore) -> Option<String> {
+    match *inst {
+        Inst::LoadUH(r, _)
+        | Inst::StoreUH(r, _)
+        | Inst::LoadUS(r, _)
+        | Inst::StoreUS(r, _)
+        | Inst::PushU(r)
+        | Inst::PopU(r) => Some(core.get_u_reg(r).to_string()),
+        Inst::LoadDH(r, _)
+        | Inst::StoreDH(r, _)
+        | Inst::LoadDS(r, _)
+        | Inst::StoreDS(r, _)
+        | Inst::PushD(r)
+        | Inst::PopD(r) => Some(json_f64(core.get_f_reg(r))),
+        _ => None,
+    }
+}
+
+fn json_str(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out += "\\\"",
+            '\\' => out += "\\\\",
+            '\n' => out += "\\n",
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// JSON没有NaN和无穷大，写为字符串
+fn json_f64(v: F64RegType) -> String {
+    if v.is_finite() {
+        format!("{:?}", v)
+    } else {
+        format!("\"{}\"", v)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_trace() {
+    use crate::asm::assemble_program;
+    use crate::VmTmp;
+
+    let program = assemble_program(
+        "
+        mu u1, 2
+        mu u8, f
+        call u8
+        storeuh u1, u1
+        loaddh f1, u1       ; 类型不符
+    f:
+        pushu u1
+        popu u2
+        ret
+        ",
+    )
+    .unwrap();
+    let mut vm = VmTmp::new();
+    vm.load_image(program.clone().into()).unwrap();
+    let mut tracer = JsonTracer::new(Vec::new(), Filter::new());
+    assert!(vm.start_with(&mut tracer).is_err());
+    let out = String::from_utf8(tracer.finish().unwrap()).unwrap();
+    let lines: Vec<_> = out.lines().collect();
+    assert_eq!(lines.len(), 8);
+    assert_eq!(
+        lines[2],
+        r#"{"step":2,"pc":2,"inst":"call u8","regs":{"bp":1},"flags":[],"mem":[]}"#
+    );
+    assert_eq!(
+        lines[3],
+        r#"{"step":3,"pc":5,"inst":"pushu u1","regs":{},"flags":[],"mem":[{"seg":"stack","addr":2,"write":true,"val":2}]}"#
+    );
+    assert_eq!(
+        lines[4],
+        r#"{"step":4,"pc":6,"inst":"popu u2","regs":{"u2":2},"flags":[],"mem":[{"seg":"stack","addr":2,"write":false,"val":2}]}"#
+    );
+    assert!(lines[6].ends_with(r#""mem":[{"seg":"heap","addr":2,"write":true,"val":2}]}"#));
+    assert!(lines[7].starts_with(r#"{"step":7,"pc":4,"inst":"loaddh f1, u1","#));
+    assert!(lines[7].contains(r#""write":false,"val":null}],"fault":"#));
+
+    let mut vm = VmTmp::new();
+    vm.load_image(
+        assemble_program("mu u8, f\ncall u8\nhalt\nf: ret")
+            .unwrap()
+            .into(),
+    )
+    .unwrap();
+    let filter = Filter {
+        pcs: 2..3,
+        steps: 0..u64::MAX,
+    };
+    let mut tracer = JsonTracer::new(Vec::new(), filter);
+    vm.start_with(&mut tracer).unwrap();
+    let out = String::from_utf8(tracer.finish().unwrap()).unwrap();
+    assert_eq!(
+        out,
+        "{\"step\":3,\"pc\":2,\"inst\":\"halt\",\"regs\":{},\"flags\":[],\"mem\":[]}\n"
+    );
+
+    let mut vm = VmTmp::new();
+    vm.load_image(program.into()).unwrap();
+    let mut chrome = ChromeTracer::default();
+    let _ = vm.start_with(&mut chrome);
+    assert_eq!(
+        chrome.to_json(vm.symbols()),
+        "{\"traceEvents\":[\n\
+         {\"name\":\"top\",\"ph\":\"X\",\"ts\":0,\"dur\":8,\"pid\":1,\"tid\":1},\n\
+         {\"name\":\"f\",\"ph\":\"X\",\"ts\":3,\"dur\":3,\"pid\":1,\"tid\":1,\"args\":{\"addr\":5}}\n\
+         ]}\n"
+    );
+}